@@ -0,0 +1,384 @@
+//! Reading HTTP responses without trusting their size.
+//!
+//! A misbehaving API could send a body big enough to fill memory. Responses
+//! are read through [`ResponseExt`], which gives up past `--max-response-size`:
+//! right away if the `Content-Length` says so, or as soon as the body grows
+//! past it otherwise. JSON is also checked for `--max-json-depth` before it's
+//! parsed, since every nested level costs the parser stack.
+//!
+//! Requests go out through [`fetch_with_retry`], which tries again with an
+//! exponential backoff when the API is rate limiting or briefly unreachable,
+//! to URLs under `--api-base-url` built with [`api_url`].
+
+use std::{
+    future::Future,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        RwLock,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use bytesize::ByteSize;
+use reqwest::{RequestBuilder, Response, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::config::Config;
+
+/// `--max-response-size`, in bytes.
+static MAX_RESPONSE_SIZE: AtomicU64 = AtomicU64::new(10_000_000);
+/// `--max-json-depth`.
+static MAX_JSON_DEPTH: AtomicUsize = AtomicUsize::new(64);
+
+/// The speedrun.com API, unless `--api-base-url` says otherwise.
+pub const DEFAULT_API_BASE_URL: &str = "https://www.speedrun.com/api/v1";
+static API_BASE_URL: RwLock<Option<String>> = RwLock::new(None);
+
+/// Attempts per request, the first one included.
+const MAX_ATTEMPTS: u32 = 5;
+/// Wait before the first retry, doubled for each one after.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Longest `Retry-After` waited out. Past it the request gives up, rather
+/// than hanging the session for as long as the server asks.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Applies the limits and base URL from `cfg` for the rest of the session.
+pub fn init(cfg: &Config) -> Result<()> {
+    MAX_RESPONSE_SIZE.store(cfg.max_response_size.as_u64(), Ordering::Relaxed);
+    MAX_JSON_DEPTH.store(cfg.max_json_depth, Ordering::Relaxed);
+    match cfg.api_base_url() {
+        Some(url) => set_base_url(url),
+        None => Ok(()),
+    }
+}
+
+/// Sends API requests to `url` from now on, once it passes [`parse_base_url`].
+pub fn set_base_url(url: &str) -> Result<()> {
+    *API_BASE_URL.write().unwrap() = Some(parse_base_url(url)?);
+    Ok(())
+}
+
+/// Checks an `--api-base-url`, and returns it without a trailing slash.
+///
+/// It has to be HTTPS, the API key goes along with requests. Plain HTTP is
+/// only accepted to this machine, for test servers.
+pub fn parse_base_url(url: &str) -> Result<String> {
+    let parsed = Url::parse(url).with_context(|| format!("Invalid API base URL {url:?}"))?;
+    let local = match parsed.host_str() {
+        Some("localhost") => true,
+        Some(host) => host
+            .trim_matches(['[', ']'])
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback()),
+        None => false,
+    };
+    match parsed.scheme() {
+        "https" => {}
+        "http" if local => {}
+        _ => bail!("The API base URL has to be https://, got {url:?}"),
+    }
+    if parsed.query().is_some() || parsed.cannot_be_a_base() {
+        bail!("The API base URL can't have a query, got {url:?}");
+    }
+    Ok(url.trim_end_matches('/').to_string())
+}
+
+/// `path` under the API base URL, like `runs/<id>`.
+pub fn api_url(path: &str) -> String {
+    let base = API_BASE_URL.read().unwrap();
+    let base = base.as_deref().unwrap_or(DEFAULT_API_BASE_URL);
+    format!("{base}/{path}")
+}
+
+/// Where a page of a speedrun.com listing sits, from its `pagination` object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrcPagination {
+    pub offset: usize,
+    pub max: usize,
+    /// The API doesn't count the items of a listing, this is only known
+    /// once the last page is reached.
+    pub total: Option<usize>,
+    /// `offset` and `max` of the next page, from its `rel="next"` link.
+    next: Option<(usize, usize)>,
+}
+
+impl SrcPagination {
+    /// Reads the `pagination` object of a listing response.
+    pub fn from_json(pagination: &Value) -> Option<Self> {
+        let offset = pagination["offset"].as_u64()? as usize;
+        let max = pagination["max"].as_u64()? as usize;
+        let size = pagination["size"].as_u64().unwrap_or_default() as usize;
+
+        let next = pagination["links"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|link| link["rel"] == "next")
+            .and_then(|link| Url::parse(link["uri"].as_str()?).ok())
+            .and_then(|uri| {
+                let param = |name: &str| {
+                    uri.query_pairs()
+                        .find(|(key, _)| key == name)
+                        .and_then(|(_, value)| value.parse::<usize>().ok())
+                };
+                Some((param("offset")?, param("max").unwrap_or(max)))
+            });
+
+        Some(Self {
+            offset,
+            max,
+            total: next.is_none().then_some(offset + size),
+            next,
+        })
+    }
+
+    /// The next page of the listing at `url`: `url` with the `offset` and
+    /// `max` of the next page, whatever else the API's own link has.
+    pub fn next_url(&self, url: &Url) -> Option<Url> {
+        let (offset, max) = self.next?;
+        let mut next = url.clone();
+        let query = url
+            .query_pairs()
+            .filter(|(key, _)| key != "offset" && key != "max")
+            .map(|(key, value)| (key.into_owned(), value.into_owned()))
+            .collect::<Vec<_>>();
+        next.query_pairs_mut()
+            .clear()
+            .extend_pairs(query)
+            .append_pair("offset", &offset.to_string())
+            .append_pair("max", &max.to_string());
+        Some(next)
+    }
+}
+
+/// Whether a response is worth asking for again: rate limiting and server
+/// errors usually pass.
+fn retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Whether a request failed before getting any response, in a way that may
+/// pass: the connection was refused or something timed out. Anything else,
+/// like a body that can't be decoded, would fail the same way again.
+fn retryable_error(e: &reqwest::Error) -> bool {
+    e.is_connect() || e.is_timeout()
+}
+
+/// How long to wait before retry number `retry`, counting from 0.
+fn backoff(retry: u32) -> Duration {
+    INITIAL_BACKOFF * 2u32.pow(retry)
+}
+
+/// Sends `request`, retrying rate limited, failed and timed out attempts with
+/// an exponential backoff. A `Retry-After` from the server is waited out
+/// instead when it's longer, up to [`MAX_RETRY_AFTER`].
+///
+/// The response is returned whatever its status once attempts run out, so
+/// callers still see the last error status.
+pub async fn fetch_with_retry(request: RequestBuilder) -> Result<Response> {
+    let mut retry = 0;
+    loop {
+        // Only bodies that can't be cloned, like streams, fail this, and the
+        // API requests don't have those.
+        let attempt = request
+            .try_clone()
+            .context("A request with a streamed body can't be retried")?;
+        let last = retry + 1 >= MAX_ATTEMPTS;
+
+        let wait = match attempt.send().await {
+            Ok(response) if last || !retryable_status(response.status()) => return Ok(response),
+            Ok(response) => {
+                let retry_after = response
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok()?.parse().ok())
+                    .map(Duration::from_secs);
+                if let Some(retry_after) = retry_after.filter(|wait| *wait > MAX_RETRY_AFTER) {
+                    tracing::warn!(
+                        "{} asked to retry in {}s, giving up",
+                        response.url(),
+                        retry_after.as_secs()
+                    );
+                    return Ok(response);
+                }
+                tracing::debug!(
+                    "{} answered {}, retrying",
+                    response.url(),
+                    response.status()
+                );
+                retry_after.unwrap_or_default().max(backoff(retry))
+            }
+            Err(e) if !last && retryable_error(&e) => {
+                tracing::debug!("Request failed, retrying: {e}");
+                backoff(retry)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        tokio::time::sleep(wait).await;
+        retry += 1;
+    }
+}
+
+pub trait RequestExt {
+    /// [`fetch_with_retry`], chained onto the request.
+    fn send_with_retry(self) -> impl Future<Output = Result<Response>> + Send;
+}
+
+impl RequestExt for RequestBuilder {
+    async fn send_with_retry(self) -> Result<Response> {
+        fetch_with_retry(self).await
+    }
+}
+
+pub trait ResponseExt {
+    /// Reads the whole body, failing past `--max-response-size`.
+    fn bytes_limited(self) -> impl Future<Output = Result<Vec<u8>>> + Send;
+
+    /// [`ResponseExt::bytes_limited`], parsed as JSON.
+    fn json_limited<T: DeserializeOwned>(self) -> impl Future<Output = Result<T>> + Send;
+}
+
+impl ResponseExt for reqwest::Response {
+    async fn bytes_limited(mut self) -> Result<Vec<u8>> {
+        let limit = MAX_RESPONSE_SIZE.load(Ordering::Relaxed);
+        let url = self.url().clone();
+
+        if let Some(len) = self.content_length().filter(|len| *len > limit) {
+            bail!(
+                "Response from {url} is {}, over the {} limit of --max-response-size",
+                ByteSize(len),
+                ByteSize(limit)
+            );
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = self.chunk().await? {
+            body.extend_from_slice(&chunk);
+            if body.len() as u64 > limit {
+                bail!(
+                    "Response from {url} goes over the {} limit of --max-response-size",
+                    ByteSize(limit)
+                );
+            }
+        }
+
+        Ok(body)
+    }
+
+    async fn json_limited<T: DeserializeOwned>(self) -> Result<T> {
+        let url = self.url().clone();
+        let body = self.bytes_limited().await?;
+        if json_depth_exceeds(&body, MAX_JSON_DEPTH.load(Ordering::Relaxed)) {
+            bail!("API response JSON from {url} is too deeply nested");
+        }
+        serde_json::from_slice(&body).with_context(|| format!("Invalid JSON from {url}"))
+    }
+}
+
+/// Whether arrays and objects in `json` nest deeper than `limit`. Only
+/// brackets are counted, the rest is left for the parser to reject.
+fn json_depth_exceeds(json: &[u8], limit: usize) -> bool {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for &byte in json {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                if depth > limit {
+                    return true;
+                }
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn pagination(offset: u64, size: u64, next: Option<&str>) -> Value {
+        let links = next
+            .map(|uri| vec![json!({ "rel": "next", "uri": uri })])
+            .unwrap_or_default();
+        json!({ "offset": offset, "max": 100, "size": size, "links": links })
+    }
+
+    #[test]
+    fn reads_pagination() {
+        let page = SrcPagination::from_json(&pagination(
+            0,
+            100,
+            Some("https://www.speedrun.com/api/v1/runs?game=x&offset=100&max=100"),
+        ))
+        .unwrap();
+        assert_eq!((page.offset, page.max, page.total), (0, 100, None));
+
+        let last = SrcPagination::from_json(&pagination(100, 42, None)).unwrap();
+        assert_eq!(last.total, Some(142));
+
+        assert_eq!(SrcPagination::from_json(&Value::Null), None);
+    }
+
+    #[test]
+    fn builds_next_page_urls() {
+        let url = Url::parse("https://mirror.test/runs?game=x&status=new&max=100").unwrap();
+        let page = SrcPagination::from_json(&pagination(
+            0,
+            100,
+            Some("https://www.speedrun.com/api/v1/runs?offset=100&max=50"),
+        ))
+        .unwrap();
+
+        // The base URL asked for stays, whatever host the API links to.
+        assert_eq!(
+            page.next_url(&url).unwrap().as_str(),
+            "https://mirror.test/runs?game=x&status=new&offset=100&max=50"
+        );
+
+        let last = SrcPagination::from_json(&pagination(100, 3, None)).unwrap();
+        assert_eq!(last.next_url(&url), None);
+    }
+
+    #[test]
+    fn measures_json_depth() {
+        assert!(!json_depth_exceeds(br#"{"a":[1,{"b":2}]}"#, 3));
+        assert!(json_depth_exceeds(br#"{"a":[1,{"b":2}]}"#, 2));
+        // Brackets in strings don't count.
+        assert!(!json_depth_exceeds(br#"{"a":"[[[[\"{{{{"}"#, 1));
+        assert!(json_depth_exceeds(&[b'['; 129], 128));
+    }
+
+    #[test]
+    fn retries_what_may_pass() {
+        assert!(retryable_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(retryable_status(StatusCode::BAD_GATEWAY));
+        assert!(!retryable_status(StatusCode::NOT_FOUND));
+        assert!(!retryable_status(StatusCode::OK));
+
+        assert_eq!(backoff(0), INITIAL_BACKOFF);
+        assert_eq!(backoff(3), INITIAL_BACKOFF * 8);
+    }
+}
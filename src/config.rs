@@ -0,0 +1,749 @@
+use std::{collections::HashMap, fmt, fs, io, path::PathBuf, time::Duration};
+
+use anyhow::{Context, Result};
+use bytesize::ByteSize;
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+
+use crate::{
+    encode::{parse_output_format, Codec, FfmpegLogLevel, HwDecode, OutputFormat},
+    logging::{LogFormat, TimestampFormat},
+    postprocess::{parse_grid, parse_timestamp},
+};
+
+const SIGNALS_HELP: &str = "\
+Signals:
+  SIGINT (Ctrl+C)  Abort the current download and exit with code 1.
+  SIGTERM          Stop the current download cleanly and exit with code 0.";
+
+const DEFAULT_USER_AGENT: &str = concat!(
+    "dlvod/",
+    env!("CARGO_PKG_VERSION"),
+    " (https://github.com/veeenu/dlvod)"
+);
+
+/// Download pending speedrun.com runs for review.
+#[derive(Debug, Clone, Parser)]
+#[command(about, disable_version_flag = true, after_help = SIGNALS_HELP)]
+pub struct Config {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Print the versions of dlvod, ffmpeg and yt-dlp.
+    #[arg(short = 'V', long)]
+    pub version: bool,
+
+    /// Print --version as JSON.
+    #[arg(long, requires = "version")]
+    pub json: bool,
+
+    /// Configuration file. Defaults to `config.toml` in the working directory,
+    /// which is optional.
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// speedrun.com API key, required to verify or reject runs.
+    #[arg(long, env = "DLVOD_API_KEY", hide_env_values = true)]
+    pub api_key: Option<String>,
+
+    /// speedrun.com API to talk to instead of
+    /// `https://www.speedrun.com/api/v1`, like a mirror or a test server.
+    /// Overrides `api_base_url` in the configuration file.
+    #[arg(long, value_name = "URL")]
+    pub api_base_url: Option<String>,
+
+    /// Reject with this rejection template instead of prompting for a reason.
+    #[arg(long, value_name = "KEY")]
+    pub rejection_key: Option<String>,
+
+    /// Print plain text without ANSI colors, and country codes instead of
+    /// flags.
+    #[arg(long)]
+    pub no_color: bool,
+
+    /// Download these runs directly, without listing pending runs.
+    #[arg(long = "run-id", value_name = "ID", num_args = 1..)]
+    pub run_ids: Vec<String>,
+
+    /// For runs with several videos, download this one instead of asking,
+    /// counting from 0.
+    #[arg(long, value_name = "N")]
+    pub vod_index: Option<usize>,
+
+    /// Only list runs by this player.
+    #[arg(long, value_name = "NAME")]
+    pub player: Option<String>,
+
+    /// Only list runs of this category.
+    #[arg(long, value_name = "NAME")]
+    pub category: Option<String>,
+
+    /// Only fetch runs of the category with this API ID. --category is
+    /// resolved to an ID too where it names a category of the game.
+    #[arg(long, value_name = "ID", conflicts_with = "category")]
+    pub category_id: Option<String>,
+
+    /// List runs without their platform and region, so the API sends less.
+    /// Those the filters, filename template, hooks or --write-metadata use
+    /// are still fetched.
+    #[arg(long)]
+    pub brief: bool,
+
+    /// List at most this many runs per game, the oldest submissions, so one
+    /// busy game doesn't crowd out the others.
+    #[arg(long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub max_per_game: Option<usize>,
+
+    /// Verify or reject every listed run instead of prompting for one.
+    /// Combine with --player and --category to narrow the scope.
+    #[arg(long, value_enum)]
+    pub bulk_action: Option<BulkAction>,
+
+    /// Rejection reason for --bulk-action reject. Falls back to
+    /// --rejection-key or a prompt.
+    #[arg(long, value_name = "TEXT")]
+    pub bulk_reason: Option<String>,
+
+    /// Reject every pending run of this player, in all the configured games,
+    /// after listing them. Needs --rejection-reason and --yes.
+    #[arg(long, value_name = "NAME", requires = "rejection_reason")]
+    pub reject_player: Option<String>,
+
+    /// Rejection reason for --reject-player.
+    #[arg(long, value_name = "TEXT", requires = "reject_player")]
+    pub rejection_reason: Option<String>,
+
+    /// Confirm destructive bulk operations.
+    #[arg(long)]
+    pub yes: bool,
+
+    /// Show which runs would be auto-verified instead of verifying them, and
+    /// print the yt-dlp and ffmpeg commands of each download instead of
+    /// running them.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// After verifying or rejecting a download, rename it to
+    /// `<name>_verified_<date>` or `<name>_rejected_<date>`.
+    #[arg(long)]
+    pub rename_reviewed: bool,
+
+    /// Template for output filenames, without extension.
+    ///
+    /// Available placeholders: {player}, {game}, {cat}, {run_id}, {platform},
+    /// {variables} (subcategory values, dash-separated). Only ASCII letters,
+    /// digits, `-` and `_` are kept, up to 200 characters.
+    #[arg(long, default_value = DEFAULT_FILENAME_TEMPLATE)]
+    pub filename_template: String,
+
+    /// Only list runs submitted for this region (e.g. "JPN / NTSC").
+    #[arg(long, value_name = "NAME")]
+    pub filter_region: Option<String>,
+
+    /// Directory downloads are written to.
+    #[arg(long, default_value = ".")]
+    pub output_dir: PathBuf,
+
+    /// Directory for intermediate files, instead of `temp_dir` in the config
+    /// file or the system's temporary directory.
+    #[arg(long, value_name = "PATH")]
+    pub temp_dir: Option<PathBuf>,
+
+    /// How to lay out downloads inside the output directory.
+    #[arg(long, value_enum, default_value_t = OrganizeBy::None)]
+    pub organize_by: OrganizeBy,
+
+    /// Keep the `.part` file of a failed download instead of removing it.
+    #[arg(long)]
+    pub keep_partial: bool,
+
+    /// What to do when the output file already exists.
+    #[arg(long, value_enum, default_value_t = CollisionStrategy::Skip)]
+    pub on_collision: CollisionStrategy,
+
+    /// Select and download several runs in one session.
+    #[arg(long)]
+    pub batch: bool,
+
+    /// Write an M3U8 playlist of the runs downloaded in this session.
+    #[arg(long, value_name = "PATH")]
+    pub playlist: Option<PathBuf>,
+
+    /// Use absolute paths in the playlist instead of paths relative to it.
+    #[arg(long, requires = "playlist")]
+    pub playlist_absolute: bool,
+
+    /// Kill and retry a download that produced no output for this many seconds.
+    #[arg(long, value_name = "SECONDS", default_value_t = 120)]
+    pub stall_timeout: u64,
+
+    /// When a streamed download fails, keep what was encoded and retry from
+    /// its last keyframe instead of starting over.
+    #[arg(long)]
+    pub resume: bool,
+
+    /// yt-dlp format selector passed as `-f`. Defaults to yt-dlp's own choice
+    /// (`bestvideo+bestaudio/best`).
+    ///
+    /// Common values: `best` (best single stream, streams most reliably),
+    /// `best[height<=1080]`, `bestvideo[height<=720]+bestaudio/best[height<=720]`,
+    /// `worst` (fastest to fetch for a quick check).
+    #[arg(long, value_name = "FORMAT")]
+    pub yt_format: Option<String>,
+
+    /// Number of fragments yt-dlp downloads concurrently (`-N`).
+    #[arg(long, value_name = "N", default_value_t = 8, value_parser = clap::value_parser!(u32).range(1..=32))]
+    pub ytdlp_threads: u32,
+
+    /// Don't pass `-N` to yt-dlp, leaving the fragment concurrency to its default.
+    #[arg(long, conflicts_with = "ytdlp_threads")]
+    pub ytdlp_no_threads: bool,
+
+    /// Run yt-dlp with `--verbose` instead of `-q`, printing what it logs
+    /// besides its progress on stderr.
+    #[arg(long)]
+    pub ytdlp_verbose: bool,
+
+    /// ffmpeg executable to run instead of the one in `PATH`. ffprobe is
+    /// expected in the same directory.
+    #[arg(long, value_name = "PATH", env = "DLVOD_FFMPEG_PATH")]
+    pub ffmpeg_path: Option<PathBuf>,
+
+    /// yt-dlp executable to run instead of the one in `PATH`.
+    #[arg(long, value_name = "PATH", env = "DLVOD_YTDLP_PATH")]
+    pub ytdlp_path: Option<PathBuf>,
+
+    /// User-Agent sent with every HTTP request.
+    #[arg(long, value_name = "STRING", default_value = DEFAULT_USER_AGENT)]
+    pub user_agent: String,
+
+    /// Idle HTTP connections kept open per host for reuse.
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    pub max_connections: usize,
+
+    /// Give up connecting to the API, or any other host, after this many
+    /// seconds. Defaults to `api_connect_timeout` from the config file, or 10.
+    #[arg(long, alias = "connect-timeout", value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub api_connect_timeout: Option<u64>,
+
+    /// Give up on an API request that hasn't completed after this many
+    /// seconds, and retry it like other failures. Defaults to `api_timeout`
+    /// from the config file, or 30.
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub api_timeout: Option<u64>,
+
+    /// Talk HTTP/2 to the speedrun.com API from the first request, without
+    /// negotiating it. Falls back to HTTP/1.1 if the API doesn't answer.
+    #[arg(long)]
+    pub http2: bool,
+
+    /// Resolve hostnames through this DNS-over-HTTPS server, e.g.
+    /// `https://cloudflare-dns.com/dns-query`. yt-dlp is pointed at a local
+    /// proxy that resolves them the same way.
+    #[cfg(feature = "doh")]
+    #[arg(long, value_name = "URL")]
+    pub doh: Option<String>,
+
+    /// Reject HTTP responses bigger than this, e.g. `10MB`.
+    #[arg(long, value_name = "SIZE", default_value = "10MB")]
+    pub max_response_size: ByteSize,
+
+    /// Reject API responses with JSON nested deeper than this. Parsing stops
+    /// at 128 levels regardless.
+    #[arg(long, value_name = "N", default_value_t = 64)]
+    pub max_json_depth: usize,
+
+    /// Don't verify TLS certificates, e.g. behind an intercepting proxy.
+    /// Also passed on to yt-dlp.
+    #[arg(short = 'k', long, conflicts_with = "ca_bundle")]
+    pub insecure: bool,
+
+    /// Trust the CA certificate in this PEM file for API requests and
+    /// segmented downloads, on top of the system ones.
+    #[arg(long, value_name = "PATH")]
+    pub ca_bundle: Option<PathBuf>,
+
+    /// How long Ctrl+C waits for the download to clean up before exiting
+    /// anyway, in milliseconds.
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    pub ctrlc_timeout: u64,
+
+    /// How often to check whether yt-dlp and ffmpeg have exited, in
+    /// milliseconds. Longer intervals mean fewer wakeups with many parallel
+    /// downloads, and a slower reaction to Ctrl+C.
+    #[arg(long, value_name = "MS", default_value_t = 100, value_parser = clap::value_parser!(u64).range(10..=5000))]
+    pub process_poll_interval: u64,
+
+    /// Exit code when some downloads of a batch fail but not all of them. A
+    /// batch where all downloads fail exits with 2.
+    #[arg(long, value_name = "CODE", default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..))]
+    pub exit_code_on_partial_failure: u8,
+
+    /// Number of runs downloaded at the same time when several are selected,
+    /// or `auto` to follow the CPU load, up to --parallel-max.
+    #[arg(long, value_name = "N|auto", default_value = "1", value_parser = parse_parallel)]
+    pub parallel: Parallel,
+
+    /// Most runs `--parallel auto` downloads at the same time.
+    #[arg(long, value_name = "N", default_value_t = 8, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub parallel_max: usize,
+
+    /// Size of the buffer between yt-dlp and ffmpeg, e.g. `64KiB` or `16M`.
+    #[arg(long, value_name = "SIZE", default_value = "64KiB")]
+    pub pipe_buffer_size: ByteSize,
+
+    /// Cap on the buffers of all parallel downloads together; each download's
+    /// buffer shrinks to its share of it.
+    #[arg(long, value_name = "SIZE")]
+    pub max_pipe_memory: Option<ByteSize>,
+
+    /// Bridge yt-dlp into ffmpeg with io_uring instead of blocking reads and
+    /// writes.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[arg(long)]
+    pub use_io_uring: bool,
+
+    /// Accept JSON commands (status, pause, resume, abort) on a Unix socket at
+    /// this path.
+    #[arg(long, value_name = "PATH")]
+    pub control_socket: Option<PathBuf>,
+
+    /// Export tracing spans to this OpenTelemetry collector, over OTLP/HTTP
+    /// (e.g. `http://localhost:4318/v1/traces`).
+    #[cfg(feature = "telemetry")]
+    #[arg(long, value_name = "URL")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Append warnings and errors to this file, with timestamps.
+    #[arg(long, value_name = "PATH")]
+    pub error_log: Option<PathBuf>,
+
+    /// Format of log entries, on stderr and in --error-log.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Log debug messages on stderr, unless `RUST_LOG` says otherwise.
+    #[arg(short, long)]
+    pub verbose: bool,
+
+    /// What the transcoding ffmpeg logs. Defaults to `warning`, or `verbose`
+    /// with --verbose.
+    #[arg(long, value_enum, value_name = "LEVEL")]
+    pub ffmpeg_loglevel: Option<FfmpegLogLevel>,
+
+    /// Timestamp on each log entry.
+    #[arg(long, value_enum, default_value_t = TimestampFormat::Rfc3339)]
+    pub log_timestamp_format: TimestampFormat,
+
+    /// Don't check that ffmpeg and yt-dlp are installed and recent enough.
+    #[arg(long)]
+    pub skip_dependency_check: bool,
+
+    /// Don't look for a newer yt-dlp at startup. Also skipped when `CI` is set.
+    #[arg(long)]
+    pub skip_update_check: bool,
+
+    /// Video encoder used for the transcode.
+    #[arg(long, value_enum, default_value_t = Codec::Auto)]
+    pub codec: Codec,
+
+    /// Also encode the stream into these formats while downloading, next to
+    /// the output: a container like `mkv`, transcoded like the output, or
+    /// `mkv:copy` to keep the source streams. Post-processing only applies to
+    /// the main output.
+    #[arg(
+        long,
+        value_name = "FORMAT,...",
+        value_delimiter = ',',
+        value_parser = parse_output_format,
+        conflicts_with_all = ["segment_size", "resume"]
+    )]
+    pub multi_output: Vec<OutputFormat>,
+
+    /// Hardware decoding, enabled automatically with the NVENC and VA-API encoders.
+    #[arg(long, value_enum, default_value_t = HwDecode::Auto)]
+    pub hwdecode: HwDecode,
+
+    /// Output frame rate [default: 30].
+    #[arg(long)]
+    pub fps: Option<u32>,
+
+    /// Output width in pixels, keeping the aspect ratio [default: 896].
+    #[arg(long, value_name = "WIDTH")]
+    pub resolution: Option<u32>,
+
+    /// ffmpeg video filter chain replacing the built-in one, which also makes
+    /// --fps and --resolution ineffective.
+    #[arg(long, value_name = "FILTERS", conflicts_with = "vf_append")]
+    pub vf: Option<String>,
+
+    /// ffmpeg video filter appended to the built-in chain. Can be repeated.
+    #[arg(long, value_name = "FILTER")]
+    pub vf_append: Vec<String>,
+
+    /// Burn the VOD's subtitles into the video. The VOD is saved next to the
+    /// output first and transcoded from there, which needs room for both.
+    #[arg(long)]
+    pub burn_subtitles: bool,
+
+    /// Also save a short animated GIF from the middle of the run, next to the
+    /// download.
+    #[arg(long, overrides_with = "no_thumbnail_gif")]
+    pub thumbnail_gif: bool,
+
+    /// Don't save a GIF preview, overriding --thumbnail-gif.
+    #[arg(long)]
+    pub no_thumbnail_gif: bool,
+
+    /// Length of the GIF preview.
+    #[arg(long, value_name = "SECONDS", default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    pub thumbnail_gif_duration: u64,
+
+    /// Also save a sprite sheet of COLSxROWS thumbnails spread over the run,
+    /// e.g. `10x10`, as `<filename>_sprites.jpg` for scrubbing previews.
+    #[arg(long, value_name = "COLSxROWS", value_parser = parse_grid)]
+    pub sprite_sheet: Option<(u32, u32)>,
+
+    /// After each download, fetch the VOD again at its original quality and
+    /// compute the transcode's VMAF score against it, also saved by
+    /// --write-metadata. Needs ffmpeg built with libvmaf.
+    #[arg(long)]
+    pub measure_quality: bool,
+
+    /// After each download, extract frames to JPEG files in
+    /// `<filename>_frames/`, for frame-by-frame review.
+    #[arg(long)]
+    pub output_images: bool,
+
+    /// Where --output-images starts extracting frames.
+    #[arg(long, value_name = "HH:MM:SS", default_value = "0", value_parser = parse_timestamp, requires = "output_images")]
+    pub start_time: Duration,
+
+    /// How long --output-images extracts frames for.
+    #[arg(long, value_name = "SECONDS", default_value = "5", value_parser = parse_timestamp, requires = "output_images")]
+    pub duration: Duration,
+
+    /// Frames per second extracted by --output-images.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 10.0,
+        value_parser = parse_fps,
+        requires = "output_images"
+    )]
+    pub fps_images: f32,
+
+    /// Also save `<filename>.json` next to each download, with the run's
+    /// metadata and the yt-dlp and ffmpeg command lines it was downloaded with.
+    #[arg(long)]
+    pub write_metadata: bool,
+
+    /// Normalize audio loudness with ffmpeg's loudnorm filter.
+    #[arg(long)]
+    pub normalize_audio: bool,
+
+    /// Measure loudness over the whole download first for a more accurate
+    /// --normalize-audio, at the cost of a second audio encode.
+    #[arg(long, requires = "normalize_audio")]
+    pub two_pass: bool,
+
+    /// DRM render node used by the VA-API encoder.
+    #[arg(long, value_name = "PATH", default_value = "/dev/dri/renderD128")]
+    pub vaapi_device: PathBuf,
+
+    /// Fetch progressive VODs as parallel byte ranges of this many megabytes,
+    /// merging them before transcoding. HLS sources keep streaming through
+    /// yt-dlp, which already fetches their fragments concurrently.
+    #[arg(long, value_name = "MB", value_parser = clap::value_parser!(u64).range(1..))]
+    pub segment_size: Option<u64>,
+
+    /// Timestamp in the VOD where the run starts, used to add "Pre-run" and
+    /// "Run Start" chapters. A `<filename>.chapters` file next to the output,
+    /// with one `<hh:mm:ss> <title>` per line, takes precedence.
+    #[arg(long, value_name = "HH:MM:SS", value_parser = parse_timestamp)]
+    pub run_start: Option<Duration>,
+
+    /// Serve an HTTP API for listing and queueing downloads instead of
+    /// prompting for a run.
+    #[arg(long)]
+    pub server: bool,
+
+    /// Port the HTTP API listens on.
+    #[arg(long, default_value_t = 8080, requires = "server")]
+    pub port: u16,
+
+    /// Address the HTTP API binds to.
+    #[arg(long, default_value = "127.0.0.1", requires = "server")]
+    pub server_host: String,
+
+    /// Require `Authorization: Bearer <token>` on every HTTP API request.
+    #[arg(long, env = "DLVOD_SERVER_TOKEN", hide_env_values = true)]
+    pub server_token: Option<String>,
+
+    #[arg(skip)]
+    pub file: FileConfig,
+}
+
+/// Games listed when the configuration file doesn't set `games`.
+const DEFAULT_GAMES: &[&str] = &["nd28z0ed", "k6qg0xdg", "k6qp429d"];
+
+pub const DEFAULT_FILENAME_TEMPLATE: &str = "{player}-{game}-{cat}-{run_id}";
+
+const DEFAULT_API_CONNECT_TIMEOUT: u64 = 10;
+const DEFAULT_API_TIMEOUT: u64 = 30;
+
+/// Settings read from the configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    /// Games whose pending runs are listed, as API IDs or abbreviations.
+    pub games: Vec<String>,
+
+    /// Canned rejection reasons, by short key.
+    pub rejection_templates: HashMap<String, String>,
+
+    /// Verify runs slower than the world record of their subcategory by at
+    /// most this percentage. Runs without a video, by banned players, or
+    /// whose record couldn't be found are left pending.
+    pub auto_verify_within_wr_percent: Option<f64>,
+
+    pub auto_verify: AutoVerify,
+
+    /// Players whose runs are flagged, and rejected with `auto_reject.apply`.
+    /// Matched ignoring case.
+    pub banned_players: Vec<String>,
+
+    /// URL of a JSON array of player names, added to `banned_players`.
+    pub ban_list_url: Option<String>,
+
+    pub auto_reject: AutoReject,
+
+    /// Shell command run before each download, with the run's metadata in
+    /// `DLVOD_*` environment variables. Failing aborts the download.
+    pub pre_download_hook: Option<String>,
+
+    /// Shell command run after each download, like `pre_download_hook` plus
+    /// `DLVOD_OUTPUT_PATH`. Failing only logs a warning.
+    pub post_download_hook: Option<String>,
+
+    /// Directory for intermediate files, overridden by `--temp-dir`.
+    pub temp_dir: Option<PathBuf>,
+
+    /// speedrun.com API base URL, overridden by `--api-base-url`.
+    pub api_base_url: Option<String>,
+
+    /// Seconds to connect to a host, overridden by `--api-connect-timeout`.
+    pub api_connect_timeout: Option<u64>,
+
+    /// Seconds for an API request to complete, overridden by `--api-timeout`.
+    pub api_timeout: Option<u64>,
+}
+
+/// The `[auto_verify]` table.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AutoVerify {
+    /// Must be set for `auto_verify_within_wr_percent` to verify anything.
+    pub apply: bool,
+}
+
+/// The `[auto_reject]` table.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AutoReject {
+    /// Must be set for runs by `banned_players` to be rejected.
+    pub apply: bool,
+
+    /// Rejection reason given to those runs.
+    pub reason: String,
+}
+
+impl Default for AutoReject {
+    fn default() -> Self {
+        Self {
+            apply: false,
+            reason: "Runs from this player are not accepted.".to_string(),
+        }
+    }
+}
+
+impl Default for FileConfig {
+    fn default() -> Self {
+        Self {
+            games: DEFAULT_GAMES.iter().map(|id| id.to_string()).collect(),
+            rejection_templates: HashMap::new(),
+            auto_verify_within_wr_percent: None,
+            auto_verify: AutoVerify::default(),
+            banned_players: Vec::new(),
+            ban_list_url: None,
+            auto_reject: AutoReject::default(),
+            pre_download_hook: None,
+            post_download_hook: None,
+            temp_dir: None,
+            api_base_url: None,
+            api_connect_timeout: None,
+            api_timeout: None,
+        }
+    }
+}
+
+impl Config {
+    /// Parses the command line and loads the configuration file.
+    pub fn load() -> Result<Self> {
+        let mut cfg = Self::parse();
+
+        let path = cfg
+            .config
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("config.toml"));
+
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                cfg.file = toml::from_str(&text)
+                    .with_context(|| format!("Couldn't parse {}", path.display()))?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound && cfg.config.is_none() => {}
+            Err(e) => return Err(e).with_context(|| format!("Couldn't read {}", path.display())),
+        }
+
+        Ok(cfg)
+    }
+
+    /// The most downloads that can run at the same time.
+    pub fn max_parallel(&self) -> usize {
+        match self.parallel {
+            Parallel::Fixed(n) => n,
+            Parallel::Auto => self.parallel_max,
+        }
+    }
+
+    /// `--ffmpeg-loglevel`, defaulting on `--verbose`.
+    pub fn ffmpeg_loglevel(&self) -> FfmpegLogLevel {
+        self.ffmpeg_loglevel.unwrap_or(if self.verbose {
+            FfmpegLogLevel::Verbose
+        } else {
+            FfmpegLogLevel::Warning
+        })
+    }
+
+    /// `--api-base-url`, then `api_base_url` from the config file.
+    pub fn api_base_url(&self) -> Option<&str> {
+        self.api_base_url
+            .as_deref()
+            .or(self.file.api_base_url.as_deref())
+    }
+
+    /// `--api-connect-timeout`, then `api_connect_timeout` from the config file.
+    pub fn api_connect_timeout(&self) -> Duration {
+        let secs = self
+            .api_connect_timeout
+            .or(self.file.api_connect_timeout)
+            .unwrap_or(DEFAULT_API_CONNECT_TIMEOUT);
+        Duration::from_secs(secs)
+    }
+
+    /// `--api-timeout`, then `api_timeout` from the config file.
+    pub fn api_timeout(&self) -> Duration {
+        let secs = self
+            .api_timeout
+            .or(self.file.api_timeout)
+            .unwrap_or(DEFAULT_API_TIMEOUT);
+        Duration::from_secs(secs)
+    }
+
+    /// Where intermediate files go: `--temp-dir`, then `temp_dir` from the
+    /// config file, then the system's temporary directory.
+    pub fn temp_dir(&self) -> PathBuf {
+        self.temp_dir
+            .clone()
+            .or_else(|| self.file.temp_dir.clone())
+            .unwrap_or_else(std::env::temp_dir)
+    }
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Print the versions of dlvod, ffmpeg and yt-dlp.
+    Version {
+        /// Print them as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check whether a newer yt-dlp is available.
+    CheckUpdates,
+    /// Summarize the downloads recorded in past sessions.
+    Stats {
+        /// Also show the average download speed per month.
+        #[arg(long)]
+        show_speeds: bool,
+    },
+    /// Show how much disk space the downloads of each game take. Only files
+    /// named by the default --filename-template are counted.
+    Du {
+        /// Directory to scan, instead of --output-dir.
+        #[arg(long, value_name = "PATH")]
+        output_dir: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BulkAction {
+    Verify,
+    Reject,
+}
+
+impl fmt::Display for BulkAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BulkAction::Verify => write!(f, "verify"),
+            BulkAction::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OrganizeBy {
+    /// Put every download directly in the output directory.
+    None,
+    /// `<output_dir>/<game>/`
+    Game,
+    /// `<output_dir>/<game>/<cat>/`
+    GameCategory,
+    /// `<output_dir>/<YYYY-MM-DD>/`, using the download date.
+    Date,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parallel {
+    Fixed(usize),
+    /// Adjusted to the CPU load while downloading.
+    Auto,
+}
+
+fn parse_parallel(s: &str) -> Result<Parallel, String> {
+    if s == "auto" {
+        return Ok(Parallel::Auto);
+    }
+
+    match s.parse() {
+        Ok(0) | Err(_) => Err(format!("expected a number above 0 or `auto`, got {s:?}")),
+        Ok(n) => Ok(Parallel::Fixed(n)),
+    }
+}
+
+fn parse_fps(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(fps) if fps > 0.0 && fps.is_finite() => Ok(fps),
+        _ => Err(format!("expected a number of frames above 0, got {s:?}")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CollisionStrategy {
+    /// Keep the existing file and don't download the run again.
+    Skip,
+    /// Replace the existing file.
+    Overwrite,
+    /// Download to `<name>_1.mp4`, `<name>_2.mp4`, ... whichever is free first.
+    Rename,
+}
@@ -0,0 +1,193 @@
+//! `--control-socket`: drive a running instance over a Unix socket.
+//!
+//! Each connection sends newline-delimited JSON commands and gets one JSON
+//! line back per command:
+//!
+//! - `{"cmd":"status"}`: the download in flight and how much was written.
+//! - `{"cmd":"pause"}` / `{"cmd":"resume"}`: hold or release the copy loop.
+//! - `{"cmd":"abort"}`: stop the download in flight, like Ctrl+C, but carry
+//!   on with the next ones.
+
+use std::{
+    fs, io,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+use crate::DownloadStatus;
+
+#[derive(Deserialize)]
+#[serde(tag = "cmd", rename_all = "lowercase")]
+enum Command {
+    Status,
+    Pause,
+    Resume,
+    Abort,
+}
+
+/// Removes the socket file when dropped.
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        remove_socket(&self.path);
+    }
+}
+
+pub fn remove_socket(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            tracing::warn!("Couldn't remove {}: {e}", path.display());
+        }
+    }
+}
+
+/// Listens on `path` for commands until the returned guard is dropped or the
+/// process exits.
+pub fn start(
+    path: &Path,
+    status: Arc<DownloadStatus>,
+    done: Arc<AtomicBool>,
+) -> Result<ControlSocket> {
+    // A previous instance that was killed may have left its socket behind.
+    // Anything else there is left alone.
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            fs::remove_file(path).with_context(|| format!("Couldn't remove {}", path.display()))?;
+        }
+        Ok(_) => bail!(
+            "{} already exists and isn't a socket, refusing to replace it",
+            path.display()
+        ),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("Couldn't check {}", path.display())),
+    }
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Couldn't create control socket {}", path.display()))?;
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_connection(
+                        stream,
+                        Arc::clone(&status),
+                        Arc::clone(&done),
+                    ));
+                }
+                Err(e) => {
+                    tracing::warn!("Control socket error: {e}");
+                    return;
+                }
+            }
+        }
+    });
+
+    Ok(ControlSocket {
+        path: path.to_path_buf(),
+    })
+}
+
+async fn handle_connection(stream: UnixStream, status: Arc<DownloadStatus>, done: Arc<AtomicBool>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str(&line) {
+            Ok(command) => execute(command, &status, &done),
+            Err(e) => json!({ "ok": false, "error": e.to_string() }),
+        };
+
+        let mut response = response.to_string();
+        response.push('\n');
+        if writer.write_all(response.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn execute(command: Command, status: &DownloadStatus, done: &AtomicBool) -> Value {
+    match command {
+        Command::Status => {
+            let current = status.current.lock().unwrap().clone();
+            json!({
+                "ok": true,
+                "run": current.as_ref().map(|(run, _)| run),
+                "elapsed_secs": current.map(|(_, start)| start.elapsed().as_secs()),
+                "bytes_written": status.bytes_written.load(Ordering::SeqCst),
+                "paused": status.paused.load(Ordering::SeqCst),
+            })
+        }
+        Command::Pause => {
+            status.paused.store(true, Ordering::SeqCst);
+            json!({ "ok": true })
+        }
+        Command::Resume => {
+            status.paused.store(false, Ordering::SeqCst);
+            json!({ "ok": true })
+        }
+        Command::Abort => {
+            // `done` would otherwise stay set and stop the next download.
+            if status.current.lock().unwrap().is_none() {
+                return json!({ "ok": false, "error": "No download in progress" });
+            }
+            done.store(true, Ordering::SeqCst);
+            json!({ "ok": true })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replaces_stale_sockets_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let status = Arc::new(DownloadStatus::default());
+        let done = Arc::new(AtomicBool::new(false));
+
+        let stale = dir.path().join("stale.sock");
+        drop(std::os::unix::net::UnixListener::bind(&stale).unwrap());
+        let guard = start(&stale, Arc::clone(&status), Arc::clone(&done)).unwrap();
+        drop(guard);
+        assert!(!stale.exists());
+
+        let file = dir.path().join("notes.txt");
+        fs::write(&file, "keep me").unwrap();
+        assert!(start(&file, status, done).is_err());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "keep me");
+    }
+
+    #[test]
+    fn aborts_only_a_download_in_flight() {
+        let status = DownloadStatus::default();
+        let done = AtomicBool::new(false);
+
+        assert_eq!(execute(Command::Abort, &status, &done)["ok"], false);
+        assert!(!done.load(Ordering::SeqCst));
+
+        status.start(&crate::Run::default());
+        assert_eq!(execute(Command::Abort, &status, &done)["ok"], true);
+        assert!(done.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,357 @@
+//! `--doh`: resolving hostnames over DNS-over-HTTPS, for networks that
+//! intercept plain DNS.
+//!
+//! reqwest takes the resolver directly, so the API, segmented downloads and
+//! everything else fetched here skip the system resolver. yt-dlp can't be
+//! given a resolver, so it goes through a local HTTP proxy instead: the proxy
+//! resolves the hosts yt-dlp asks for over DoH and connects to them.
+//!
+//! Either way only the lookup changes. Connections go to the resolved IP, but
+//! the `Host` header and the TLS SNI keep the hostname: reqwest only sees an IP
+//! for the socket, and the proxy tunnels TLS untouched with `CONNECT` and
+//! forwards plain HTTP requests as they were written.
+
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, OnceLock},
+};
+
+use anyhow::{bail, Context, Result};
+use hickory_resolver::{
+    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    TokioAsyncResolver,
+};
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    Url,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::OnceCell,
+};
+
+use crate::config::Config;
+
+/// The only path hickory sends queries to.
+const DNS_QUERY_PATH: &str = "/dns-query";
+
+/// Request heads bigger than this are refused by the proxy.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+static RESOLVER: OnceLock<DohResolver> = OnceLock::new();
+static PROXY_URL: OnceLock<String> = OnceLock::new();
+
+/// A resolver querying one DoH server, set up on the first lookup.
+#[derive(Clone)]
+pub struct DohResolver(Arc<Inner>);
+
+struct Inner {
+    url: Url,
+    resolver: OnceCell<TokioAsyncResolver>,
+}
+
+impl DohResolver {
+    /// A resolver for the DoH server at `url`, like
+    /// `https://cloudflare-dns.com/dns-query`.
+    pub fn new(url: &str) -> Result<Self> {
+        let parsed = Url::parse(url).with_context(|| format!("Invalid --doh URL {url:?}"))?;
+        if parsed.scheme() != "https" {
+            bail!("--doh needs an https:// URL, got {url:?}");
+        }
+        if parsed.path() != DNS_QUERY_PATH {
+            bail!("--doh only supports servers answering on {DNS_QUERY_PATH}, got {url:?}");
+        }
+        if parsed.host_str().is_none() {
+            bail!("--doh URL {url:?} has no host");
+        }
+
+        Ok(Self(Arc::new(Inner {
+            url: parsed,
+            resolver: OnceCell::new(),
+        })))
+    }
+
+    async fn resolver(&self) -> Result<&TokioAsyncResolver> {
+        self.0
+            .resolver
+            .get_or_try_init(|| async {
+                let url = &self.0.url;
+                let host = url.host_str().unwrap_or_default();
+                let port = url.port_or_known_default().unwrap_or(443);
+
+                // The DoH server itself can only be found through the system
+                // resolver, unless it's given by IP.
+                let ips = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+                    Ok(ip) => vec![ip],
+                    Err(_) => tokio::net::lookup_host((host, port))
+                        .await
+                        .with_context(|| format!("Couldn't resolve the DoH server {host}"))?
+                        .map(|addr| addr.ip())
+                        .collect(),
+                };
+
+                let servers =
+                    NameServerConfigGroup::from_ips_https(&ips, port, host.to_string(), true);
+                let config = ResolverConfig::from_parts(None, Vec::new(), servers);
+                tracing::debug!("Resolving hostnames through {url}");
+                Ok(TokioAsyncResolver::tokio(config, ResolverOpts::default()))
+            })
+            .await
+    }
+
+    /// The addresses of `host`.
+    pub async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>> {
+        let lookup = self
+            .resolver()
+            .await?
+            .lookup_ip(host)
+            .await
+            .with_context(|| format!("Couldn't resolve {host} over DoH"))?;
+        Ok(lookup.into_iter().collect())
+    }
+}
+
+impl Resolve for DohResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let ips = resolver.lookup(name.as_str()).await?;
+            // reqwest fills in the port, and keeps the hostname for the
+            // `Host` header and SNI.
+            let addrs: Addrs = Box::new(
+                ips.into_iter()
+                    .map(|ip| SocketAddr::new(ip, 0))
+                    .collect::<Vec<_>>()
+                    .into_iter(),
+            );
+            Ok(addrs)
+        })
+    }
+}
+
+/// Sets up `--doh` for the session and starts the proxy for yt-dlp.
+pub async fn init(cfg: &Config) -> Result<()> {
+    let Some(url) = &cfg.doh else {
+        return Ok(());
+    };
+
+    let resolver = DohResolver::new(url)?;
+    let resolver = RESOLVER.get_or_init(|| resolver);
+
+    let listener = TcpListener::bind(("127.0.0.1", 0))
+        .await
+        .context("Couldn't start the DoH proxy")?;
+    let addr = listener.local_addr()?;
+    tracing::debug!("DoH proxy for yt-dlp listening on {addr}");
+    let _ = PROXY_URL.set(format!("http://{addr}"));
+
+    let resolver = resolver.clone();
+    tokio::spawn(serve_proxy(listener, move |host| {
+        let resolver = resolver.clone();
+        async move { resolver.lookup(&host).await }
+    }));
+
+    Ok(())
+}
+
+/// The session's resolver, once [`init`] has set one up.
+pub fn resolver() -> Option<DohResolver> {
+    RESOLVER.get().cloned()
+}
+
+/// The proxy yt-dlp should go through, once [`init`] has started one.
+pub fn proxy_url() -> Option<String> {
+    PROXY_URL.get().cloned()
+}
+
+async fn serve_proxy<F, Fut>(listener: TcpListener, lookup: F)
+where
+    F: Fn(String) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Result<Vec<IpAddr>>> + Send,
+{
+    loop {
+        let Ok((client, _)) = listener.accept().await else {
+            continue;
+        };
+        let lookup = lookup.clone();
+        tokio::spawn(async move {
+            if let Err(e) = proxy_connection(client, lookup).await {
+                tracing::debug!("DoH proxy: {e:#}");
+            }
+        });
+    }
+}
+
+/// Serves one proxy connection: a `CONNECT` tunnel, or a plain HTTP request
+/// forwarded as it is, head included.
+async fn proxy_connection<F, Fut>(mut client: TcpStream, lookup: F) -> Result<()>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<Vec<IpAddr>>>,
+{
+    let mut head = Vec::new();
+    let head_len = loop {
+        if let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if head.len() > MAX_HEAD_SIZE {
+            bail!("Request head too big");
+        }
+        let mut buf = [0u8; 4096];
+        let read = client.read(&mut buf).await?;
+        if read == 0 {
+            bail!("Connection closed before the request head");
+        }
+        head.extend_from_slice(&buf[..read]);
+    };
+
+    let request_line = String::from_utf8_lossy(&head[..head_len])
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+    );
+
+    let (host, port, tunnel) = if method.eq_ignore_ascii_case("CONNECT") {
+        let (host, port) = target
+            .rsplit_once(':')
+            .with_context(|| format!("Invalid CONNECT target {target:?}"))?;
+        (
+            host.trim_matches(['[', ']']).to_string(),
+            port.parse()?,
+            true,
+        )
+    } else {
+        let url =
+            Url::parse(target).with_context(|| format!("Invalid request target {target:?}"))?;
+        let host = url
+            .host_str()
+            .context("Request target has no host")?
+            .to_string();
+        (host, url.port_or_known_default().unwrap_or(80), false)
+    };
+
+    let ips = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => lookup(host.clone()).await?,
+    };
+    let mut upstream = None;
+    for ip in ips {
+        if let Ok(stream) = TcpStream::connect((ip, port)).await {
+            upstream = Some(stream);
+            break;
+        }
+    }
+    let Some(mut upstream) = upstream else {
+        client
+            .write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")
+            .await?;
+        bail!("Couldn't connect to {host}:{port}");
+    };
+
+    if tunnel {
+        client
+            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await?;
+        // Anything sent right after the head already belongs to the tunnel.
+        upstream.write_all(&head[head_len..]).await?;
+    } else {
+        upstream.write_all(&head).await?;
+    }
+
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Starts the proxy with every name resolving to localhost.
+    async fn local_proxy() -> SocketAddr {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_proxy(listener, |_| async {
+            Ok(vec![IpAddr::from([127, 0, 0, 1])])
+        }));
+        addr
+    }
+
+    /// Accepts one connection and returns the request head it got.
+    async fn upstream() -> (u16, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                let read = stream.read(&mut buf).await.unwrap();
+                head.extend_from_slice(&buf[..read]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(head).unwrap()
+        });
+        (port, task)
+    }
+
+    #[test]
+    fn rejects_urls_hickory_cant_query() {
+        assert!(DohResolver::new("https://cloudflare-dns.com/dns-query").is_ok());
+        assert!(DohResolver::new("http://cloudflare-dns.com/dns-query").is_err());
+        assert!(DohResolver::new("https://dns.google/resolve").is_err());
+        assert!(DohResolver::new("not a url").is_err());
+    }
+
+    #[tokio::test]
+    async fn forwarded_requests_keep_their_host_header() {
+        let proxy = local_proxy().await;
+        let (port, upstream) = upstream().await;
+
+        let client = reqwest::Client::builder()
+            .proxy(reqwest::Proxy::http(format!("http://{proxy}")).unwrap())
+            .build()
+            .unwrap();
+        let response = client
+            .get(format!("http://vods.example.test:{port}/video"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 204);
+
+        let head = upstream.await.unwrap();
+        let head = head.to_ascii_lowercase();
+        assert!(
+            head.contains(&format!("host: vods.example.test:{port}")),
+            "{head}"
+        );
+    }
+
+    #[tokio::test]
+    async fn tunnels_connect_requests() {
+        let proxy = local_proxy().await;
+        let (port, upstream) = upstream().await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let connect = format!("CONNECT vods.example.test:{port} HTTP/1.1\r\n\r\n");
+        stream.write_all(connect.as_bytes()).await.unwrap();
+
+        let mut reply = [0u8; 39];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert!(reply.starts_with(b"HTTP/1.1 200"));
+
+        // What's sent through the tunnel arrives untouched.
+        let request = "GET / HTTP/1.1\r\nHost: vods.example.test\r\n\r\n";
+        stream.write_all(request.as_bytes()).await.unwrap();
+        assert_eq!(upstream.await.unwrap(), request);
+    }
+}
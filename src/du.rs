@@ -0,0 +1,168 @@
+//! `dlvod du`: how much room the downloads of each game take.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use bytesize::ByteSize;
+
+/// A download found in the output directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownloadEntry {
+    pub path: PathBuf,
+    /// The game's abbreviation, from the filename.
+    pub game: String,
+    pub size: u64,
+}
+
+/// Reads the game out of a filename made from the default template,
+/// `{player}-{game}-{cat}-{run_id}.mp4`, or `{game}-{cat}-{run_id}.mp4` for
+/// players whose name left nothing to keep.
+///
+/// The fields are counted from the end: the game, category and run ID are
+/// slugs without dashes, the player's name may have some. A `_verified_...`
+/// or `_1` suffix from renaming doesn't get in the way. Other templates
+/// aren't recognized.
+fn parse_game(filename: &str) -> Option<&str> {
+    let mut stem = filename.strip_suffix(".mp4")?;
+    if let Some(end) = stem
+        .rfind("_verified_")
+        .or_else(|| stem.rfind("_rejected_"))
+    {
+        stem = &stem[..end];
+    }
+    let mut fields = stem.rsplitn(4, '-');
+    let (_run_id, _cat, game) = (fields.next()?, fields.next()?, fields.next()?);
+    let player = fields.next();
+    if player.is_some_and(str::is_empty)
+        || game.is_empty()
+        || !game.chars().all(|c| c.is_ascii_alphanumeric())
+    {
+        return None;
+    }
+    Some(game)
+}
+
+/// Finds the downloads in `dir` and its subdirectories, as laid out by any
+/// `--organize-by`. Files that don't look like downloads are left out.
+pub fn scan_downloads(dir: &Path) -> Result<Vec<DownloadEntry>> {
+    let mut entries = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let read =
+            fs::read_dir(&dir).with_context(|| format!("Couldn't read {}", dir.display()))?;
+        for entry in read {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+                continue;
+            }
+            if !file_type.is_file() {
+                continue;
+            }
+
+            let filename = entry.file_name();
+            let Some(game) = filename.to_str().and_then(parse_game) else {
+                continue;
+            };
+            let size = match entry.metadata() {
+                Ok(metadata) => metadata.len(),
+                // Removed while scanning.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            entries.push(DownloadEntry {
+                path: entry.path(),
+                game: game.to_string(),
+                size,
+            });
+        }
+    }
+
+    Ok(entries)
+}
+
+/// `(game, total size, file count)` for each game, biggest first.
+pub fn usage_by_game(entries: &[DownloadEntry]) -> Vec<(String, u64, usize)> {
+    let mut games: HashMap<&str, (u64, usize)> = HashMap::new();
+    for entry in entries {
+        let game = games.entry(&entry.game).or_default();
+        game.0 += entry.size;
+        game.1 += 1;
+    }
+
+    let mut usage = games
+        .into_iter()
+        .map(|(game, (size, count))| (game.to_string(), size, count))
+        .collect::<Vec<_>>();
+    usage.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    usage
+}
+
+/// Prints the disk usage table of the downloads in `dir`.
+pub fn print_usage(dir: &Path) -> Result<()> {
+    let entries = scan_downloads(dir)?;
+    if entries.is_empty() {
+        println!("No downloads in {}", dir.display());
+        return Ok(());
+    }
+
+    println!("\n  {:<16}{:>12}{:>8}", "Game", "Size", "Files");
+    for (game, size, count) in usage_by_game(&entries) {
+        println!("  {game:<16}{:>12}{count:>8}", ByteSize(size).to_string());
+    }
+
+    let total: u64 = entries.iter().map(|entry| entry.size).sum();
+    println!("\n  {} in {} downloads", ByteSize(total), entries.len());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_game_from_filenames() {
+        assert_eq!(
+            parse_game("someone-sm64-120star-y8dwe0lz.mp4"),
+            Some("sm64")
+        );
+        assert_eq!(
+            parse_game("so-me-one-sm64-120star-y8dwe0lz.mp4"),
+            Some("sm64")
+        );
+        assert_eq!(
+            parse_game("someone-sm64-120star-y8dwe0lz_verified_2024-01-01.mp4"),
+            Some("sm64")
+        );
+        assert_eq!(parse_game("sm64-120star-y8dwe0lz.mp4"), Some("sm64"));
+        assert_eq!(parse_game("120star-y8dwe0lz.mp4"), None);
+        assert_eq!(parse_game("someone-sm64-120star-y8dwe0lz.mkv"), None);
+        assert_eq!(parse_game("holiday.mp4"), None);
+    }
+
+    #[test]
+    fn sums_downloads_per_game() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("sm64");
+        fs::create_dir(&nested).unwrap();
+        fs::write(dir.path().join("a-sm64-16star-1.mp4"), [0; 10]).unwrap();
+        fs::write(nested.join("b-sm64-70star-2.mp4"), [0; 20]).unwrap();
+        fs::write(dir.path().join("c-oot-any-3.mp4"), [0; 50]).unwrap();
+        fs::write(dir.path().join("sm64-any-4.mp4"), [0; 5]).unwrap();
+        fs::write(dir.path().join("notes.txt"), [0; 100]).unwrap();
+
+        let entries = scan_downloads(dir.path()).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(
+            usage_by_game(&entries),
+            [("oot".to_string(), 50, 1), ("sm64".to_string(), 35, 3)]
+        );
+    }
+}
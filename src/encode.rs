@@ -0,0 +1,323 @@
+use std::{ffi::OsStr, path::Path, process::Stdio};
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use once_cell::sync::OnceCell;
+use tokio::process::Command;
+
+use crate::{config::Config, postprocess::LOUDNORM_TARGET, tools};
+
+/// Appended to downloads until they're complete.
+pub const PART_EXTENSION: &str = "part";
+
+/// The extension the file at `path` is named for, past a `.part` suffix.
+pub fn media_extension(path: &Path) -> Option<&OsStr> {
+    match path.extension() {
+        Some(ext) if ext == PART_EXTENSION => Path::new(path.file_stem()?).extension(),
+        ext => ext,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Codec {
+    /// Use the first hardware encoder that works, falling back to libx264.
+    Auto,
+    /// NVIDIA NVENC.
+    Nvenc,
+    /// Apple VideoToolbox.
+    Videotoolbox,
+    /// VA-API (Intel/AMD on Linux), using `--vaapi-device`.
+    Vaapi,
+    /// Software x264.
+    Libx264,
+}
+
+/// Encoders tried by [`Codec::Auto`], in order of preference.
+pub const FALLBACK_CHAIN: &[Codec] = &[Codec::Nvenc, Codec::Videotoolbox, Codec::Libx264];
+
+static WORKING_ENCODER: OnceCell<Codec> = OnceCell::new();
+
+/// Whether to decode on the GPU when encoding on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HwDecode {
+    /// Decode in hardware when the encoder is NVENC or VA-API.
+    Auto,
+    /// Always decode in software.
+    Off,
+}
+
+/// ffmpeg's `-loglevel`, for the transcode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FfmpegLogLevel {
+    Quiet,
+    Panic,
+    Fatal,
+    Error,
+    Warning,
+    Info,
+    Verbose,
+    Debug,
+}
+
+impl FfmpegLogLevel {
+    /// The `-loglevel` value. Every line is prefixed with its level, so it
+    /// can be logged at the matching one.
+    pub fn arg(self) -> String {
+        let level = self
+            .to_possible_value()
+            .expect("no variant is skipped")
+            .get_name()
+            .to_string();
+        format!("level+{level}")
+    }
+}
+
+impl Codec {
+    fn encoder(self) -> &'static str {
+        match self {
+            Codec::Auto => unreachable!("auto must be resolved before encoding"),
+            Codec::Nvenc => "h264_nvenc",
+            Codec::Videotoolbox => "h264_videotoolbox",
+            Codec::Vaapi => "h264_vaapi",
+            Codec::Libx264 => "libx264",
+        }
+    }
+
+    /// Resolves [`Codec::Auto`] to a concrete encoder, probing only once per session.
+    pub async fn resolve(self) -> Result<Codec> {
+        if self != Codec::Auto {
+            return Ok(self);
+        }
+
+        if let Some(codec) = WORKING_ENCODER.get() {
+            return Ok(*codec);
+        }
+
+        let codec = find_working_encoder(FALLBACK_CHAIN).await?;
+        Ok(*WORKING_ENCODER.get_or_init(|| codec))
+    }
+}
+
+/// Returns the first of `candidates` that ffmpeg can actually encode with.
+pub async fn find_working_encoder(candidates: &[Codec]) -> Result<Codec> {
+    for &codec in candidates {
+        let status = Command::new(tools::ffmpeg())
+            .args(["-hide_banner", "-loglevel", "error"])
+            .args(["-f", "lavfi", "-i", "nullsrc", "-t", "1"])
+            .args(["-c:v", codec.encoder(), "-f", "null", "-"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await?;
+
+        if status.success() {
+            tracing::debug!("Using encoder {}", codec.encoder());
+            return Ok(codec);
+        }
+
+        tracing::debug!("Encoder {} is not available", codec.encoder());
+    }
+
+    bail!("None of the candidate encoders work with this ffmpeg")
+}
+
+/// Default output frame rate.
+pub const DEFAULT_FPS: u32 = 30;
+/// Default output width; the height follows the aspect ratio.
+pub const DEFAULT_WIDTH: u32 = 896;
+
+/// Escapes `path` for use as a filter option value inside a filter graph.
+pub fn escape_filter_path(path: &Path) -> String {
+    let escape = |s: &str, special: &[char]| {
+        let mut escaped = String::with_capacity(s.len());
+        for c in s.chars() {
+            if special.contains(&c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    };
+
+    // Once for the filter's option parser, once for the graph parser.
+    let option = escape(&path.to_string_lossy(), &['\\', '\'', ':']);
+    escape(&option, &['\\', '\'', '[', ']', ',', ';'])
+}
+
+/// Whether to decode on the GPU. The subtitles filter renders in system memory,
+/// which VA-API frames never reach when decoded there.
+fn use_hwdecode(codec: Codec, cfg: &Config, subtitles: bool) -> bool {
+    cfg.hwdecode == HwDecode::Auto && !(codec == Codec::Vaapi && subtitles)
+}
+
+/// Assembles the `-filter:v` chain: `--vf` verbatim, or the built-in frame rate
+/// and scaling filters followed by `--vf-append`. Subtitles burned in from
+/// `subtitles` come first either way.
+fn build_video_filter(codec: Codec, cfg: &Config, subtitles: Option<&Path>) -> String {
+    let subtitles = subtitles.map(|path| format!("subtitles={}", escape_filter_path(path)));
+
+    let chain: Vec<String> = match &cfg.vf {
+        Some(vf) => vec![vf.clone()],
+        None => builtin_video_filters(codec, cfg, subtitles.is_some()),
+    };
+
+    subtitles
+        .into_iter()
+        .chain(chain)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn builtin_video_filters(codec: Codec, cfg: &Config, subtitles: bool) -> Vec<String> {
+    let fps = format!("fps={}", cfg.fps.unwrap_or(DEFAULT_FPS));
+    let width = cfg.resolution.unwrap_or(DEFAULT_WIDTH);
+    let hwdecode = use_hwdecode(codec, cfg, subtitles);
+    let append = cfg.vf_append.iter().cloned();
+
+    match codec {
+        // Frames stay in GPU memory, so they must be scaled there.
+        Codec::Vaapi if hwdecode => [fps, format!("scale_vaapi=w={width}:h=-2")]
+            .into_iter()
+            .chain(append)
+            .collect(),
+        // Software filters have to run before the frames are uploaded.
+        Codec::Vaapi => [fps, format!("scale={width}:-1")]
+            .into_iter()
+            .chain(append)
+            .chain(["format=nv12".to_string(), "hwupload".to_string()])
+            .collect(),
+        _ => [fps, format!("scale={width}:-1")]
+            .into_iter()
+            .chain(append)
+            .collect(),
+    }
+}
+
+/// Builds the ffmpeg arguments for transcoding `input`, minus the output path.
+/// `input` is `pipe:` for the piped VOD; with `subtitles`, that file's subtitle
+/// track is burned into the video.
+///
+/// With a hardware encoder, decoding is moved to the GPU too unless
+/// `--hwdecode off` is set. The input is a pipe, so ffmpeg can't probe ahead to
+/// check that the device supports the source codec: `-hwaccel` falls back to
+/// software decoding for unsupported streams, but a broken driver only shows up
+/// once the transcode starts, and `--hwdecode off` is the way around it.
+pub fn build_ffmpeg_args(
+    codec: Codec,
+    cfg: &Config,
+    input: &str,
+    subtitles: Option<&Path>,
+) -> Vec<String> {
+    let hwdecode = use_hwdecode(codec, cfg, subtitles.is_some());
+    let vaapi_device = cfg.vaapi_device.to_string_lossy().into_owned();
+    let mut args = vec!["-y".to_string()];
+
+    match codec {
+        Codec::Nvenc if hwdecode => args.extend(["-hwaccel".into(), "cuda".into()]),
+        Codec::Vaapi if hwdecode => args.extend([
+            "-hwaccel".into(),
+            "vaapi".into(),
+            "-hwaccel_device".into(),
+            vaapi_device,
+            "-hwaccel_output_format".into(),
+            "vaapi".into(),
+        ]),
+        Codec::Vaapi => args.extend(["-vaapi_device".into(), vaapi_device]),
+        _ => {}
+    }
+
+    let filter = build_video_filter(codec, cfg, subtitles);
+
+    args.extend(["-i", input, "-c:v", codec.encoder()].map(String::from));
+
+    if codec == Codec::Libx264 {
+        args.extend(["-preset", "veryfast"].map(String::from));
+    }
+
+    args.extend(["-x264-params", "keyint=30:min-keyint=30:no-scenecut=1"].map(String::from));
+    args.extend(["-filter:v".to_string(), filter]);
+
+    // With --two-pass, loudnorm runs after the download instead, once the whole
+    // audio track can be measured.
+    if cfg.normalize_audio && !cfg.two_pass {
+        args.extend(["-af".to_string(), format!("loudnorm={LOUDNORM_TARGET}")]);
+    }
+
+    args.extend(["-c:a", "aac", "-b:a", "96k", "-ar", "44100"].map(String::from));
+
+    if codec == Codec::Videotoolbox {
+        args.extend(["-prio_speed", "true"].map(String::from));
+    }
+
+    args
+}
+
+/// An extra output for `--multi-output`: a container, transcoded like the main
+/// output or with the source streams copied as they are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputFormat {
+    /// Also the extension of the output file, which ffmpeg picks the muxer by.
+    pub container: String,
+    pub copy: bool,
+}
+
+/// Parses a `<container>` or `<container>:copy` output format.
+pub fn parse_output_format(s: &str) -> Result<OutputFormat> {
+    let (container, copy) = match s.split_once(':') {
+        Some((container, "copy")) => (container, true),
+        Some(_) => bail!("Invalid output format {s:?}, expected e.g. mkv or mkv:copy"),
+        None => (s, false),
+    };
+
+    if container.is_empty() || !container.chars().all(|c| c.is_ascii_alphanumeric()) {
+        bail!("Invalid output format {s:?}, expected e.g. mkv or mkv:copy");
+    }
+
+    Ok(OutputFormat {
+        container: container.to_ascii_lowercase(),
+        copy,
+    })
+}
+
+/// Builds the ffmpeg arguments for a `--multi-output` format, minus the output
+/// path.
+pub fn build_output_args(format: &OutputFormat, codec: Codec, cfg: &Config) -> Vec<String> {
+    if format.copy {
+        ["-y", "-i", "pipe:", "-map", "0", "-c", "copy"]
+            .map(String::from)
+            .into()
+    } else {
+        build_ffmpeg_args(codec, cfg, "pipe:", None)
+    }
+}
+
+/// `-f` for writing to `output` when it's a `.part` file, whose extension
+/// doesn't tell ffmpeg the muxer, or nothing otherwise.
+pub fn part_muxer_args(output: &Path) -> Vec<String> {
+    if output.extension().is_none_or(|ext| ext != PART_EXTENSION) {
+        return Vec::new();
+    }
+
+    let container = media_extension(output)
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    let muxer = match container.as_str() {
+        "mkv" => "matroska",
+        "ts" => "mpegts",
+        "m4v" => "mp4",
+        "m4a" => "ipod",
+        other => other,
+    };
+    vec!["-f".to_string(), muxer.to_string()]
+}
+
+/// Builds the ffmpeg arguments for saving the piped VOD as-is, every stream
+/// included, minus the output path.
+pub fn build_copy_args() -> Vec<String> {
+    [
+        "-y", "-i", "pipe:", "-map", "0", "-c", "copy", "-f", "matroska",
+    ]
+    .map(String::from)
+    .into()
+}
@@ -0,0 +1,64 @@
+//! Shell commands run around each download, from `pre_download_hook` and
+//! `post_download_hook` in the configuration file.
+
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::Run;
+
+/// Runs `cmd` through the shell with `env` added to its environment, failing
+/// if it exits with a non-zero status.
+pub fn run_hook(cmd: &str, env: &[(String, String)]) -> Result<()> {
+    #[cfg(unix)]
+    let mut shell = {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    #[cfg(windows)]
+    let mut shell = {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    };
+
+    let status = shell
+        .arg(cmd)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::null())
+        .status()
+        .with_context(|| format!("Couldn't run hook {cmd:?}"))?;
+
+    if !status.success() {
+        bail!("Hook {cmd:?} failed: {status}");
+    }
+
+    Ok(())
+}
+
+/// The run's metadata as `DLVOD_*` variables, plus `DLVOD_OUTPUT_PATH` once
+/// the download is done.
+pub fn hook_env(run: &Run, output_path: Option<&Path>) -> Vec<(String, String)> {
+    let mut env = vec![
+        ("DLVOD_RUN_ID", run.run_id.clone()),
+        ("DLVOD_PLAYER", run.player.clone()),
+        ("DLVOD_GAME", run.game.clone()),
+        ("DLVOD_GAME_NAME", run.game_name.clone()),
+        ("DLVOD_CATEGORY", run.cat_full.clone()),
+        ("DLVOD_TIME", run.time.clone()),
+        ("DLVOD_VOD_URL", run.vod_uri.clone()),
+        ("DLVOD_RUN_URL", run.permalink.clone()),
+        ("DLVOD_PLATFORM", run.platform.clone().unwrap_or_default()),
+        ("DLVOD_REGION", run.region.clone().unwrap_or_default()),
+    ];
+
+    if let Some(path) = output_path {
+        env.push(("DLVOD_OUTPUT_PATH", path.to_string_lossy().into_owned()));
+    }
+
+    env.into_iter().map(|(k, v)| (k.to_string(), v)).collect()
+}
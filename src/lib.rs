@@ -0,0 +1,3165 @@
+//! Downloading pending speedrun.com runs for review, from the command line.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::{exit, ExitCode, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, LazyLock, Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use bytesize::ByteSize;
+use chrono::{DateTime, Utc};
+use dialoguer::{MultiSelect, Select};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::{
+    io::AsyncBufReadExt,
+    process::{Child, ChildStderr, Command},
+};
+use tracing::Instrument;
+
+use crate::{
+    api::{RequestExt, ResponseExt},
+    config::{CollisionStrategy, Config, OrganizeBy},
+    encode::{build_copy_args, build_ffmpeg_args, build_output_args, Codec, OutputFormat},
+};
+
+pub mod api;
+pub mod config;
+#[cfg(unix)]
+mod control;
+#[cfg(feature = "doh")]
+mod doh;
+mod du;
+mod encode;
+mod hooks;
+mod logging;
+mod moderation;
+mod parallel;
+mod pipe;
+pub mod platform;
+mod postprocess;
+mod process;
+mod progress;
+mod resume;
+mod segments;
+mod server;
+mod sidecar;
+mod stats;
+mod tools;
+mod tui;
+
+pub fn slug(s: &str) -> String {
+    s.to_ascii_lowercase()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect()
+}
+
+/// The download stopped producing output and was killed by the watchdog.
+#[derive(Debug)]
+struct Stalled;
+
+impl fmt::Display for Stalled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "stalled")
+    }
+}
+
+impl std::error::Error for Stalled {}
+
+/// The output file already exists and `--on-collision skip` is set.
+#[derive(Debug)]
+struct Skipped(PathBuf);
+
+impl fmt::Display for Skipped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} already exists", self.0.display())
+    }
+}
+
+impl std::error::Error for Skipped {}
+
+/// The run was submitted without a video.
+#[derive(Debug)]
+struct NoVod;
+
+impl fmt::Display for NoVod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the run has no VOD")
+    }
+}
+
+impl std::error::Error for NoVod {}
+
+/// `--dry-run` printed the commands instead of downloading.
+#[derive(Debug)]
+struct DryRun;
+
+impl fmt::Display for DryRun {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "dry run, nothing was downloaded")
+    }
+}
+
+impl std::error::Error for DryRun {}
+
+const MAX_STALL_RETRIES: u32 = 3;
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Poll interval for processes not covered by `--process-poll-interval`, like mpv.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+async fn wait_cmd(
+    child: &mut Child,
+    done: &Arc<AtomicBool>,
+    stalled: &AtomicBool,
+    poll_interval: Duration,
+) -> Result<()> {
+    let pid = child.id();
+    loop {
+        match child.try_wait() {
+            Ok(None) => (),
+            Ok(Some(status)) => {
+                process::release(pid);
+                if status.success() {
+                    return Ok(());
+                }
+                bail!("failed: {status}");
+            }
+            Err(e) => bail!("error: {e}"),
+        };
+
+        if done.load(Ordering::SeqCst) {
+            process::kill_process_tree(child)?;
+            bail!("Ctrl+C");
+        }
+
+        if stalled.load(Ordering::SeqCst) {
+            process::kill_process_tree(child)?;
+            return Err(Stalled.into());
+        }
+
+        tokio::time::sleep(poll_interval).await;
+    }
+}
+
+pub fn format_time(d: Duration) -> String {
+    let as_secs = d.as_secs();
+    let ms = d.subsec_millis();
+    let s = as_secs % 60;
+    let m = (as_secs / 60) % 60;
+    let h = as_secs / 3600;
+
+    if ms > 0 {
+        format!("{h:02}:{m:02}:{s:02}.{ms:03}")
+    } else {
+        format!("{h:02}:{m:02}:{s:02}")
+    }
+}
+
+/// Parses an ISO 8601 run time like `PT1H23M45.67S`.
+pub fn parse_run_time(time: &str) -> Result<Duration> {
+    let parsed = iso8601_duration::Duration::parse(time)
+        .map_err(|e| anyhow!("{e:?}"))
+        .with_context(|| format!("Couldn't parse run time {time}"))?;
+    // Years and months have no fixed length, and negative times are nonsense.
+    parsed
+        .to_std()
+        .with_context(|| format!("Run time {time} isn't a valid duration"))?;
+
+    // The crate sums everything into an f32, which is off by milliseconds
+    // past the first hour, so the seconds are read from the string itself.
+    let seconds = match time.strip_suffix('S') {
+        Some(rest) => rest
+            .rsplit(['T', 'H', 'M'])
+            .next()
+            .and_then(|s| s.parse::<f64>().ok())
+            .with_context(|| format!("Couldn't parse run time {time}"))?,
+        None => 0.0,
+    };
+    let minutes = (parsed.day as f64 * 24.0 + parsed.hour as f64) * 60.0 + parsed.minute as f64;
+
+    Ok(Duration::from_millis(
+        ((minutes * 60.0 + seconds) * 1000.0).round() as u64,
+    ))
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Run {
+    pub run_id: String,
+    /// The video to download, one of `vod_uris`. Empty if `vod_missing`.
+    pub vod_uri: String,
+    /// Every video attached to the run, like a Twitch VOD and a YouTube backup.
+    pub vod_uris: Vec<String>,
+    /// Submitted without a video, as text-only runs are.
+    pub vod_missing: bool,
+    /// The player the run is listed under, the first one of co-op runs.
+    pub player: String,
+    /// Everyone who played the run, `player` first.
+    pub players: Vec<String>,
+    /// Unset for guest players, who have no account.
+    pub player_id: Option<String>,
+    /// The player's two-letter ISO country code, if they set a location.
+    pub country: Option<String>,
+    pub game: String,
+    pub game_id: String,
+    pub game_name: String,
+    /// The run's page on speedrun.com, where its discussion happens.
+    pub permalink: String,
+    /// The game's small cover art.
+    pub cover_uri: Option<String>,
+    pub category_id: String,
+    pub cat_full: String,
+    pub cat: String,
+    pub time: String,
+    #[serde(skip)]
+    pub duration: Duration,
+    pub platform: Option<String>,
+    pub region: Option<String>,
+    pub variables: Vec<(String, String)>,
+    /// The `(variable ID, value ID)` pairs of the variables that split the
+    /// category's leaderboard, like a game version.
+    #[serde(skip)]
+    pub subcategory: Vec<(String, String)>,
+    pub submitted: Option<DateTime<Utc>>,
+    /// The runner's submission comment.
+    pub notes: Option<String>,
+    /// Faster than the current world record of its category.
+    pub would_be_wr: bool,
+    /// Submitted by a player on the ban list.
+    pub banned: bool,
+    #[serde(skip)]
+    pub wr_time: Option<Duration>,
+}
+
+impl Run {
+    pub fn filename(&self, template: &str) -> String {
+        let platform = self
+            .platform
+            .as_deref()
+            .map(slug)
+            .unwrap_or_else(|| "unknown".to_string());
+        let variables = self
+            .variables
+            .iter()
+            .map(|(_, label)| slug(label))
+            .collect::<Vec<_>>()
+            .join("-");
+
+        // Cut before filling in the template, so a long name doesn't push the
+        // run ID past the end and give two runs the same file.
+        let filename = sanitize_filename(
+            &template
+                .replace("{player}", &shorten_placeholder(&self.player))
+                .replace("{game}", &shorten_placeholder(&self.game))
+                .replace("{cat}", &shorten_placeholder(&self.cat))
+                .replace("{run_id}", &self.run_id)
+                .replace("{platform}", &shorten_placeholder(&platform))
+                .replace("{variables}", &shorten_placeholder(&variables)),
+        );
+        if filename.is_empty() {
+            sanitize_filename(&self.run_id)
+        } else {
+            filename
+        }
+    }
+}
+
+/// Longest filename kept, short of Windows' 260 character path limit with
+/// room for the directory and extension.
+pub const MAX_FILENAME_LEN: usize = 200;
+
+/// Longest a placeholder other than `{run_id}` gets in a filename.
+const MAX_PLACEHOLDER_LEN: usize = 64;
+
+/// The characters of `value` that [`sanitize_filename`] keeps, up to
+/// [`MAX_PLACEHOLDER_LEN`] of them.
+fn shorten_placeholder(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .take(MAX_PLACEHOLDER_LEN)
+        .collect()
+}
+
+/// Keeps what's safe in a filename everywhere: ASCII letters and digits, with
+/// `-` and `_` separating them. Runs of separators left by dropped characters
+/// or empty placeholders are collapsed, and Windows' device names get a `_`.
+pub fn sanitize_filename(s: &str) -> String {
+    let mut name = String::new();
+    for c in s.chars() {
+        let separator = (c == '-' || c == '_') && !name.is_empty() && !name.ends_with(['-', '_']);
+        if c.is_ascii_alphanumeric() || separator {
+            name.push(c);
+        }
+    }
+
+    name.truncate(MAX_FILENAME_LEN);
+    let mut name = name.trim_end_matches(['-', '_']).to_string();
+
+    const RESERVED: &[&str] = &["con", "prn", "aux", "nul"];
+    let lower = name.to_ascii_lowercase();
+    let device = RESERVED.contains(&lower.as_str())
+        || ((lower.starts_with("com") || lower.starts_with("lpt"))
+            && lower.len() == 4
+            && lower.ends_with(|c: char| c.is_ascii_digit() && c != '0'));
+    if device {
+        name.push('_');
+    }
+
+    name
+}
+
+/// Reads the `(variable name, value label)` pairs selected for a run, in the
+/// order the category defines its variables.
+fn run_variables(value: &Value) -> Vec<(String, String)> {
+    let Some(selected) = value["values"].as_object() else {
+        return Vec::new();
+    };
+
+    value["category"]["data"]["variables"]["data"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|variable| {
+            let id = variable["id"].as_str()?;
+            let value_id = selected.get(id)?.as_str()?;
+            let name = variable["name"].as_str()?;
+            let label = variable["values"]["values"][value_id]["label"].as_str()?;
+            Some((name.to_string(), label.to_string()))
+        })
+        .collect()
+}
+
+/// Reads the `(variable ID, value ID)` pairs selected for a run among the
+/// variables that are subcategories, the ones its leaderboard is split by.
+fn run_subcategory(value: &Value) -> Vec<(String, String)> {
+    let Some(selected) = value["values"].as_object() else {
+        return Vec::new();
+    };
+
+    value["category"]["data"]["variables"]["data"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|variable| variable["is-subcategory"].as_bool() == Some(true))
+        .filter_map(|variable| {
+            let id = variable["id"].as_str()?;
+            let value_id = selected.get(id)?.as_str()?;
+            Some((id.to_string(), value_id.to_string()))
+        })
+        .collect()
+}
+
+/// A player's name: the international one of an account, or the name given
+/// for a guest.
+fn player_name(player: &Value) -> Option<&str> {
+    player["names"]["international"]
+        .as_str()
+        .or(player["name"].as_str())
+}
+
+/// The embedded player a run is listed under, the first one.
+///
+/// Renamed accounts can leave several players with the same name on a run.
+/// Then the one downloaded before, per the history, is taken, or the first.
+fn main_player<'a>(run_id: &str, value: &'a Value) -> Option<&'a Value> {
+    let players = value["players"]["data"].as_array()?;
+    let first = players.first()?;
+    let name = slug(player_name(first)?);
+
+    let namesakes = players
+        .iter()
+        .filter(|player| player_name(player).map(slug).as_ref() == Some(&name))
+        .collect::<Vec<_>>();
+    if namesakes.len() < 2 {
+        return Some(first);
+    }
+
+    tracing::warn!(
+        "{run_id} has {} players named {name}, telling them apart by ID",
+        namesakes.len()
+    );
+    let known = stats::known_player_ids();
+    Some(
+        namesakes
+            .into_iter()
+            .find(|player| player["id"].as_str().is_some_and(|id| known.contains(id)))
+            .unwrap_or(first),
+    )
+}
+
+impl TryFrom<&Value> for Run {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &Value) -> Result<Self> {
+        let run_id = value["id"]
+            .as_str()
+            .context("Can't read run ID")?
+            .to_string();
+        let vod_uris = value["videos"]["links"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|link| link["uri"].as_str())
+            .map(platform::normalize_twitch_url)
+            .collect::<Vec<_>>();
+        let vod_missing = vod_uris.is_empty();
+        let vod_uri = vod_uris.first().cloned().unwrap_or_default();
+        let player_data = main_player(&run_id, value).context("Can't read player data")?;
+        let player = player_name(player_data)
+            .context("Can't read player data")?
+            .to_string();
+        let player_id = player_data["id"].as_str().map(str::to_string);
+        let mut players = vec![player.clone()];
+        for name in value["players"]["data"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(player_name)
+        {
+            if !players.iter().any(|p| p == name) {
+                players.push(name.to_string());
+            }
+        }
+        let country = player_data["location"]["country"]["code"]
+            .as_str()
+            .map(str::to_string);
+        let game = value["game"]["data"]["abbreviation"]
+            .as_str()
+            .context("Can't read game data")?
+            .to_string();
+        let game_id = value["game"]["data"]["id"]
+            .as_str()
+            .context("Can't read game ID")?
+            .to_string();
+        let game_name = value["game"]["data"]["names"]["twitch"]
+            .as_str()
+            .context("Can't read game name")?
+            .to_string();
+        let permalink = value["weblink"]
+            .as_str()
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("https://www.speedrun.com/{game}/run/{run_id}"));
+        let cover_uri = value["game"]["data"]["assets"]["cover-small"]["uri"]
+            .as_str()
+            .map(str::to_string);
+        let cat_name = value["category"]["data"]["name"]
+            .as_str()
+            .context("Can't read category name")?;
+        let category_id = value["category"]["data"]["id"]
+            .as_str()
+            .context("Can't read category ID")?
+            .to_string();
+        let cat = slug(cat_name);
+        let variables = run_variables(value);
+        let subcategory = run_subcategory(value);
+        let cat_full = if variables.is_empty() {
+            cat_name.to_string()
+        } else {
+            let values = variables
+                .iter()
+                .map(|(name, label)| format!("{name}: {label}"))
+                .collect::<Vec<_>>();
+            format!("{cat_name} - {}", values.join(", "))
+        };
+        let duration = parse_run_time(
+            value["times"]["primary"]
+                .as_str()
+                .context("Couldn't read run time")?,
+        )?;
+        let time = format_time(duration);
+        let platform = value["platform"]["data"]["name"]
+            .as_str()
+            .map(str::to_string);
+        let region = value["region"]["data"]["name"].as_str().map(str::to_string);
+        let submitted = value["submitted"]
+            .as_str()
+            .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+            .map(|d| d.with_timezone(&Utc));
+        let notes = value["comment"]
+            .as_str()
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_string);
+
+        Ok(Self {
+            run_id,
+            vod_uri,
+            vod_uris,
+            vod_missing,
+            player,
+            players,
+            player_id,
+            country,
+            game,
+            game_id,
+            game_name,
+            permalink,
+            cover_uri,
+            category_id,
+            cat_full,
+            cat,
+            time,
+            duration,
+            platform,
+            region,
+            variables,
+            subcategory,
+            submitted,
+            notes,
+            would_be_wr: false,
+            banned: false,
+            wr_time: None,
+        })
+    }
+}
+
+/// Cleared by `--no-color`.
+static COLORS: AtomicBool = AtomicBool::new(true);
+
+fn colors_enabled() -> bool {
+    COLORS.load(Ordering::Relaxed)
+}
+
+/// Wraps `text` in the ANSI style `code` (e.g. `1;33`), unless `--no-color`.
+fn paint(code: &str, text: impl fmt::Display) -> String {
+    if colors_enabled() {
+        format!("\x1b[{code}m{text}\x1b[0m")
+    } else {
+        text.to_string()
+    }
+}
+
+/// The flag emoji of a two-letter ISO country code, as the pair of regional
+/// indicator symbols matching its letters.
+fn country_flag(code: &str) -> Option<String> {
+    let letters = code.as_bytes();
+    if letters.len() != 2 || !letters.iter().all(u8::is_ascii_alphabetic) {
+        return None;
+    }
+
+    letters
+        .iter()
+        .map(|c| char::from_u32(0x1F1E6 + u32::from(c.to_ascii_uppercase() - b'A')))
+        .collect()
+}
+
+impl Run {
+    /// The player's country as a flag, or as its code with `--no-color`.
+    fn country_label(&self) -> Option<String> {
+        let code = self.country.as_deref()?;
+        match country_flag(code) {
+            Some(flag) if colors_enabled() => Some(flag),
+            _ => Some(code.to_uppercase()),
+        }
+    }
+}
+
+impl fmt::Display for Run {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let country = self
+            .country_label()
+            .map(|label| format!("{label} "))
+            .unwrap_or_default();
+
+        if self.banned {
+            write!(f, "{} ", paint("1;31", "[BANNED]"))?;
+        }
+
+        if self.vod_missing {
+            write!(f, "{} ", paint("1;90", "[NO VOD]"))?;
+        }
+
+        write!(
+            f,
+            "{} - {} in {} by {country}{}",
+            paint("33", &self.game_name),
+            paint("34", &self.cat_full),
+            paint("32", &self.time),
+            paint("32", self.players.join(" & ")),
+        )?;
+
+        if let Some(platform) = &self.platform {
+            write!(f, " {}", paint("35", format!("[{platform}]")))?;
+        }
+
+        if let Some(region) = &self.region {
+            write!(f, " {}", paint("36", format!("({region})")))?;
+        }
+
+        if self.would_be_wr {
+            write!(f, " {}", paint("1;33", "(would be #1 if verified)"))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds the HTTP client used for segmented downloads and other hosts.
+fn http_client(cfg: &Config) -> Result<reqwest::Client> {
+    client_builder(cfg)?
+        .build()
+        .context("Couldn't create the HTTP client")
+}
+
+/// [`http_client`], for the speedrun.com API, over HTTP/2 with `--http2`.
+///
+/// Only API requests get `--api-timeout`: downloads can take much longer.
+pub async fn api_client(cfg: &Config) -> Result<reqwest::Client> {
+    let client = client_builder(cfg)?
+        .timeout(cfg.api_timeout())
+        .build()
+        .context("Couldn't create the HTTP client")?;
+    if !cfg.http2 {
+        return Ok(client);
+    }
+
+    let http2 = client_builder(cfg)?
+        .timeout(cfg.api_timeout())
+        .http2_prior_knowledge()
+        .build()
+        .context("Couldn't create the HTTP client")?;
+    // Nothing is negotiated with prior knowledge, so the only way to know the
+    // API speaks HTTP/2 is to try. Any answer at all will do.
+    match http2.head(api::api_url("")).send().await {
+        Ok(_) => Ok(http2),
+        Err(e) => {
+            tracing::warn!("The API doesn't speak HTTP/2, using HTTP/1.1: {e}");
+            Ok(client)
+        }
+    }
+}
+
+fn client_builder(cfg: &Config) -> Result<reqwest::ClientBuilder> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(&cfg.user_agent)
+        .pool_max_idle_per_host(cfg.max_connections)
+        .connect_timeout(cfg.api_connect_timeout())
+        .connection_verbose(false)
+        .danger_accept_invalid_certs(cfg.insecure);
+
+    if let Some(path) = &cfg.ca_bundle {
+        let pem = fs::read(path).with_context(|| format!("Couldn't read {}", path.display()))?;
+        let cert = reqwest::Certificate::from_pem(&pem)
+            .with_context(|| format!("{} is not a PEM certificate", path.display()))?;
+        builder = builder.add_root_certificate(cert);
+    }
+
+    #[cfg(feature = "doh")]
+    if let Some(resolver) = doh::resolver() {
+        builder = builder.dns_resolver(Arc::new(resolver));
+    }
+
+    Ok(builder)
+}
+
+/// What run requests embed, from [`build_embed_list`].
+static EMBEDS: OnceLock<String> = OnceLock::new();
+
+/// The resources embedded in run requests: the players, game and category
+/// [`Run::try_from`] needs, plus whatever is shown or used of the rest.
+fn build_embed_list(cfg: &Config) -> String {
+    let hooks = cfg.file.pre_download_hook.is_some() || cfg.file.post_download_hook.is_some();
+    let everything = !cfg.brief || cfg.write_metadata;
+
+    // The variables are always needed, to find the world record of the
+    // run's subcategory.
+    let mut embeds = vec!["players", "game", "category.variables"];
+    if everything || hooks || cfg.filename_template.contains("{platform}") {
+        embeds.push("platform");
+    }
+    if everything || hooks || cfg.filter_region.is_some() {
+        embeds.push("region");
+    }
+    embeds.join(",")
+}
+
+fn embeds() -> &'static str {
+    EMBEDS.get().map_or(
+        "players,game,category.variables,platform,region",
+        String::as_str,
+    )
+}
+
+/// Whether a `games` entry is an API ID rather than an abbreviation.
+///
+/// IDs are eight lowercase alphanumeric characters and nearly always contain
+/// a digit; abbreviations that happen to match (e.g. `smb12345`) must be given
+/// as IDs.
+fn looks_like_game_id(s: &str) -> bool {
+    s.len() == 8
+        && s.chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && s.chars().any(|c| c.is_ascii_digit())
+}
+
+static GAME_ID_CACHE: Mutex<Option<HashMap<String, String>>> = Mutex::new(None);
+
+/// Resolves a game abbreviation like `sm64` to its API ID, once per session.
+async fn resolve_game_id(game: &str, client: &reqwest::Client) -> Result<String> {
+    if looks_like_game_id(game) {
+        return Ok(game.to_string());
+    }
+
+    if let Some(id) = GAME_ID_CACHE
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|cache| cache.get(game))
+    {
+        return Ok(id.clone());
+    }
+
+    let games: Value = client
+        .get(api::api_url("games"))
+        .query(&[("abbreviation", game)])
+        .send_with_retry()
+        .await
+        .context("Requesting game data")?
+        .json_limited()
+        .await
+        .context("Parsing game data")?;
+
+    let id = games["data"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|g| {
+            g["abbreviation"]
+                .as_str()
+                .is_some_and(|a| a.eq_ignore_ascii_case(game))
+        })
+        .and_then(|g| g["id"].as_str())
+        .with_context(|| format!("No game with abbreviation {game:?}"))?
+        .to_string();
+
+    GAME_ID_CACHE
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(game.to_string(), id.clone());
+
+    Ok(id)
+}
+
+/// Narrows the run listing to one category, asked for in the API request.
+enum CategoryFilter {
+    Id(String),
+    /// Resolved per game with [`list_categories`].
+    Name(String),
+}
+
+impl CategoryFilter {
+    fn from_config(cfg: &Config) -> Option<Self> {
+        match (&cfg.category_id, &cfg.category) {
+            (Some(id), _) => Some(Self::Id(id.clone())),
+            (None, Some(name)) => Some(Self::Name(name.clone())),
+            (None, None) => None,
+        }
+    }
+
+    /// The category's ID in `game_id`. `None` means the whole game has to be
+    /// fetched: `--category` can also match a category's variables, which
+    /// only the listed runs have.
+    async fn resolve(&self, game_id: &str, client: &reqwest::Client) -> Option<String> {
+        let name = match self {
+            CategoryFilter::Id(id) => return Some(id.clone()),
+            CategoryFilter::Name(name) => name,
+        };
+
+        match list_categories(game_id, client).await {
+            Ok(categories) => categories
+                .into_iter()
+                .find(|(_, cat)| cat.eq_ignore_ascii_case(name) || slug(cat) == slug(name))
+                .map(|(id, _)| id),
+            Err(e) => {
+                tracing::debug!("Couldn't list the categories of {game_id}: {e:#}");
+                None
+            }
+        }
+    }
+}
+
+/// Lists the `(ID, name)` of every category of a game.
+async fn list_categories(game_id: &str, client: &reqwest::Client) -> Result<Vec<(String, String)>> {
+    let categories: Value = client
+        .get(api::api_url(&format!("games/{game_id}/categories")))
+        .send_with_retry()
+        .await
+        .context("Requesting categories")?
+        .json_limited()
+        .await
+        .context("Parsing categories")?;
+
+    Ok(categories["data"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|category| {
+            let id = category["id"].as_str()?;
+            let name = category["name"].as_str()?;
+            Some((id.to_string(), name.to_string()))
+        })
+        .collect())
+}
+
+/// Most pages of pending runs fetched per game, 100 runs each.
+const MAX_RUN_PAGES: usize = 20;
+
+pub async fn get_pending_runs(
+    game: &str,
+    category: Option<&str>,
+    client: &reqwest::Client,
+) -> Result<Vec<Run>> {
+    get_pending_runs_since(game, category, None, client).await
+}
+
+/// [`get_pending_runs`], only keeping those submitted since `last_seen`.
+///
+/// The API is asked for them with `submitted_after`, and the runs are
+/// filtered again with [`incremental_filter`] in case it ignored it.
+#[tracing::instrument(skip(client))]
+pub async fn get_pending_runs_since(
+    game: &str,
+    category: Option<&str>,
+    last_seen: Option<DateTime<Utc>>,
+    client: &reqwest::Client,
+) -> Result<Vec<Run>> {
+    let mut url = reqwest::Url::parse_with_params(
+        &api::api_url("runs"),
+        [("game", game), ("status", "new"), ("embed", embeds())],
+    )?;
+    if let Some(category) = category {
+        url.query_pairs_mut().append_pair("category", category);
+    }
+    if let Some(last_seen) = last_seen {
+        url.query_pairs_mut().append_pair(
+            "submitted_after",
+            &last_seen.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        );
+    }
+    url.query_pairs_mut().append_pair("max", "100");
+
+    let mut runs = Vec::new();
+    for _ in 0..MAX_RUN_PAGES {
+        let page: Value = client
+            .get(url.clone())
+            .send_with_retry()
+            .await
+            .context("Requesting runs metadata")?
+            .json_limited()
+            .await
+            .context("Parsing run metadata")?;
+        for run in page["data"].as_array().context("Unexpected value")? {
+            runs.push(Run::try_from(run)?);
+        }
+
+        match api::SrcPagination::from_json(&page["pagination"]).and_then(|p| p.next_url(&url)) {
+            Some(next) => url = next,
+            None => return Ok(incremental_filter(runs, &last_seen)),
+        }
+    }
+
+    tracing::warn!(
+        "{game} has more than {} pending runs, only listing those",
+        runs.len()
+    );
+    Ok(incremental_filter(runs, &last_seen))
+}
+
+/// The runs submitted since `last_seen`, or all of them without one.
+///
+/// Runs from the second of `last_seen` itself are kept: others submitted in
+/// it may not have been listed yet, and callers drop the ones they've seen by
+/// ID. Runs without a submission date can't be told apart from new ones, so
+/// they're kept too.
+pub fn incremental_filter(runs: Vec<Run>, last_seen: &Option<DateTime<Utc>>) -> Vec<Run> {
+    let Some(last_seen) = last_seen else {
+        return runs;
+    };
+    runs.into_iter()
+        .filter(|run| {
+            run.submitted
+                .is_none_or(|submitted| submitted >= *last_seen)
+        })
+        .collect()
+}
+
+/// When the most recently submitted of `runs` was submitted.
+pub fn latest_submission(runs: &[Run]) -> Option<DateTime<Utc>> {
+    runs.iter().filter_map(|run| run.submitted).max()
+}
+
+type WrCache = HashMap<(String, String, Vec<(String, String)>), Option<Duration>>;
+
+static WR_CACHE: Mutex<Option<WrCache>> = Mutex::new(None);
+
+/// Fetches the world record time of a category, in the subcategory given as
+/// `(variable ID, value ID)` pairs, once per session.
+///
+/// Returns `None` if the leaderboard is empty.
+async fn fetch_wr_time(
+    game: &str,
+    category: &str,
+    subcategory: &[(String, String)],
+    client: &reqwest::Client,
+) -> Result<Option<Duration>> {
+    let key = (game.to_string(), category.to_string(), subcategory.to_vec());
+
+    if let Some(wr) = WR_CACHE
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|cache| cache.get(&key))
+    {
+        return Ok(*wr);
+    }
+
+    let url = reqwest::Url::parse_with_params(
+        &api::api_url(&format!("leaderboards/{game}/category/{category}")),
+        std::iter::once(("top".to_string(), "1".to_string())).chain(
+            subcategory
+                .iter()
+                .map(|(variable, value)| (format!("var-{variable}"), value.clone())),
+        ),
+    )?;
+    let leaderboard: Value = client
+        .get(url)
+        .send_with_retry()
+        .await
+        .context("Requesting leaderboard")?
+        .json_limited()
+        .await
+        .context("Parsing leaderboard")?;
+
+    let wr = leaderboard["data"]["runs"][0]["run"]["times"]["primary_t"]
+        .as_f64()
+        .map(|secs| Duration::from_millis((secs * 1000.0).round() as u64));
+
+    WR_CACHE
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(key, wr);
+
+    Ok(wr)
+}
+
+static RULES_CACHE: Mutex<Option<HashMap<String, String>>> = Mutex::new(None);
+
+/// Fetches the rules text of a category, once per session.
+async fn fetch_category_rules(category: &str, client: &reqwest::Client) -> Result<String> {
+    if let Some(rules) = RULES_CACHE
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|cache| cache.get(category))
+    {
+        return Ok(rules.clone());
+    }
+
+    let body: Value = client
+        .get(api::api_url(&format!("categories/{category}")))
+        .send_with_retry()
+        .await
+        .context("Requesting category")?
+        .json_limited()
+        .await
+        .context("Parsing category")?;
+
+    let rules = body["data"]["rules"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+
+    RULES_CACHE
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(category.to_string(), rules.clone());
+
+    Ok(rules)
+}
+
+/// A verified run from a player's history.
+struct RunSummary {
+    game: String,
+    category: String,
+    time: String,
+    date: String,
+}
+
+/// Fetches the ten most recently submitted verified runs of a player.
+async fn fetch_player_runs(player_id: &str, client: &reqwest::Client) -> Result<Vec<RunSummary>> {
+    let body: Value = client
+        .get(api::api_url("runs"))
+        .query(&[
+            ("user", player_id),
+            ("status", "verified"),
+            ("orderby", "submitted"),
+            ("direction", "desc"),
+            ("max", "10"),
+            ("embed", "game,category"),
+        ])
+        .send_with_retry()
+        .await
+        .context("Requesting player runs")?
+        .json_limited()
+        .await
+        .context("Parsing player runs")?;
+
+    let runs = body["data"]
+        .as_array()
+        .context("Unexpected value")?
+        .iter()
+        .map(|run| RunSummary {
+            game: run["game"]["data"]["names"]["international"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            category: run["category"]["data"]["name"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            time: run["times"]["primary_t"]
+                .as_f64()
+                .map(|secs| format_time(Duration::from_millis((secs * 1000.0).round() as u64)))
+                .unwrap_or_default(),
+            date: run["date"].as_str().unwrap_or_default().to_string(),
+        })
+        .collect();
+
+    Ok(runs)
+}
+
+/// Flags the runs that would beat the world record of their category.
+pub async fn mark_wr_candidates(runs: &mut [Run], client: &reqwest::Client) {
+    for run in runs {
+        match fetch_wr_time(&run.game_id, &run.category_id, &run.subcategory, client).await {
+            Ok(wr) => {
+                // An empty leaderboard says nothing about how fast the run is.
+                run.would_be_wr = wr.is_some_and(|wr| run.duration < wr);
+                run.wr_time = wr;
+            }
+            Err(e) => tracing::warn!(
+                "Couldn't fetch the world record for {} - {}: {e:?}",
+                run.game_name,
+                run.cat_full
+            ),
+        }
+    }
+}
+
+/// Fetches a single run by ID, whatever its status.
+pub async fn get_run(run_id: &str, client: &reqwest::Client) -> Result<Run> {
+    let response = client
+        .get(api::api_url(&format!("runs/{run_id}?embed={}", embeds())))
+        .send_with_retry()
+        .await
+        .context("Requesting run metadata")?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        bail!("There's no run with ID {run_id:?}");
+    }
+
+    let run: Value = response
+        .error_for_status()?
+        .json_limited()
+        .await
+        .context("Parsing run metadata")?;
+    Run::try_from(&run["data"])
+}
+
+/// Downloads the runs given with `--run-id`, without prompting.
+async fn download_run_ids(
+    cfg: &Config,
+    client: &reqwest::Client,
+    status: &Arc<DownloadStatus>,
+    done: &Arc<AtomicBool>,
+) -> Result<Vec<Result<()>>> {
+    let mut outcomes = Vec::new();
+
+    for run_id in &cfg.run_ids {
+        let result = match get_run(run_id, client).await {
+            Ok(mut run) => {
+                stats::SESSION_STATS.fetched.fetch_add(1, Ordering::Relaxed);
+                choose_vod_source(&mut run, cfg)?;
+                match download_run(&run, cfg, status, done).await {
+                    Ok(path) => {
+                        println!("Downloaded {run} to {}", path.display());
+                        Ok(())
+                    }
+                    // Already warned about, there's nothing to download.
+                    Err(e) if e.is::<NoVod>() || e.is::<DryRun>() || e.is::<Skipped>() => Ok(()),
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => {
+                stats::SESSION_STATS.failed.fetch_add(1, Ordering::Relaxed);
+                Err(e)
+            }
+        };
+
+        match result {
+            Err(e) if cfg.run_ids.len() > 1 && !done.load(Ordering::SeqCst) => {
+                tracing::error!("Couldn't download {run_id}: {e:?}");
+                outcomes.push(Err(e));
+            }
+            Err(e) => return Err(e),
+            Ok(()) => outcomes.push(Ok(())),
+        }
+    }
+
+    Ok(outcomes)
+}
+
+/// Fetches the pending runs of every game concurrently, skipping games whose
+/// request fails. Runs are deduplicated by ID.
+async fn get_all_pending_runs(
+    games: &[String],
+    category: Option<&CategoryFilter>,
+    client: &reqwest::Client,
+) -> Result<Vec<Run>> {
+    get_all_pending_runs_since(games, category, None, client).await
+}
+
+/// [`get_all_pending_runs`], only keeping those submitted since `last_seen`.
+async fn get_all_pending_runs_since(
+    games: &[String],
+    category: Option<&CategoryFilter>,
+    last_seen: Option<DateTime<Utc>>,
+    client: &reqwest::Client,
+) -> Result<Vec<Run>> {
+    let mut game_ids = Vec::with_capacity(games.len());
+    for game in games {
+        game_ids.push(resolve_game_id(game, client).await?);
+    }
+
+    let results = futures::future::join_all(game_ids.iter().map(|id| async move {
+        let category = match category {
+            Some(category) => category.resolve(id, client).await,
+            None => None,
+        };
+        get_pending_runs_since(id, category.as_deref(), last_seen, client).await
+    }))
+    .await;
+
+    let mut seen = HashSet::new();
+    let mut runs = Vec::new();
+    let mut failures = 0;
+
+    for (game_id, result) in game_ids.iter().zip(results) {
+        match result {
+            Ok(game_runs) => runs.extend(
+                game_runs
+                    .into_iter()
+                    .filter(|run| seen.insert(run.run_id.clone())),
+            ),
+            Err(e) => {
+                tracing::warn!("Couldn't fetch runs for game {game_id}: {e:?}");
+                failures += 1;
+            }
+        }
+    }
+
+    if failures > 0 && failures == game_ids.len() {
+        bail!("Couldn't fetch runs for any game");
+    }
+
+    Ok(runs)
+}
+
+fn resolve_output_path(run: &Run, output_dir: &Path, scheme: OrganizeBy) -> Result<PathBuf> {
+    let dir = match scheme {
+        OrganizeBy::None => output_dir.to_path_buf(),
+        OrganizeBy::Game => output_dir.join(&run.game),
+        OrganizeBy::GameCategory => output_dir.join(&run.game).join(&run.cat),
+        OrganizeBy::Date => output_dir.join(chrono::Local::now().format("%Y-%m-%d").to_string()),
+    };
+
+    fs::create_dir_all(&dir)
+        .with_context(|| format!("Couldn't create output directory {}", dir.display()))?;
+
+    Ok(dir)
+}
+
+/// Returns the path to write `path` to under `strategy`, or a [`Skipped`] error
+/// if it exists and shouldn't be touched.
+fn resolve_collision(path: &Path, strategy: CollisionStrategy) -> Result<PathBuf> {
+    if !path.exists() {
+        return Ok(path.to_path_buf());
+    }
+
+    match strategy {
+        CollisionStrategy::Skip => Err(Skipped(path.to_path_buf()).into()),
+        CollisionStrategy::Overwrite => Ok(path.to_path_buf()),
+        CollisionStrategy::Rename => {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let ext = path
+                .extension()
+                .map(|e| format!(".{}", e.to_string_lossy()));
+            (1..)
+                .map(|n| path.with_file_name(format!("{stem}_{n}{}", ext.as_deref().unwrap_or(""))))
+                .find(|candidate| !candidate.exists())
+                .context("No free file name left")
+        }
+    }
+}
+
+/// Where the `--multi-output` formats other than the main output's go, next
+/// to `output_path` and under `strategy`. Those to skip are left out, the
+/// others are still worth making.
+fn extra_output_paths(
+    output_path: &Path,
+    formats: &[OutputFormat],
+    strategy: CollisionStrategy,
+) -> Result<Vec<(OutputFormat, PathBuf)>> {
+    let mut outputs = Vec::with_capacity(formats.len());
+    for format in formats {
+        let path = output_path.with_extension(&format.container);
+        if path == output_path {
+            continue;
+        }
+        match resolve_collision(&path, strategy) {
+            Ok(path) => outputs.push((format.clone(), path)),
+            Err(e) if e.is::<Skipped>() => progress::println(format_args!("Not writing {e}")),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(outputs)
+}
+
+fn write_m3u8_playlist(downloads: &[(Run, PathBuf)], out: &Path, absolute: bool) -> Result<()> {
+    let base = out.parent().unwrap_or(Path::new(""));
+    let mut playlist = String::from("#EXTM3U\n");
+
+    for (run, path) in downloads {
+        let entry = if absolute {
+            fs::canonicalize(path)
+                .with_context(|| format!("Couldn't resolve {}", path.display()))?
+        } else {
+            path.strip_prefix(base).unwrap_or(path).to_path_buf()
+        };
+
+        playlist.push_str(&format!(
+            "#EXTINF:{},{} - {} by {}\n{}\n",
+            run.duration.as_secs(),
+            run.game_name,
+            run.cat_full,
+            run.player,
+            entry.display()
+        ));
+    }
+
+    fs::write(out, playlist).with_context(|| format!("Couldn't write playlist {}", out.display()))
+}
+
+/// Progress of the download in flight, shared with the status reporter.
+#[derive(Default)]
+struct DownloadStatus {
+    current: Mutex<Option<(Run, Instant)>>,
+    last_progress: Mutex<String>,
+    bytes_written: Arc<AtomicU64>,
+    /// Set through the control socket to hold the copy loop.
+    paused: AtomicBool,
+    /// Where progress goes instead of stdout, when downloading in parallel.
+    progress: Option<tokio::sync::mpsc::Sender<progress::ProgressEvent>>,
+    /// What the last attempt ran, for `--write-metadata`.
+    commands: Mutex<sidecar::Commands>,
+}
+
+impl DownloadStatus {
+    fn start(&self, run: &Run) {
+        *self.current.lock().unwrap() = Some((run.clone(), Instant::now()));
+        self.last_progress.lock().unwrap().clear();
+        self.bytes_written.store(0, Ordering::SeqCst);
+    }
+
+    fn finish(&self) {
+        *self.current.lock().unwrap() = None;
+    }
+
+    fn report(&self) -> String {
+        let Some((run, start_time)) = self.current.lock().unwrap().clone() else {
+            return "No download in progress".to_string();
+        };
+
+        let bytes_written = self.bytes_written.load(Ordering::SeqCst);
+        let elapsed = Duration::from_secs(start_time.elapsed().as_secs());
+        let progress = self.last_progress.lock().unwrap();
+        let eta = progress
+            .split_once("ETA ")
+            .and_then(|(_, rest)| rest.split_whitespace().next())
+            .unwrap_or("unknown");
+
+        format!(
+            "{run}: {} written in {}, ETA {eta}",
+            ByteSize(bytes_written),
+            format_time(elapsed)
+        )
+    }
+}
+
+/// Prints the download status to stderr whenever SIGUSR1 is received.
+#[cfg(unix)]
+fn spawn_status_reporter(status: Arc<DownloadStatus>) -> Result<()> {
+    let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGUSR1])?;
+
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            eprintln!("\n{}", status.report());
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn spawn_status_reporter(_: Arc<DownloadStatus>) -> Result<()> {
+    tracing::debug!("SIGUSR1 is not available on this platform, status reporting disabled");
+    Ok(())
+}
+
+fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Sets `done` if nothing was written for longer than `timeout`.
+///
+/// The thread checks every [`WATCHDOG_INTERVAL`] and returns as soon as `done`
+/// is set, either by itself or by the caller; unpark it to stop it early.
+fn start_watchdog(
+    last_write: Arc<AtomicU64>,
+    timeout: Duration,
+    done: Arc<AtomicBool>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::park_timeout(WATCHDOG_INTERVAL);
+
+        if done.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let idle = unix_millis().saturating_sub(last_write.load(Ordering::SeqCst));
+        if Duration::from_millis(idle) > timeout {
+            tracing::warn!("No output for {}s, killing the download", idle / 1000);
+            done.store(true, Ordering::SeqCst);
+            return;
+        }
+    })
+}
+
+/// Plays the VOD in mpv and waits for the player to close.
+///
+/// Without mpv, prints the URL instead so it can be opened elsewhere.
+async fn preview_run(run: &Run, done: &Arc<AtomicBool>) -> Result<()> {
+    if run.vod_missing {
+        println!("{} has no VOD to preview", run.run_id);
+        return Ok(());
+    }
+
+    let Some(mpv) = tools::find_on_path("mpv") else {
+        println!("mpv not found, open {} to preview the run", run.vod_uri);
+        return Ok(());
+    };
+
+    let mut child = Command::new(mpv)
+        .args(["--no-terminal", "--really-quiet", &run.vod_uri])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .context("Couldn't start mpv")?;
+
+    wait_cmd(
+        &mut child,
+        done,
+        &AtomicBool::new(false),
+        DEFAULT_POLL_INTERVAL,
+    )
+    .await
+    .context("mpv process")
+}
+
+/// Set by Ctrl+C and SIGTERM, which end the session. `done` is also set by
+/// them, but only stops the downloads in flight.
+static SHUTTING_DOWN: LazyLock<Arc<AtomicBool>> = LazyLock::new(Default::default);
+
+/// How often [`shutdown_requested`] checks for a shutdown.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Completes once Ctrl+C or SIGTERM asked the session to end.
+async fn shutdown_requested() {
+    while !SHUTTING_DOWN.load(Ordering::SeqCst) {
+        tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+    }
+}
+
+/// Runs `prompt` on a thread of its own, since SIGTERM can't interrupt a
+/// blocking read of the terminal. A shutdown stops the wait: the terminal is
+/// put back the way it was, and there's no answer.
+fn interruptible_prompt<T: Send + 'static>(
+    prompt: impl FnOnce() -> dialoguer::Result<T> + Send + 'static,
+) -> Result<T> {
+    prompt_until(&SHUTTING_DOWN, prompt)
+}
+
+/// [`interruptible_prompt`], giving up once `shutting_down` is set.
+fn prompt_until<T: Send + 'static>(
+    shutting_down: &AtomicBool,
+    prompt: impl FnOnce() -> dialoguer::Result<T> + Send + 'static,
+) -> Result<T> {
+    #[cfg(unix)]
+    let termios = nix::sys::termios::tcgetattr(io::stdin()).ok();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(prompt());
+    });
+
+    loop {
+        match rx.recv_timeout(SHUTDOWN_POLL_INTERVAL) {
+            Ok(answer) => return Ok(answer?),
+            Err(RecvTimeoutError::Timeout) if !shutting_down.load(Ordering::SeqCst) => {}
+            Err(RecvTimeoutError::Timeout) => break,
+            Err(RecvTimeoutError::Disconnected) => bail!("The prompt panicked"),
+        }
+    }
+
+    // The prompt was left in raw mode with the cursor hidden.
+    #[cfg(unix)]
+    if let Some(termios) = termios {
+        let _ =
+            nix::sys::termios::tcsetattr(io::stdin(), nix::sys::termios::SetArg::TCSANOW, &termios);
+    }
+    let _ = dialoguer::console::Term::stderr().show_cursor();
+    bail!("Shutting down, the prompt went unanswered")
+}
+
+async fn download_run(
+    run: &Run,
+    cfg: &Config,
+    status: &Arc<DownloadStatus>,
+    done: &Arc<AtomicBool>,
+) -> Result<PathBuf> {
+    let result = download_run_counted(run, cfg, status, done).await;
+
+    // What stopped this download shouldn't stop the next one, unless the
+    // session is ending.
+    if !SHUTTING_DOWN.load(Ordering::SeqCst) {
+        done.store(false, Ordering::SeqCst);
+    }
+
+    result
+}
+
+async fn download_run_counted(
+    run: &Run,
+    cfg: &Config,
+    status: &Arc<DownloadStatus>,
+    done: &Arc<AtomicBool>,
+) -> Result<PathBuf> {
+    let stats = &stats::SESSION_STATS;
+    if run.vod_missing {
+        tracing::warn!("{} has no VOD, skipping", run.run_id);
+        stats.skipped.fetch_add(1, Ordering::Relaxed);
+        return Err(NoVod.into());
+    }
+
+    // Tags everything logged during the download with the run it's about.
+    let span = tracing::warn_span!(
+        "download",
+        run_id = %run.run_id,
+        url = %run.vod_uri,
+        codec = tracing::field::Empty,
+        output_size = tracing::field::Empty,
+    );
+    let result = download_run_inner(run, cfg, status, done)
+        .instrument(span)
+        .await;
+
+    match &result {
+        // Callers count a skipped run as done, its file is already there, but
+        // it wasn't downloaded now.
+        Err(e) if e.is::<Skipped>() => {
+            progress::println(format_args!("{e}, skipping"));
+            stats.skipped.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(e) if e.is::<DryRun>() => {}
+        Ok(path) => {
+            stats.downloaded.fetch_add(1, Ordering::Relaxed);
+            if let Ok(metadata) = fs::metadata(path) {
+                stats
+                    .bytes_written
+                    .fetch_add(metadata.len(), Ordering::Relaxed);
+            }
+        }
+        Err(_) => {
+            stats.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    result
+}
+
+async fn download_run_inner(
+    run: &Run,
+    cfg: &Config,
+    status: &Arc<DownloadStatus>,
+    done: &Arc<AtomicBool>,
+) -> Result<PathBuf> {
+    let output_path = resolve_output_path(run, &cfg.output_dir, cfg.organize_by)?
+        .join(format!("{}.mp4", run.filename(&cfg.filename_template)));
+    let output_path = resolve_collision(&output_path, cfg.on_collision)?;
+
+    progress::println(format_args!("URL: {}", run.vod_uri));
+    match platform::vod_platform(&run.vod_uri) {
+        platform::VodPlatform::TwitchClip => {
+            tracing::warn!(
+                "{} is a Twitch clip, it may not cover the whole run",
+                run.vod_uri
+            );
+        }
+        platform::VodPlatform::Bilibili => {
+            progress::println(
+                "Bilibili needs cookies, reading them from Chrome: log in there if the download \
+                 fails",
+            );
+        }
+        _ => {}
+    }
+
+    let extra_outputs = extra_output_paths(&output_path, &cfg.multi_output, cfg.on_collision)?;
+
+    if cfg.dry_run {
+        let codec = cfg.codec.resolve().await?;
+        print_pipeline(run, cfg, &output_path, &extra_outputs, codec);
+        return Err(DryRun.into());
+    }
+
+    if let Some(hook) = &cfg.file.pre_download_hook {
+        hooks::run_hook(hook, &hooks::hook_env(run, None)).context("Pre-download hook")?;
+    }
+
+    let codec = cfg.codec.resolve().await?;
+    tracing::Span::current().record("codec", format!("{codec:?}"));
+    // Written as `.part` files, only renamed once complete.
+    let output_part = part_path(&output_path);
+    let extra_parts = extra_outputs
+        .iter()
+        .map(|(format, path)| (format.clone(), part_path(path)))
+        .collect::<Vec<_>>();
+
+    let started = Instant::now();
+    let transferred = if cfg.burn_subtitles {
+        // burn_subtitles() stages the output on its own.
+        let source = postprocess::subtitle_source(&output_path, &cfg.temp_dir())?;
+        transfer(run, cfg, source.path(), &extra_parts, codec, status, done)
+            .await
+            .and_then(|()| {
+                progress::println("Burning in subtitles...");
+                postprocess::burn_subtitles(source.path(), &output_path, codec, cfg)
+            })
+            .map(|args| {
+                let mut commands = status.commands.lock().unwrap();
+                commands.postprocess_args.push(args);
+            })
+    } else {
+        transfer(run, cfg, &output_part, &extra_parts, codec, status, done)
+            .await
+            .and_then(|()| atomic_rename(&output_part, &output_path))
+    };
+    let transferred = transferred.and_then(|()| {
+        extra_parts
+            .iter()
+            .zip(&extra_outputs)
+            .try_for_each(|((_, part), (_, path))| atomic_rename(part, path))
+    });
+
+    if let Err(e) = transferred {
+        // Whatever ffmpeg wrote so far isn't playable.
+        let written = std::iter::once(&output_part).chain(extra_parts.iter().map(|(_, p)| p));
+        for path in written.filter(|path| path.exists()) {
+            if cfg.keep_partial {
+                progress::println(format_args!("Partial download kept at {}", path.display()));
+                continue;
+            }
+            match fs::remove_file(path) {
+                Ok(()) => tracing::debug!("Removed partial {}", path.display()),
+                Err(e) => tracing::warn!("Couldn't remove {}: {e}", path.display()),
+            }
+        }
+        return Err(e);
+    }
+
+    for (_, path) in &extra_outputs {
+        progress::println(format_args!("Also saved to {}", path.display()));
+    }
+
+    // Only for the summary, a file ffprobe can't read isn't an error yet.
+    if let Ok(media) = postprocess::probe_duration(&output_path) {
+        stats::SESSION_STATS.record_encode(started.elapsed(), media);
+    }
+
+    if cfg.normalize_audio && cfg.two_pass {
+        progress::println("Normalizing audio...");
+        postprocess::normalize_audio_two_pass(&output_path, &cfg.temp_dir())?;
+    }
+
+    postprocess::add_chapters(&output_path, cfg.run_start, &cfg.temp_dir())?;
+
+    // The preview is a nice-to-have, it doesn't fail the download.
+    if cfg.thumbnail_gif && !cfg.no_thumbnail_gif {
+        match postprocess::generate_gif_preview(&output_path, run, cfg.thumbnail_gif_duration) {
+            Ok(gif) => progress::println(format_args!("Preview saved to {}", gif.display())),
+            Err(e) => tracing::warn!("Couldn't generate a GIF preview: {e:#}"),
+        }
+    }
+
+    if let Some((cols, rows)) = cfg.sprite_sheet {
+        match postprocess::generate_sprite_sheet(&output_path, run, cols, rows) {
+            Ok(sprites) => {
+                progress::println(format_args!("Sprite sheet saved to {}", sprites.display()))
+            }
+            Err(e) => tracing::warn!("Couldn't generate a sprite sheet: {e:#}"),
+        }
+    }
+
+    if cfg.output_images {
+        match postprocess::extract_frames(
+            &output_path,
+            cfg.start_time,
+            cfg.duration,
+            cfg.fps_images,
+        ) {
+            Ok(frames) => match frames.first().and_then(|frame| frame.parent()) {
+                Some(dir) => progress::println(format_args!(
+                    "{} frames saved to {}",
+                    frames.len(),
+                    dir.display()
+                )),
+                None => tracing::warn!("No frames in the range given to --output-images"),
+            },
+            Err(e) => tracing::warn!("Couldn't extract frames: {e:#}"),
+        }
+    }
+
+    let mut vmaf = None;
+    if cfg.measure_quality {
+        progress::println("Measuring quality...");
+        match measure_quality(run, cfg, &output_path) {
+            Ok(score) => {
+                progress::println(format_args!("VMAF: {score:.2}"));
+                vmaf = Some(score);
+            }
+            Err(e) => tracing::warn!("Couldn't measure the quality: {e:#}"),
+        }
+    }
+
+    if cfg.write_metadata {
+        let commands = status.commands.lock().unwrap().clone();
+        match sidecar::write(run, &commands, vmaf, &output_path) {
+            Ok(path) => progress::println(format_args!("Metadata saved to {}", path.display())),
+            Err(e) => tracing::warn!("Couldn't write the metadata: {e:#}"),
+        }
+    }
+
+    if let Ok(metadata) = fs::metadata(&output_path) {
+        tracing::Span::current().record("output_size", metadata.len());
+    }
+
+    progress::println(format_args!("Run page: {}", run.permalink));
+
+    if let Some(hook) = &cfg.file.post_download_hook {
+        if let Err(e) = hooks::run_hook(hook, &hooks::hook_env(run, Some(&output_path))) {
+            tracing::warn!("Post-download hook: {e:#}");
+        }
+    }
+
+    Ok(output_path)
+}
+
+/// Where `path` is written until it's complete.
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(format!(".{}", encode::PART_EXTENSION));
+    PathBuf::from(part)
+}
+
+/// Moves the finished download at `from` to `to`, replacing what's there.
+fn atomic_rename(from: &Path, to: &Path) -> Result<()> {
+    // Windows won't rename over an existing file.
+    #[cfg(windows)]
+    match fs::remove_file(to) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("Couldn't replace {}", to.display())),
+    }
+
+    fs::rename(from, to)
+        .with_context(|| format!("Couldn't move {} to {}", from.display(), to.display()))
+}
+
+/// `--measure-quality`: the VMAF score of `output_path` against a fresh copy
+/// of the source, deleted after.
+fn measure_quality(run: &Run, cfg: &Config, output_path: &Path) -> Result<f64> {
+    let dir = tempfile::Builder::new()
+        .prefix("dlvod-reference-")
+        .tempdir_in(cfg.temp_dir())
+        .context("Couldn't create a directory for the reference")?;
+    let reference = postprocess::download_reference(run, cfg, dir.path())?;
+    postprocess::measure_vmaf(output_path, &reference)
+}
+
+/// Downloads the VOD and transcodes it into `output_path`, and into each of
+/// `extra_outputs`.
+async fn transfer(
+    run: &Run,
+    cfg: &Config,
+    output_path: &Path,
+    extra_outputs: &[(OutputFormat, PathBuf)],
+    codec: Codec,
+    status: &Arc<DownloadStatus>,
+    done: &Arc<AtomicBool>,
+) -> Result<()> {
+    if let Some(segment_mb) = cfg.segment_size {
+        let segment_size = segment_mb * 1024 * 1024;
+        match download_segmented(run, cfg, segment_size, output_path, codec, status, done).await {
+            Ok(true) => return Ok(()),
+            Ok(false) => {
+                progress::println("Source can't be fetched in segments, streaming it instead")
+            }
+            Err(e) if done.load(Ordering::SeqCst) => return Err(e),
+            Err(e) => tracing::warn!("Segmented download failed, streaming instead: {e:?}"),
+        }
+    }
+
+    let mut attempt = 1;
+    let mut resume = resume::Resume::default();
+    loop {
+        let seek = resume.offset();
+        let attempt_result = download_attempt(
+            run,
+            cfg,
+            output_path,
+            extra_outputs,
+            codec,
+            seek,
+            status,
+            done,
+        )
+        .await;
+        match attempt_result {
+            Ok(()) => return resume.finish(output_path, &cfg.temp_dir()),
+            Err(e) if done.load(Ordering::SeqCst) => return Err(e),
+            Err(e) if cfg.resume && attempt < MAX_STALL_RETRIES => {
+                if resume.save_part(output_path, &cfg.temp_dir())? {
+                    let from = resume.offset().unwrap_or_default();
+                    progress::println(format_args!(
+                        "\nDownload failed, resuming from {} ({attempt}/{MAX_STALL_RETRIES})...",
+                        format_time(from)
+                    ));
+                } else {
+                    progress::println(format_args!(
+                        "\nDownload failed, retrying ({attempt}/{MAX_STALL_RETRIES})..."
+                    ));
+                }
+                tracing::debug!("Download attempt failed: {e:?}");
+                attempt += 1;
+            }
+            Err(e) if e.is::<Stalled>() && attempt < MAX_STALL_RETRIES => {
+                progress::println(format_args!(
+                    "\nDownload stalled, retrying ({attempt}/{MAX_STALL_RETRIES})..."
+                ));
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// With `seek`, ffmpeg drops the input up to there: see [`resume`].
+fn ffmpeg_command(
+    codec: Codec,
+    cfg: &Config,
+    output_path: &Path,
+    seek: Option<Duration>,
+) -> Command {
+    // With --burn-subtitles, the VOD is only saved here and transcoded after.
+    let mut args = if cfg.burn_subtitles {
+        build_copy_args()
+    } else {
+        build_ffmpeg_args(codec, cfg, "pipe:", None)
+    };
+
+    // An input option, it has to come before `-i`.
+    if let (Some(seek), Some(input)) = (seek, args.iter().position(|arg| arg == "-i")) {
+        let seek = format!("{:.3}", seek.as_secs_f64());
+        args.splice(input..input, ["-ss".to_string(), seek]);
+    }
+
+    let mut ffmpeg_cmd = Command::new(tools::ffmpeg());
+    ffmpeg_cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .args(["-loglevel".to_string(), cfg.ffmpeg_loglevel().arg()])
+        .args(args)
+        .args(encode::part_muxer_args(output_path))
+        .arg(output_path);
+    ffmpeg_cmd
+}
+
+/// The ffmpeg encoding the piped VOD into a `--multi-output` format.
+fn extra_output_command(
+    format: &OutputFormat,
+    codec: Codec,
+    cfg: &Config,
+    output_path: &Path,
+) -> Command {
+    let mut ffmpeg_cmd = Command::new(tools::ffmpeg());
+    ffmpeg_cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .args(["-loglevel".to_string(), cfg.ffmpeg_loglevel().arg()])
+        .args(build_output_args(format, codec, cfg))
+        .args(encode::part_muxer_args(output_path))
+        .arg(output_path);
+    ffmpeg_cmd
+}
+
+/// Fetches the VOD in parallel byte ranges, then feeds the segments to ffmpeg.
+///
+/// Returns `false` without downloading anything if the source doesn't support it.
+async fn download_segmented(
+    run: &Run,
+    cfg: &Config,
+    segment_size: u64,
+    output_path: &Path,
+    codec: Codec,
+    status: &Arc<DownloadStatus>,
+    done: &Arc<AtomicBool>,
+) -> Result<bool> {
+    let client = http_client(cfg)?;
+    let Some(source) = segments::segmented_source(&run.vod_uri, &client, cfg.insecure).await?
+    else {
+        return Ok(false);
+    };
+
+    // Removes the segments whichever way this returns.
+    let segment_dir = tempfile::Builder::new()
+        .prefix(&format!("dlvod-{}-", run.run_id))
+        .tempdir_in(cfg.temp_dir())
+        .context("Couldn't create a directory for segments")?;
+
+    status.start(run);
+    let result = segments::download_segments(
+        &client,
+        &source,
+        segment_size,
+        segment_dir.path(),
+        &format!("dlvod-{}", run.run_id),
+        &status.bytes_written,
+        done,
+    )
+    .await;
+    status.finish();
+    let segment_paths = result?;
+
+    let mut ffmpeg_cmd = ffmpeg_command(codec, cfg, output_path, None);
+    *status.commands.lock().unwrap() = sidecar::Commands {
+        ytdlp_args: Vec::new(),
+        ffmpeg_args: sidecar::extract_args(&ffmpeg_cmd),
+        ..Default::default()
+    };
+    let mut ffmpeg_child = process::spawn(&mut ffmpeg_cmd)?;
+    spawn_ffmpeg_logger(ffmpeg_child.stderr.take().unwrap());
+    let mut ffmpeg_stdin = ffmpeg_child.stdin.take().unwrap();
+
+    let merged = segments::merge_segments(&segment_paths, &mut ffmpeg_stdin).await;
+    drop(ffmpeg_stdin);
+    segments::remove_segments(&segment_paths);
+    if let Err(e) = merged {
+        process::kill_and_wait(&mut ffmpeg_child).await;
+        return Err(e).context("Couldn't write to ffmpeg");
+    }
+
+    let poll_interval = Duration::from_millis(cfg.process_poll_interval);
+    let result = wait_cmd(
+        &mut ffmpeg_child,
+        done,
+        &AtomicBool::new(false),
+        poll_interval,
+    )
+    .await;
+    process::kill_and_wait(&mut ffmpeg_child).await;
+    result.context("ffmpeg process")?;
+    progress::println("Done!");
+
+    Ok(true)
+}
+
+/// Logs the lines of a transcoding ffmpeg's `stderr`, at the level each was
+/// tagged with by [`FfmpegLogLevel::arg`].
+fn spawn_ffmpeg_logger(stderr: ChildStderr) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut lines = tokio::io::BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            // After the `[h264 @ 0x...]` context, if there's one.
+            let level = [
+                "panic", "fatal", "error", "warning", "info", "verbose", "debug",
+            ]
+            .into_iter()
+            .find(|level| line.contains(&format!("[{level}] ")));
+            match level {
+                Some("panic" | "fatal" | "error") => tracing::error!("ffmpeg: {line}"),
+                Some("warning") => tracing::warn!("ffmpeg: {line}"),
+                Some("verbose") => tracing::debug!("ffmpeg: {line}"),
+                Some("debug") => tracing::trace!("ffmpeg: {line}"),
+                _ => tracing::info!("ffmpeg: {line}"),
+            }
+        }
+    })
+}
+
+/// How many progress lines can wait for the renderer before yt-dlp's stderr
+/// stops being read.
+const PROGRESS_BACKLOG: usize = 64;
+
+/// Reads yt-dlp's progress lines from `stderr` and prints them as they come,
+/// or hands them to the aggregator in `status`, on the runtime. Returns the
+/// rendering task. With `verbose`, the lines that aren't progress go to
+/// stderr instead.
+///
+/// Progress display is best-effort: a closed stdout (or a panic in the
+/// renderer) must not take the download down with it.
+fn spawn_progress_reporter(
+    run_id: &str,
+    stderr: ChildStderr,
+    status: Arc<DownloadStatus>,
+    verbose: bool,
+) -> tokio::task::JoinHandle<()> {
+    let run_id = run_id.to_string();
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<String>(PROGRESS_BACKLOG);
+
+    tokio::spawn(async move {
+        let mut lines = tokio::io::BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if verbose && !line.starts_with("[download]") {
+                let _ = writeln!(progress::stderr(), "[yt-dlp] {line}");
+                continue;
+            }
+            if tx.send(line).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        while let Some(line) = rx.recv().await {
+            let line = line.trim_end();
+            let bytes_read = status.bytes_written.load(Ordering::SeqCst);
+            *status.last_progress.lock().unwrap() = line.to_string();
+
+            if let Some(progress) = &status.progress {
+                let event = progress::ProgressEvent {
+                    run_id: run_id.clone(),
+                    bytes: bytes_read,
+                    message: line.to_string(),
+                };
+                if progress.send(event).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+
+            let mut stdout = io::stdout().lock();
+            let _ = write!(stdout, "\r\x1b[2K\r{line} ({})", ByteSize(bytes_read));
+            let _ = stdout.flush();
+        }
+    })
+}
+
+/// The yt-dlp streaming the run's VOD to its stdout.
+fn ytdlp_command(run: &Run, cfg: &Config) -> Command {
+    let mut yt_dlp_cmd = Command::new(tools::ytdlp());
+    yt_dlp_cmd.args(tools::ytdlp_network_args());
+    yt_dlp_cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .args([
+            &run.vod_uri,
+            "--downloader",
+            "aria2c",
+            "--progress",
+            "--newline",
+            "-o",
+            "-",
+        ])
+        .arg(if cfg.ytdlp_verbose { "--verbose" } else { "-q" });
+
+    let platform = platform::vod_platform(&run.vod_uri);
+    yt_dlp_cmd.args(platform.ytdlp_args());
+    if let Some(format) = cfg.yt_format.as_deref().or(platform.ytdlp_format()) {
+        yt_dlp_cmd.args(["-f", format]);
+    }
+
+    if cfg.insecure {
+        yt_dlp_cmd.arg("--no-check-certificates");
+    }
+
+    if !cfg.ytdlp_no_threads {
+        yt_dlp_cmd.args(["-N".to_string(), cfg.ytdlp_threads.to_string()]);
+    }
+
+    yt_dlp_cmd
+}
+
+/// Prints what [`download_attempt`] would run, for `--dry-run`.
+fn print_pipeline(
+    run: &Run,
+    cfg: &Config,
+    output_path: &Path,
+    extra_outputs: &[(OutputFormat, PathBuf)],
+    codec: Codec,
+) {
+    // yt-dlp is given a pipe it never reads from.
+    let mut pipeline = format!(
+        "{} < /dev/null",
+        process::shell_command(&ytdlp_command(run, cfg))
+    );
+    if !extra_outputs.is_empty() {
+        let extras = extra_outputs
+            .iter()
+            .map(|(format, path)| {
+                let cmd = extra_output_command(format, codec, cfg, path);
+                format!(" >({})", process::shell_command(&cmd))
+            })
+            .collect::<String>();
+        pipeline.push_str(&format!(" | tee{extras}"));
+    }
+    let ffmpeg_cmd = ffmpeg_command(codec, cfg, output_path, None);
+    pipeline.push_str(&format!(" | {}", process::shell_command(&ffmpeg_cmd)));
+    progress::println(format_args!("{pipeline}"));
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn download_attempt(
+    run: &Run,
+    cfg: &Config,
+    output_path: &Path,
+    extra_outputs: &[(OutputFormat, PathBuf)],
+    codec: Codec,
+    seek: Option<Duration>,
+    status: &Arc<DownloadStatus>,
+    done: &Arc<AtomicBool>,
+) -> Result<()> {
+    let mut yt_dlp_cmd = ytdlp_command(run, cfg);
+    let mut ffmpeg_cmd = ffmpeg_command(codec, cfg, output_path, seek);
+    *status.commands.lock().unwrap() = sidecar::Commands {
+        ytdlp_args: sidecar::extract_args(&yt_dlp_cmd),
+        ffmpeg_args: sidecar::extract_args(&ffmpeg_cmd),
+        ..Default::default()
+    };
+
+    let started = Instant::now();
+    let mut yt_dlp_child = process::spawn(&mut yt_dlp_cmd)?;
+    let mut ffmpeg_child = match process::spawn(&mut ffmpeg_cmd) {
+        Ok(child) => child,
+        Err(e) => {
+            process::kill_and_wait(&mut yt_dlp_child).await;
+            return Err(e.into());
+        }
+    };
+    spawn_ffmpeg_logger(ffmpeg_child.stderr.take().unwrap());
+    let mut extra_children = Vec::with_capacity(extra_outputs.len());
+    for (format, path) in extra_outputs {
+        match process::spawn(&mut extra_output_command(format, codec, cfg, path)) {
+            Ok(mut child) => {
+                spawn_ffmpeg_logger(child.stderr.take().unwrap());
+                extra_children.push((child, path));
+            }
+            Err(e) => {
+                process::kill_and_wait(&mut yt_dlp_child).await;
+                process::kill_and_wait(&mut ffmpeg_child).await;
+                for (child, _) in &mut extra_children {
+                    process::kill_and_wait(child).await;
+                }
+                return Err(e.into());
+            }
+        }
+    }
+
+    let yt_dlp_stdout = yt_dlp_child.stdout.take().unwrap();
+    let yt_dlp_stderr = yt_dlp_child.stderr.take().unwrap();
+    let ffmpeg_stdin = ffmpeg_child.stdin.take().unwrap();
+
+    status.start(run);
+
+    let progress = spawn_progress_reporter(
+        &run.run_id,
+        yt_dlp_stderr,
+        Arc::clone(status),
+        cfg.ytdlp_verbose,
+    );
+
+    let last_write = Arc::new(AtomicU64::new(unix_millis()));
+    let stalled = Arc::new(AtomicBool::new(false));
+    let watchdog = start_watchdog(
+        Arc::clone(&last_write),
+        Duration::from_secs(cfg.stall_timeout),
+        Arc::clone(&stalled),
+    );
+
+    // Both ends are dropped once the copy is over, closing ffmpeg's input.
+    let pipe_task = if extra_children.is_empty() {
+        tokio::spawn(pipe::copy(
+            yt_dlp_stdout,
+            ffmpeg_stdin,
+            pipe::Options::new(cfg),
+            Arc::clone(status),
+            last_write,
+            Arc::clone(done),
+        ))
+    } else {
+        let stdins = std::iter::once(ffmpeg_stdin)
+            .chain(
+                extra_children
+                    .iter_mut()
+                    .map(|(child, _)| child.stdin.take().unwrap()),
+            )
+            .collect();
+        tokio::spawn(pipe::tee(
+            yt_dlp_stdout,
+            stdins,
+            pipe::Options::new(cfg),
+            Arc::clone(status),
+            last_write,
+            Arc::clone(done),
+        ))
+    };
+
+    let poll_interval = Duration::from_millis(cfg.process_poll_interval);
+    let mut result = wait_cmd(&mut yt_dlp_child, done, &stalled, poll_interval)
+        .await
+        .context("yt-dlp process");
+    if result.is_ok() {
+        result = wait_cmd(&mut ffmpeg_child, done, &stalled, poll_interval)
+            .await
+            .context("ffmpeg process");
+        for (child, path) in &mut extra_children {
+            if result.is_err() {
+                break;
+            }
+            result = wait_cmd(child, done, &stalled, poll_interval)
+                .await
+                .with_context(|| format!("ffmpeg process for {}", path.display()));
+        }
+    } else if cfg.resume && !done.load(Ordering::SeqCst) {
+        // ffmpeg finalizes what it got once its input closes, the retry picks
+        // up from there.
+        let finalized = wait_cmd(
+            &mut ffmpeg_child,
+            done,
+            &AtomicBool::new(false),
+            poll_interval,
+        )
+        .await;
+        if let Err(e) = finalized {
+            tracing::debug!("ffmpeg didn't finalize the partial download: {e:#}");
+        }
+    }
+
+    // Whichever process failed, stalled or was aborted, the others are
+    // stopped too: a retry must not run next to them, and the copy only ends
+    // once both of its ends are closed. Those that exited are only reaped.
+    process::kill_and_wait(&mut yt_dlp_child).await;
+    process::kill_and_wait(&mut ffmpeg_child).await;
+    for (child, _) in &mut extra_children {
+        process::kill_and_wait(child).await;
+    }
+
+    let record = stats::DownloadRecord::new(
+        &run.run_id,
+        run.player_id.as_deref(),
+        status.bytes_written.load(Ordering::SeqCst),
+        started.elapsed(),
+    );
+    progress::println("\nDone!");
+    status.finish();
+
+    stalled.store(true, Ordering::SeqCst);
+    watchdog.thread().unpark();
+    watchdog
+        .join()
+        .map_err(|e| anyhow!("Watchdog error: {e:?}"))?;
+
+    let pipe_result = pipe_task.await.map_err(|e| anyhow!("I/O error: {e:?}"))?;
+    result?;
+    pipe_result?;
+
+    // The renderer ends once yt-dlp closes its stderr.
+    if progress.await.is_err() {
+        tracing::warn!("yt-dlp progress reporter panicked");
+    }
+
+    record.report();
+    stats::record_download(&record);
+
+    Ok(())
+}
+
+/// Everything `dlvod` does, from parsing the command line on.
+///
+/// Returns the exit code rather than exiting, so that what's still alive,
+/// like the control socket, is cleaned up on the way out.
+pub async fn run() -> Result<ExitCode> {
+    let mut cfg = Config::load()?;
+    COLORS.store(!cfg.no_color, Ordering::Relaxed);
+    let _ = EMBEDS.set(build_embed_list(&cfg));
+    tools::init(&cfg)?;
+    api::init(&cfg)?;
+    #[cfg(feature = "doh")]
+    doh::init(&cfg).await?;
+
+    let version_json = match cfg.command {
+        Some(config::Command::Version { json }) => Some(json),
+        Some(config::Command::Stats { show_speeds }) => {
+            return stats::print_history(show_speeds).map(|()| ExitCode::SUCCESS);
+        }
+        Some(config::Command::Du { ref output_dir }) => {
+            if cfg.filename_template != config::DEFAULT_FILENAME_TEMPLATE {
+                tracing::warn!(
+                    "du only reads games out of the default --filename-template, downloads named \
+                     otherwise are left out"
+                );
+            }
+            return du::print_usage(output_dir.as_ref().unwrap_or(&cfg.output_dir))
+                .map(|()| ExitCode::SUCCESS);
+        }
+        Some(config::Command::CheckUpdates) => {
+            return tools::check_updates(&http_client(&cfg)?)
+                .await
+                .map(|()| ExitCode::SUCCESS);
+        }
+        None if cfg.version => Some(cfg.json),
+        None => None,
+    };
+    if let Some(json) = version_json {
+        tools::print_versions(json);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // Before anything that logs, so the dependency checks are logged too.
+    let logging = logging::init(&cfg)?;
+    logging::install_panic_hook();
+
+    if !cfg.skip_dependency_check {
+        tools::check_dependency("ffmpeg", tools::MIN_FFMPEG_VERSION)?;
+        tools::check_dependency("yt-dlp", tools::MIN_YTDLP_VERSION)?;
+    }
+
+    if !cfg.skip_update_check {
+        tools::warn_if_ytdlp_outdated(&http_client(&cfg)?).await;
+    }
+
+    if let Some(url) = &cfg.file.ban_list_url {
+        let shared = moderation::load_ban_list(url, &http_client(&cfg)?).await;
+        cfg.file.banned_players.extend(shared);
+    }
+
+    let temp_dir = cfg.temp_dir();
+    fs::create_dir_all(&temp_dir)
+        .with_context(|| format!("Couldn't create temporary directory {}", temp_dir.display()))?;
+
+    if cfg.yt_format.is_some() {
+        tracing::warn!(
+            "Formats that merge separate video and audio streams may not stream well to ffmpeg; \
+             prefer single-stream formats"
+        );
+    }
+
+    if cfg.insecure {
+        tracing::warn!(
+            "--insecure: TLS certificates are NOT verified, connections can be intercepted"
+        );
+    }
+
+    if cfg.vf.is_some() && (cfg.fps.is_some() || cfg.resolution.is_some()) {
+        tracing::warn!("--vf replaces the built-in filters, --fps and --resolution are ignored");
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+
+    ctrlc::set_handler({
+        let done = Arc::clone(&done);
+        let control_socket = cfg.control_socket.clone();
+        let timeout = Duration::from_millis(cfg.ctrlc_timeout);
+        move || {
+            // The download notices this, cleans up and ends the session, which
+            // exits with 1 on its own. Exiting here is the fallback for
+            // whatever doesn't watch `done`, like prompts.
+            SHUTTING_DOWN.store(true, Ordering::SeqCst);
+            done.store(true, Ordering::SeqCst);
+            thread::sleep(timeout);
+
+            // exit() skips destructors, so the socket guard won't run.
+            #[cfg(unix)]
+            if let Some(path) = &control_socket {
+                control::remove_socket(path);
+            }
+
+            exit(1);
+        }
+    })?;
+
+    let terminated = Arc::new(AtomicBool::new(false));
+
+    #[cfg(unix)]
+    {
+        // SIGTERM asks for a clean shutdown: stop the current download through
+        // the same path as Ctrl+C, but without the forced exit.
+        signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&terminated))?;
+        signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&SHUTTING_DOWN))?;
+        signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&done))?;
+    }
+
+    let status = Arc::new(DownloadStatus::default());
+    spawn_status_reporter(Arc::clone(&status))?;
+
+    #[cfg(unix)]
+    let _control_socket = match &cfg.control_socket {
+        Some(path) => Some(control::start(
+            path,
+            Arc::clone(&status),
+            Arc::clone(&done),
+        )?),
+        None => None,
+    };
+
+    #[cfg(not(unix))]
+    if cfg.control_socket.is_some() {
+        bail!("--control-socket is only supported on Unix");
+    }
+
+    let partial_failure_code = cfg.exit_code_on_partial_failure;
+    let result = if cfg.server {
+        server::serve(cfg, status, Arc::clone(&done))
+            .await
+            .map(|()| Vec::new())
+    } else {
+        session(&cfg, &status, &done).await
+    };
+
+    stats::print_session_summary(&stats::SESSION_STATS);
+    if let Some(summary) = stats::SESSION_STATS.summary() {
+        logging.append_to_error_log(&summary);
+    }
+    logging.shutdown();
+
+    if terminated.load(Ordering::SeqCst) {
+        eprintln!("\nTerminated, shutting down.");
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let outcomes = result?;
+    let failed = outcomes.iter().filter(|outcome| outcome.is_err()).count();
+    if failed > 0 {
+        eprintln!("{failed} of {} downloads failed", outcomes.len());
+    }
+
+    Ok(ExitCode::from(batch_exit_code(
+        failed,
+        outcomes.len(),
+        partial_failure_code,
+    )))
+}
+
+/// Exit code of a batch where `failed` of `total` downloads failed: 2 when
+/// all of them did, `partial` when only some did.
+pub fn batch_exit_code(failed: usize, total: usize, partial: u8) -> u8 {
+    match failed {
+        0 => 0,
+        n if n == total => 2,
+        _ => partial,
+    }
+}
+
+/// Drops the runs excluded by the command line filters.
+pub fn apply_filters(runs: &mut Vec<Run>, cfg: &Config) {
+    if let Some(region) = &cfg.filter_region {
+        runs.retain(|run| {
+            run.region
+                .as_deref()
+                .is_some_and(|r| r.eq_ignore_ascii_case(region))
+        });
+    }
+
+    if let Some(player) = &cfg.player {
+        runs.retain(|run| run.players.iter().any(|p| p.eq_ignore_ascii_case(player)));
+    }
+
+    if let Some(category) = &cfg.category {
+        runs.retain(|run| run.cat_full.eq_ignore_ascii_case(category) || run.cat == slug(category));
+    }
+}
+
+/// Keeps the `max_per_game` oldest submissions of each game, sorted by
+/// submission date. Runs without a date sort last.
+pub fn balance_runs(runs: Vec<Run>, max_per_game: usize) -> Vec<Run> {
+    let by_date = |a: &Run, b: &Run| {
+        (a.submitted.is_none(), a.submitted).cmp(&(b.submitted.is_none(), b.submitted))
+    };
+
+    let mut games: HashMap<String, Vec<Run>> = HashMap::new();
+    for run in runs {
+        games.entry(run.game.clone()).or_default().push(run);
+    }
+
+    let mut balanced = games
+        .into_values()
+        .flat_map(|mut runs| {
+            runs.sort_by(by_date);
+            runs.truncate(max_per_game);
+            runs
+        })
+        .collect::<Vec<_>>();
+    balanced.sort_by(by_date);
+    balanced
+}
+
+/// Prompts for runs with dialoguer, for when there's no terminal for the TUI.
+fn select_runs(runs: &[Run], cfg: &Config) -> Result<Vec<usize>> {
+    let choices = runs.iter().map(|run| run.to_string()).collect::<Vec<_>>();
+    let selected = if cfg.batch {
+        interruptible_prompt(move || {
+            MultiSelect::new()
+                .with_prompt("Choose runs")
+                .items(&choices[..])
+                .interact_opt()
+        })?
+        .unwrap_or_default()
+    } else {
+        interruptible_prompt(move || {
+            Select::new()
+                .with_prompt("Choose a run")
+                .default(0)
+                .items(&choices[..])
+                .interact_opt()
+        })?
+        .into_iter()
+        .collect()
+    };
+
+    Ok(selected)
+}
+
+/// Picks which of a run's videos to download: the one at `--vod-index`, or
+/// the one chosen at a prompt. Without either, the first one is kept.
+fn choose_vod_source(run: &mut Run, cfg: &Config) -> Result<()> {
+    if run.vod_uris.len() < 2 {
+        return Ok(());
+    }
+
+    let index = match cfg.vod_index {
+        Some(index) if index < run.vod_uris.len() => index,
+        Some(index) => {
+            tracing::warn!(
+                "{} has no video {index}, only {}, downloading the first one",
+                run.run_id,
+                run.vod_uris.len()
+            );
+            0
+        }
+        None if io::stdin().is_terminal() => {
+            let prompt = format!("Choose video source for {}", run.run_id);
+            let uris = run.vod_uris.clone();
+            interruptible_prompt(move || {
+                Select::new()
+                    .with_prompt(prompt)
+                    .default(0)
+                    .items(&uris[..])
+                    .interact_opt()
+            })?
+            .unwrap_or(0)
+        }
+        None => 0,
+    };
+    run.vod_uri = run.vod_uris[index].clone();
+
+    Ok(())
+}
+
+/// Downloads the `selected` runs, up to `--parallel` at a time.
+///
+/// Each download reports to its own [`DownloadStatus`], so SIGUSR1 and the
+/// control socket don't see them.
+async fn download_parallel(
+    runs: &[Run],
+    selected: &[usize],
+    cfg: &Config,
+    done: &Arc<AtomicBool>,
+) -> Vec<(usize, Result<PathBuf>)> {
+    let limiter = parallel::Limiter::new(cfg);
+    let aggregator = progress::ProgressAggregator::start();
+    let cfg = Arc::new(cfg.clone());
+
+    let tasks = selected.iter().map(|&choice| {
+        let run = runs[choice].clone();
+        let limiter = &limiter;
+        let progress = aggregator.sender();
+        let cfg = Arc::clone(&cfg);
+        let done = Arc::clone(done);
+
+        async move {
+            let _permit = limiter.acquire().await;
+
+            // The download pipeline blocks on child processes, keep it off
+            // the runtime's worker threads.
+            let handle = tokio::runtime::Handle::current();
+            let result = tokio::task::spawn_blocking(move || {
+                let status = Arc::new(DownloadStatus {
+                    progress: Some(progress),
+                    ..Default::default()
+                });
+                handle.block_on(download_run(&run, &cfg, &status, &done))
+            })
+            .await
+            .context("Download task failed")
+            .and_then(|r| r);
+
+            (choice, result)
+        }
+    });
+
+    let results = futures::future::join_all(tasks).await;
+    aggregator.finish().await;
+    results
+}
+
+/// Reviews a finished download and adds it to `outcomes`, or reports why it
+/// failed. Failures only end the session outside of batches.
+async fn finish_download(
+    run: &Run,
+    result: Result<PathBuf>,
+    batch: bool,
+    cfg: &Config,
+    client: &reqwest::Client,
+    done: &Arc<AtomicBool>,
+    outcomes: &mut Vec<Result<(Run, PathBuf)>>,
+) -> Result<()> {
+    let result = match result.map_err(|e| e.downcast::<Skipped>()) {
+        // Already there, and reviewed back when it was downloaded.
+        Err(Ok(Skipped(path))) => {
+            outcomes.push(Ok((run.clone(), path)));
+            return Ok(());
+        }
+        Err(Err(e)) => Err(e),
+        Ok(path) => Ok(path),
+    };
+
+    match result {
+        Ok(mut path) => {
+            if let Some(api_key) = &cfg.api_key {
+                match moderation::review_run(run, &path, cfg, client, api_key).await {
+                    Ok(reviewed) => path = reviewed,
+                    Err(e) => tracing::error!("Couldn't review {}: {e:?}", run.run_id),
+                }
+            }
+            outcomes.push(Ok((run.clone(), path)));
+            Ok(())
+        }
+        // Already warned about, there's nothing to download.
+        Err(e) if e.is::<NoVod>() || e.is::<DryRun>() => Ok(()),
+        Err(e) if batch && !done.load(Ordering::SeqCst) => {
+            tracing::error!("Couldn't download {}: {e:?}", run.run_id);
+            outcomes.push(Err(e));
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Runs an interactive or `--run-id` session, returning the outcome of every
+/// download that didn't end it.
+async fn session(
+    cfg: &Config,
+    status: &Arc<DownloadStatus>,
+    done: &Arc<AtomicBool>,
+) -> Result<Vec<Result<()>>> {
+    let client = api_client(cfg).await?;
+
+    if !cfg.run_ids.is_empty() {
+        return download_run_ids(cfg, &client, status, done).await;
+    }
+
+    if let (Some(player), Some(reason)) = (&cfg.reject_player, &cfg.rejection_reason) {
+        let api_key = cfg
+            .api_key
+            .as_deref()
+            .context("--reject-player requires an API key")?;
+        if !cfg.yes {
+            bail!("Refusing to reject the runs of {player} without --yes");
+        }
+        moderation::reject_runs_by_player(player, reason, &cfg.file.games, &client, api_key)
+            .await?;
+        return Ok(Vec::new());
+    }
+
+    let category = CategoryFilter::from_config(cfg);
+    let mut runs = get_all_pending_runs(&cfg.file.games, category.as_ref(), &client).await?;
+    stats::SESSION_STATS
+        .fetched
+        .store(runs.len(), Ordering::Relaxed);
+
+    apply_filters(&mut runs, cfg);
+    if let Some(max) = cfg.max_per_game {
+        runs = balance_runs(runs, max);
+    }
+    mark_wr_candidates(&mut runs, &client).await;
+    moderation::mark_banned(&mut runs, &cfg.file.banned_players);
+    moderation::auto_reject_banned(&mut runs, cfg, &client).await?;
+
+    if let Some(percent) = cfg.file.auto_verify_within_wr_percent {
+        moderation::auto_verify(&mut runs, percent, cfg, &client).await?;
+    }
+
+    if let Some(action) = cfg.bulk_action {
+        let api_key = cfg
+            .api_key
+            .as_deref()
+            .context("--bulk-action requires an API key")?;
+        moderation::bulk_action(&runs, action, cfg, &client, api_key).await?;
+        return Ok(Vec::new());
+    }
+
+    let selected = if io::stdin().is_terminal() && io::stdout().is_terminal() {
+        tui::run_tui(&runs, &client, done)?
+    } else {
+        select_runs(&runs, cfg)?
+    };
+    for &choice in &selected {
+        choose_vod_source(&mut runs[choice], cfg)?;
+    }
+
+    // Keep going past failures whenever more than one run was picked.
+    let batch = cfg.batch || selected.len() > 1;
+
+    let mut outcomes = Vec::new();
+    if cfg.max_parallel() > 1 && selected.len() > 1 {
+        // Reviews wait until everything is downloaded, prompts can't overlap.
+        for (choice, result) in download_parallel(&runs, &selected, cfg, done).await {
+            let run = &runs[choice];
+            finish_download(run, result, batch, cfg, &client, done, &mut outcomes).await?;
+        }
+    } else {
+        for choice in selected {
+            let run = &runs[choice];
+            let result = download_run(run, cfg, status, done).await;
+            finish_download(run, result, batch, cfg, &client, done, &mut outcomes).await?;
+        }
+    }
+
+    if let Some(playlist) = &cfg.playlist {
+        let downloads = outcomes
+            .iter()
+            .filter_map(|outcome| outcome.as_ref().ok().cloned())
+            .collect::<Vec<_>>();
+        write_m3u8_playlist(&downloads, playlist, cfg.playlist_absolute)?;
+    }
+
+    Ok(outcomes
+        .into_iter()
+        .map(|outcome| outcome.map(drop))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn fixture(player: &str) -> Run {
+        Run::try_from(&fixture_json(player)).unwrap()
+    }
+
+    fn fixture_json(player: &str) -> Value {
+        json!({
+            "id": "y8dwe0lz",
+            "weblink": "https://www.speedrun.com/sm64/run/y8dwe0lz",
+            "videos": { "links": [{ "uri": "https://www.twitch.tv/videos/123" }] },
+            "players": { "data": [{ "id": "p1", "names": { "international": player } }] },
+            "game": {
+                "data": {
+                    "id": "o1y9wo6q",
+                    "abbreviation": "sm64",
+                    "names": { "twitch": "Super Mario 64" }
+                }
+            },
+            "category": { "data": { "id": "wkpoo02r", "name": "120 Star" } },
+            "times": { "primary": "PT1H39M12S" },
+            "submitted": "2024-01-02T03:04:05Z"
+        })
+    }
+
+    /// A run of `game` submitted on `date`, e.g. `2024-01-02`.
+    fn submitted(game: &str, date: &str, id: &str) -> Run {
+        let mut run = fixture("Someone");
+        run.game = game.to_string();
+        run.run_id = id.to_string();
+        run.submitted = Some(format!("{date}T00:00:00Z").parse().unwrap());
+        run
+    }
+
+    fn config(args: &[&str]) -> Config {
+        use clap::Parser;
+        Config::parse_from(std::iter::once("dlvod").chain(args.iter().copied()))
+    }
+
+    fn ids(runs: &[Run]) -> Vec<&str> {
+        runs.iter().map(|run| run.run_id.as_str()).collect()
+    }
+
+    #[test]
+    fn slugs_keep_lowercase_letters_and_digits() {
+        assert_eq!(slug("120 Star"), "120star");
+        assert_eq!(slug("Any% (No BLJs)"), "anynobljs");
+        assert_eq!(slug("Ünïcödé"), "ncd");
+        assert_eq!(slug(""), "");
+    }
+
+    #[test]
+    fn formats_times() {
+        assert_eq!(format_time(Duration::from_secs(0)), "00:00:00");
+        assert_eq!(format_time(Duration::from_secs(5952)), "01:39:12");
+        assert_eq!(
+            format_time(Duration::from_millis(5_952_500)),
+            "01:39:12.500"
+        );
+        assert_eq!(format_time(Duration::from_secs(100 * 3600)), "100:00:00");
+    }
+
+    #[test]
+    fn parses_run_times() {
+        assert_eq!(
+            parse_run_time("PT1H23M45.67S").unwrap(),
+            Duration::from_millis(5_025_670)
+        );
+        assert_eq!(
+            parse_run_time("PT2H").unwrap(),
+            Duration::from_secs(2 * 3600)
+        );
+        assert_eq!(parse_run_time("PT45S").unwrap(), Duration::from_secs(45));
+        assert_eq!(
+            parse_run_time("PT12M0.5S").unwrap(),
+            Duration::from_millis(720_500)
+        );
+        assert_eq!(
+            parse_run_time("P1DT1H").unwrap(),
+            Duration::from_secs(25 * 3600)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_run_times() {
+        for time in ["", "1:23:45", "PT", "PTxS", "P1M", "PT1H23M45.67"] {
+            assert!(parse_run_time(time).is_err(), "{time:?}");
+        }
+    }
+
+    #[test]
+    fn reads_bilibili_cookies_from_the_browser() {
+        let cfg = config(&[]);
+        for url in [
+            "https://www.bilibili.com/video/BV1xx411c7mD",
+            "https://bilibili.com/video/BV1xx411c7mD",
+            "https://m.bilibili.com/video/BV1xx411c7mD",
+            "https://b23.tv/abcdef",
+        ] {
+            let mut run = fixture("Someone");
+            run.vod_uri = url.to_string();
+            let args = sidecar::extract_args(&ytdlp_command(&run, &cfg));
+            let at = args.iter().position(|arg| arg == "--cookies-from-browser");
+            assert_eq!(at.map(|at| args[at + 1].as_str()), Some("chrome"), "{url}");
+        }
+
+        let run = fixture("Someone");
+        let args = sidecar::extract_args(&ytdlp_command(&run, &cfg));
+        assert!(!args.iter().any(|arg| arg == "--cookies-from-browser"));
+    }
+
+    #[test]
+    fn fills_in_filename_templates() {
+        let mut run = fixture("Someone");
+        run.platform = Some("Nintendo 64".to_string());
+        run.variables = vec![
+            ("Version".to_string(), "N64".to_string()),
+            ("Glitches".to_string(), "No BLJ".to_string()),
+        ];
+
+        assert_eq!(run.filename(TEMPLATE), "Someone-sm64-120star-y8dwe0lz");
+        assert_eq!(
+            run.filename("{game}_{platform}_{variables}"),
+            "sm64_nintendo64_n64-noblj"
+        );
+        assert_eq!(run.filename("{run_id}.{unknown}"), "y8dwe0lzunknown");
+
+        run.platform = None;
+        run.variables.clear();
+        assert_eq!(
+            run.filename("{player}-{platform}-{variables}"),
+            "Someone-unknown"
+        );
+    }
+
+    #[test]
+    fn reads_run_fixtures() {
+        let run = fixture("Someone");
+        assert_eq!(run.run_id, "y8dwe0lz");
+        assert_eq!(run.player, "Someone");
+        assert_eq!(run.players, ["Someone"]);
+        assert_eq!(run.player_id.as_deref(), Some("p1"));
+        assert_eq!(run.game, "sm64");
+        assert_eq!(run.cat, "120star");
+        assert_eq!(run.cat_full, "120 Star");
+        assert_eq!(run.duration, Duration::from_secs(5952));
+        assert_eq!(run.vod_uris, ["https://www.twitch.tv/videos/123"]);
+        assert_eq!(run.platform, None);
+        assert_eq!(run.notes, None);
+        assert!(!run.would_be_wr && !run.banned && !run.vod_missing);
+    }
+
+    #[test]
+    fn runs_without_videos_are_flagged() {
+        let mut value = fixture_json("Someone");
+        value["videos"] = Value::Null;
+        let run = Run::try_from(&value).unwrap();
+        assert!(run.vod_missing);
+        assert_eq!(run.vod_uri, "");
+        assert!(run.to_string().contains("[NO VOD]"));
+    }
+
+    #[test]
+    fn keeps_every_video_normalized() {
+        let mut value = fixture_json("Someone");
+        value["videos"]["links"] = json!([
+            { "uri": "https://m.twitch.tv/someone/v/123?t=1h" },
+            { "uri": "https://youtu.be/abc" },
+        ]);
+        let run = Run::try_from(&value).unwrap();
+        assert_eq!(run.vod_uri, "https://www.twitch.tv/videos/123");
+        assert_eq!(
+            run.vod_uris,
+            ["https://www.twitch.tv/videos/123", "https://youtu.be/abc"]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_a_built_permalink() {
+        let mut value = fixture_json("Someone");
+        value.as_object_mut().unwrap().remove("weblink");
+        value["comment"] = json!("   ");
+        let run = Run::try_from(&value).unwrap();
+        assert_eq!(run.permalink, "https://www.speedrun.com/sm64/run/y8dwe0lz");
+        assert_eq!(run.notes, None);
+    }
+
+    #[test]
+    fn rejects_incomplete_runs() {
+        for field in ["id", "players", "game", "category", "times"] {
+            let mut value = fixture_json("Someone");
+            value.as_object_mut().unwrap().remove(field);
+            assert!(Run::try_from(&value).is_err(), "parsed without {field}");
+        }
+
+        let mut value = fixture_json("Someone");
+        value["times"]["primary"] = json!("an hour");
+        assert!(Run::try_from(&value).is_err());
+    }
+
+    #[test]
+    fn namesakes_fall_back_to_the_first_player() {
+        let mut value = fixture_json("Someone");
+        value["players"]["data"] = json!([
+            { "id": "unknown1", "names": { "international": "Someone" } },
+            { "id": "unknown2", "names": { "international": "someone" } },
+        ]);
+        let run = Run::try_from(&value).unwrap();
+        assert_eq!(run.player_id.as_deref(), Some("unknown1"));
+        assert_eq!(run.players, ["Someone", "someone"]);
+    }
+
+    #[test]
+    fn filters_runs() {
+        let mut jpn = fixture("Someone");
+        jpn.run_id = "jpn".to_string();
+        jpn.region = Some("JPN / NTSC".to_string());
+        let mut coop = fixture("Other");
+        coop.run_id = "coop".to_string();
+        coop.players.push("Partner".to_string());
+        let mut any = fixture("Third");
+        any.run_id = "any".to_string();
+        any.cat = "any".to_string();
+        any.cat_full = "Any%".to_string();
+        let runs = vec![jpn, coop, any];
+
+        let filtered = |args: &[&str]| {
+            let mut runs = runs.clone();
+            apply_filters(&mut runs, &config(args));
+            ids(&runs).into_iter().map(String::from).collect::<Vec<_>>()
+        };
+
+        assert_eq!(filtered(&[]), ["jpn", "coop", "any"]);
+        assert_eq!(filtered(&["--filter-region", "jpn / ntsc"]), ["jpn"]);
+        assert_eq!(filtered(&["--player", "partner"]), ["coop"]);
+        assert_eq!(filtered(&["--category", "ANY%"]), ["any"]);
+        assert_eq!(filtered(&["--category", "120 star"]), ["jpn", "coop"]);
+        assert!(filtered(&["--player", "nobody"]).is_empty());
+    }
+
+    #[test]
+    fn keeps_runs_submitted_since_last_seen() {
+        let mut undated = submitted("sm64", "2024-01-01", "undated");
+        undated.submitted = None;
+        let runs = vec![
+            submitted("sm64", "2024-01-01", "old"),
+            submitted("sm64", "2024-02-01", "last-seen"),
+            submitted("sm64", "2024-03-01", "new"),
+            undated,
+        ];
+
+        assert_eq!(ids(&incremental_filter(runs.clone(), &None)).len(), 4);
+
+        let last_seen = latest_submission(&runs[..2]);
+        assert_eq!(
+            ids(&incremental_filter(runs.clone(), &last_seen)),
+            ["last-seen", "new", "undated"]
+        );
+        assert_eq!(latest_submission(&runs), runs[2].submitted);
+        assert_eq!(latest_submission(&runs[3..]), None);
+    }
+
+    #[test]
+    fn balances_runs_across_games() {
+        let runs = vec![
+            submitted("sm64", "2024-03-01", "sm64-3"),
+            submitted("sm64", "2024-01-01", "sm64-1"),
+            submitted("oot", "2024-02-15", "oot-1"),
+            submitted("sm64", "2024-02-01", "sm64-2"),
+            submitted("oot", "2024-04-01", "oot-2"),
+        ];
+
+        assert_eq!(
+            ids(&balance_runs(runs.clone(), 2)),
+            ["sm64-1", "sm64-2", "oot-1", "oot-2"]
+        );
+        assert_eq!(ids(&balance_runs(runs.clone(), 1)), ["sm64-1", "oot-1"]);
+        assert_eq!(ids(&balance_runs(runs, 10)).len(), 5);
+    }
+
+    #[test]
+    fn undated_runs_sort_last() {
+        let mut undated = submitted("sm64", "2024-01-01", "undated");
+        undated.submitted = None;
+        let runs = vec![undated, submitted("sm64", "2024-05-01", "dated")];
+
+        assert_eq!(ids(&balance_runs(runs.clone(), 2)), ["dated", "undated"]);
+        assert_eq!(ids(&balance_runs(runs, 1)), ["dated"]);
+    }
+
+    #[test]
+    fn batch_exit_codes() {
+        assert_eq!(batch_exit_code(0, 3, 1), 0);
+        assert_eq!(batch_exit_code(1, 3, 1), 1);
+        assert_eq!(batch_exit_code(1, 3, 4), 4);
+        assert_eq!(batch_exit_code(3, 3, 4), 2);
+    }
+
+    #[test]
+    fn embeds_only_whats_used() {
+        assert_eq!(
+            build_embed_list(&config(&[])),
+            "players,game,category.variables,platform,region"
+        );
+        assert_eq!(
+            build_embed_list(&config(&["--brief"])),
+            "players,game,category.variables"
+        );
+        assert_eq!(
+            build_embed_list(&config(&["--brief", "--filter-region", "PAL"])),
+            "players,game,category.variables,region"
+        );
+        assert_eq!(
+            build_embed_list(&config(&[
+                "--brief",
+                "--filename-template",
+                "{platform}-{variables}"
+            ])),
+            "players,game,category.variables,platform"
+        );
+    }
+
+    #[test]
+    fn prompts_give_up_on_shutdown() {
+        let shutting_down = AtomicBool::new(false);
+        assert_eq!(prompt_until(&shutting_down, || Ok(3)).unwrap(), 3);
+
+        // A prompt nobody answers, until the test is over.
+        let (answer, wait) = mpsc::channel::<()>();
+        shutting_down.store(true, Ordering::SeqCst);
+        let started = Instant::now();
+        let unanswered = prompt_until(&shutting_down, move || {
+            let _ = wait.recv();
+            Ok(())
+        });
+        drop(answer);
+        assert!(unanswered.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn resolves_collisions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.mp4");
+        assert_eq!(
+            resolve_collision(&path, CollisionStrategy::Skip).unwrap(),
+            path
+        );
+
+        fs::write(&path, "").unwrap();
+        fs::write(dir.path().join("run_1.mp4"), "").unwrap();
+        let skipped = resolve_collision(&path, CollisionStrategy::Skip).unwrap_err();
+        assert!(skipped.is::<Skipped>());
+        assert_eq!(
+            resolve_collision(&path, CollisionStrategy::Overwrite).unwrap(),
+            path
+        );
+        assert_eq!(
+            resolve_collision(&path, CollisionStrategy::Rename).unwrap(),
+            dir.path().join("run_2.mp4")
+        );
+    }
+
+    #[test]
+    fn extra_outputs_respect_collisions() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("run.mp4");
+        let formats =
+            ["mp4", "mkv", "webm:copy"].map(|format| encode::parse_output_format(format).unwrap());
+        fs::write(dir.path().join("run.mkv"), "").unwrap();
+
+        let paths = |strategy| {
+            extra_output_paths(&output, &formats, strategy)
+                .unwrap()
+                .into_iter()
+                .map(|(_, path)| path.file_name().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths(CollisionStrategy::Skip), ["run.webm"]);
+        assert_eq!(paths(CollisionStrategy::Rename), ["run_1.mkv", "run.webm"]);
+        assert_eq!(paths(CollisionStrategy::Overwrite), ["run.mkv", "run.webm"]);
+    }
+
+    const TEMPLATE: &str = "{player}-{game}-{cat}-{run_id}";
+
+    #[test]
+    fn sanitizes_filenames() {
+        assert_eq!(
+            sanitize_filename("Some One-sm64-120star"),
+            "SomeOne-sm64-120star"
+        );
+        assert_eq!(sanitize_filename("a--b__c-_d"), "a-b_c-d");
+        assert_eq!(sanitize_filename("-a-"), "a");
+        assert_eq!(sanitize_filename("../../etc/passwd"), "etcpasswd");
+        assert_eq!(sanitize_filename("C:\\x<y>z|?*\""), "Cxyz");
+        assert_eq!(sanitize_filename("日本語"), "");
+        assert_eq!(sanitize_filename("CON"), "CON_");
+        assert_eq!(sanitize_filename("lpt1"), "lpt1_");
+        assert_eq!(sanitize_filename("com10"), "com10");
+    }
+
+    #[test]
+    fn caps_the_filename_length() {
+        let long = "a".repeat(1000);
+        assert_eq!(sanitize_filename(&long).len(), MAX_FILENAME_LEN);
+
+        // No separator left dangling where the cut falls.
+        let long = format!("{}-{}", "a".repeat(MAX_FILENAME_LEN - 1), "b".repeat(10));
+        assert_eq!(sanitize_filename(&long), "a".repeat(MAX_FILENAME_LEN - 1));
+    }
+
+    #[test]
+    fn filenames_fall_back_to_the_run_id() {
+        let run = fixture("日本語の名前");
+        assert_eq!(run.filename("{player}"), "y8dwe0lz");
+        assert_eq!(run.filename(TEMPLATE), "sm64-120star-y8dwe0lz");
+        assert_eq!(fixture("🎮").filename("{player}{platform}"), "unknown");
+    }
+
+    #[test]
+    fn long_player_names_are_cut() {
+        let mut run = fixture(&"x".repeat(500));
+        let filename = run.filename(TEMPLATE);
+        assert_eq!(
+            filename,
+            format!("{}-sm64-120star-y8dwe0lz", "x".repeat(MAX_PLACEHOLDER_LEN))
+        );
+
+        // Other runs by the same player don't get the same file.
+        run.run_id = "zpqoxkny".to_string();
+        assert_ne!(run.filename(TEMPLATE), filename);
+        assert!(run.filename(TEMPLATE).ends_with("-zpqoxkny"));
+    }
+
+    #[test]
+    fn part_files_keep_their_muxer() {
+        let part = part_path(Path::new("out/run.mp4"));
+        assert_eq!(part, Path::new("out/run.mp4.part"));
+        assert_eq!(encode::part_muxer_args(&part), ["-f", "mp4"]);
+        assert_eq!(
+            encode::part_muxer_args(&part_path(Path::new("run.mkv"))),
+            ["-f", "matroska"]
+        );
+        assert!(encode::part_muxer_args(Path::new("run.mp4")).is_empty());
+    }
+
+    #[test]
+    fn atomic_rename_replaces_the_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("run.mp4");
+        let part = part_path(&target);
+        fs::write(&target, "old").unwrap();
+        fs::write(&part, "new").unwrap();
+
+        atomic_rename(&part, &target).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+        assert!(!part.exists());
+    }
+
+    #[test]
+    fn only_extracts_frames_at_positive_rates() {
+        use clap::Parser;
+
+        let parse = |fps| Config::try_parse_from(["dlvod", "--output-images", "--fps-images", fps]);
+        assert_eq!(parse("0.5").unwrap().fps_images, 0.5);
+        for fps in ["0", "-1", "inf", "NaN", "ten"] {
+            assert!(parse(fps).is_err(), "{fps}");
+        }
+    }
+}
@@ -0,0 +1,267 @@
+//! Tracing setup: stderr output filtered by `RUST_LOG`, plus `--error-log`
+//! and, with the `telemetry` feature, `--otlp-endpoint`.
+
+use std::{
+    backtrace::{Backtrace, BacktraceStatus},
+    fmt,
+    fs::OpenOptions,
+    io::Write,
+    panic,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{
+    filter::{EnvFilter, LevelFilter},
+    fmt::{
+        self as fmt_layer,
+        format::{DefaultFields, Format, Full, Writer},
+        time::{FormatTime, SystemTime, Uptime},
+        MakeWriter,
+    },
+    layer::{self, SubscriberExt},
+    util::SubscriberInitExt,
+    Layer, Registry,
+};
+
+use crate::{config::Config, progress};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// One human-readable line per event.
+    Text,
+    /// One JSON object per event.
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TimestampFormat {
+    /// Wall-clock time, e.g. `2024-05-01T12:34:56.789012Z`.
+    Rfc3339,
+    /// Seconds since the Unix epoch.
+    Unix,
+    /// Seconds since dlvod started.
+    Relative,
+    /// No timestamp at all.
+    None,
+}
+
+/// Prints seconds since the Unix epoch, with microseconds.
+struct UnixTime;
+
+impl FormatTime for UnixTime {
+    fn format_time(&self, w: &mut Writer<'_>) -> fmt::Result {
+        let now = chrono::Utc::now();
+        write!(
+            w,
+            "{}.{:06}",
+            now.timestamp(),
+            now.timestamp_subsec_micros()
+        )
+    }
+}
+
+/// The `--error-log` file, and how many events went into it.
+pub struct ErrorLog {
+    path: PathBuf,
+    count: Arc<AtomicUsize>,
+}
+
+impl ErrorLog {
+    /// Tells the user where to look, if anything was logged.
+    pub fn report(&self) {
+        let count = self.count.load(Ordering::SeqCst);
+        if count > 0 {
+            eprintln!("{count} errors logged to {}", self.path.display());
+        }
+    }
+}
+
+/// Outputs that need attention when the program ends.
+pub struct Logging {
+    error_log: Option<ErrorLog>,
+    #[cfg(feature = "telemetry")]
+    tracer_provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Logging {
+    /// Adds `text` to the error log as-is, if there is one.
+    pub fn append_to_error_log(&self, text: &str) {
+        let Some(error_log) = &self.error_log else {
+            return;
+        };
+
+        let appended = OpenOptions::new()
+            .append(true)
+            .open(&error_log.path)
+            .and_then(|mut file| file.write_all(text.as_bytes()));
+        if let Err(e) = appended {
+            eprintln!("Couldn't write to {}: {e}", error_log.path.display());
+        }
+    }
+
+    /// Reports the error log and flushes pending spans.
+    pub fn shutdown(&self) {
+        if let Some(error_log) = &self.error_log {
+            error_log.report();
+        }
+
+        #[cfg(feature = "telemetry")]
+        if let Some(provider) = &self.tracer_provider {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Couldn't flush spans: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(feature = "telemetry")]
+fn otlp_layer(endpoint: &str) -> Result<(BoxedLayer, opentelemetry_sdk::trace::SdkTracerProvider)> {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::WithExportConfig;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .context("Couldn't create the OTLP exporter")?;
+
+    let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            opentelemetry_sdk::Resource::builder()
+                .with_service_name(env!("CARGO_PKG_NAME"))
+                .build(),
+        )
+        .build();
+
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+        .with_filter(LevelFilter::INFO)
+        .boxed();
+
+    Ok((layer, provider))
+}
+
+/// Counts the events that reach the error log.
+struct Counter(Arc<AtomicUsize>);
+
+impl<S: Subscriber> Layer<S> for Counter {
+    fn on_event(&self, _: &Event<'_>, _: layer::Context<'_, S>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+fn format_layer<W>(writer: W, cfg: &Config, ansi: bool) -> BoxedLayer
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+
+    match cfg.log_timestamp_format {
+        TimestampFormat::Rfc3339 => with_format(layer.with_timer(SystemTime), cfg.log_format),
+        TimestampFormat::Unix => with_format(layer.with_timer(UnixTime), cfg.log_format),
+        TimestampFormat::Relative => {
+            with_format(layer.with_timer(Uptime::default()), cfg.log_format)
+        }
+        TimestampFormat::None => with_format(layer.without_time(), cfg.log_format),
+    }
+}
+
+fn with_format<W, T>(
+    layer: fmt_layer::Layer<Registry, DefaultFields, Format<Full, T>, W>,
+    format: LogFormat,
+) -> BoxedLayer
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+    T: FormatTime + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    }
+}
+
+/// Installs the global subscriber. Warnings and errors also go to
+/// `--error-log`, whatever `RUST_LOG` says.
+pub fn init(cfg: &Config) -> Result<Logging> {
+    let stderr_filter = EnvFilter::builder()
+        .with_default_directive(if cfg.verbose {
+            LevelFilter::DEBUG.into()
+        } else {
+            LevelFilter::WARN.into()
+        })
+        .from_env_lossy();
+
+    let mut layers = vec![format_layer(progress::stderr, cfg, !cfg.no_color)
+        .with_filter(stderr_filter)
+        .boxed()];
+
+    let error_log = match &cfg.error_log {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Couldn't open error log {}", path.display()))?;
+            let count = Arc::new(AtomicUsize::new(0));
+
+            layers.push(
+                format_layer(Mutex::new(file), cfg, false)
+                    .and_then(Counter(Arc::clone(&count)))
+                    .with_filter(LevelFilter::WARN)
+                    .boxed(),
+            );
+
+            Some(ErrorLog {
+                path: path.clone(),
+                count,
+            })
+        }
+        None => None,
+    };
+
+    #[cfg(feature = "telemetry")]
+    let tracer_provider = match &cfg.otlp_endpoint {
+        Some(endpoint) => {
+            let (layer, provider) = otlp_layer(endpoint)?;
+            layers.push(layer);
+            Some(provider)
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry().with(layers).init();
+
+    Ok(Logging {
+        error_log,
+        #[cfg(feature = "telemetry")]
+        tracer_provider,
+    })
+}
+
+/// Reports panics through tracing, so they reach `--error-log` too, instead
+/// of only printing them to stderr.
+pub fn install_panic_hook() {
+    panic::set_hook(Box::new(|info| {
+        let thread = std::thread::current();
+        let thread = thread.name().unwrap_or("<unnamed>");
+        let backtrace = Backtrace::capture();
+
+        if backtrace.status() == BacktraceStatus::Captured {
+            tracing::error!("Thread '{thread}' panicked: {info}\n{backtrace}");
+        } else {
+            tracing::error!("Thread '{thread}' panicked: {info}");
+        }
+    }));
+}
@@ -0,0 +1,594 @@
+//! Verifying and rejecting runs through the speedrun.com API.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use dialoguer::{Confirm, Input, Select};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    api::{api_url, RequestExt, ResponseExt},
+    config::{BulkAction, Config},
+    get_all_pending_runs, interruptible_prompt, paint, Run,
+};
+
+async fn set_run_status(
+    run_id: &str,
+    status: Value,
+    client: &reqwest::Client,
+    api_key: &str,
+) -> Result<()> {
+    let response = client
+        .put(api_url(&format!("runs/{run_id}/status")))
+        .header("X-API-Key", api_key)
+        .json(&json!({ "status": status }))
+        .send_with_retry()
+        .await
+        .context("Requesting run status change")?;
+
+    if !response.status().is_success() {
+        let code = response.status();
+        let body: Value = response.json_limited().await.unwrap_or_default();
+        let message = body["message"].as_str().unwrap_or("no details");
+        bail!("Couldn't change the status of run {run_id}: {code} ({message})");
+    }
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(client, api_key))]
+pub async fn verify_run(run_id: &str, client: &reqwest::Client, api_key: &str) -> Result<()> {
+    set_run_status(run_id, json!({ "status": "verified" }), client, api_key).await
+}
+
+pub async fn reject_run(
+    run_id: &str,
+    reason: &str,
+    client: &reqwest::Client,
+    api_key: &str,
+) -> Result<()> {
+    set_run_status(
+        run_id,
+        json!({ "status": "rejected", "reason": reason }),
+        client,
+        api_key,
+    )
+    .await
+}
+
+/// Prompts for a rejection reason among `templates`, or a custom one.
+pub fn select_rejection_reason(templates: &HashMap<String, String>) -> Result<String> {
+    let mut keys = templates.keys().collect::<Vec<_>>();
+    keys.sort();
+
+    let mut choices = keys
+        .iter()
+        .map(|key| format!("{key}: {}", templates[*key]))
+        .collect::<Vec<_>>();
+    choices.push("Custom...".to_string());
+
+    let choice = interruptible_prompt(move || {
+        Select::new()
+            .with_prompt("Rejection reason")
+            .default(0)
+            .items(&choices[..])
+            .interact()
+    })?;
+
+    match keys.get(choice) {
+        Some(key) => Ok(templates[*key].clone()),
+        None => interruptible_prompt(|| Input::new().with_prompt("Reason").interact_text()),
+    }
+}
+
+/// Resolves the rejection reason from `--rejection-key`, or prompts for one.
+pub fn rejection_reason(cfg: &Config) -> Result<String> {
+    let templates = &cfg.file.rejection_templates;
+
+    match &cfg.rejection_key {
+        Some(key) => templates
+            .get(key)
+            .cloned()
+            .with_context(|| format!("No rejection template named {key:?}")),
+        None => select_rejection_reason(templates),
+    }
+}
+
+/// Renames a reviewed download to `<stem>_<action>_<timestamp>.<ext>`, adding
+/// a numeric suffix if that name is taken.
+pub fn rename_after_verification(
+    original: &Path,
+    action: &str,
+    timestamp: &str,
+) -> Result<PathBuf> {
+    let stem = original
+        .file_stem()
+        .context("Download has no file name")?
+        .to_string_lossy();
+    let ext = original
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+
+    let mut target = original.with_file_name(format!("{stem}_{action}_{timestamp}{ext}"));
+    let mut n = 1;
+    while target.exists() {
+        target = original.with_file_name(format!("{stem}_{action}_{timestamp}_{n}{ext}"));
+        n += 1;
+    }
+
+    fs::rename(original, &target).with_context(|| {
+        format!(
+            "Couldn't rename {} to {}",
+            original.display(),
+            target.display()
+        )
+    })?;
+
+    Ok(target)
+}
+
+/// Asks whether to verify or reject a downloaded run, and applies the decision.
+///
+/// Returns the path of the download, which changes with `--rename-reviewed`.
+pub async fn review_run(
+    run: &Run,
+    path: &Path,
+    cfg: &Config,
+    client: &reqwest::Client,
+    api_key: &str,
+) -> Result<PathBuf> {
+    let prompt = format!("Review {run}");
+    let choice = interruptible_prompt(move || {
+        Select::new()
+            .with_prompt(prompt)
+            .default(0)
+            .items(&["Leave pending", "Verify", "Reject"])
+            .interact_opt()
+    })?;
+
+    let action = match choice {
+        Some(1) => {
+            verify_run(&run.run_id, client, api_key).await?;
+            println!("Verified {}", run.run_id);
+            "verified"
+        }
+        Some(2) => {
+            let reason = rejection_reason(cfg)?;
+            reject_run(&run.run_id, &reason, client, api_key).await?;
+            println!("Rejected {}", run.run_id);
+            "rejected"
+        }
+        _ => return Ok(path.to_path_buf()),
+    };
+
+    if !cfg.rename_reviewed {
+        return Ok(path.to_path_buf());
+    }
+
+    let timestamp = chrono::Local::now().format("%Y-%m-%d").to_string();
+    let renamed = rename_after_verification(path, action, &timestamp)?;
+    println!("Renamed to {}", renamed.display());
+
+    Ok(renamed)
+}
+
+/// Applies `action` to every run in `runs` with a video, reporting failures
+/// at the end.
+pub async fn bulk_action(
+    runs: &[Run],
+    action: BulkAction,
+    cfg: &Config,
+    client: &reqwest::Client,
+    api_key: &str,
+) -> Result<()> {
+    let (missing, runs): (Vec<_>, Vec<_>) = runs.iter().partition(|run| run.vod_missing);
+    if !missing.is_empty() {
+        tracing::warn!(
+            "Leaving {} runs without a video pending, they need a look first",
+            missing.len()
+        );
+    }
+
+    if runs.is_empty() {
+        println!("No runs match the filters.");
+        return Ok(());
+    }
+
+    for run in &runs {
+        println!("  {run}");
+    }
+
+    if !cfg.yes {
+        bail!("Refusing to {action} {} runs without --yes", runs.len());
+    }
+
+    let reason = match action {
+        BulkAction::Verify => None,
+        BulkAction::Reject => match &cfg.bulk_reason {
+            Some(reason) => Some(reason.clone()),
+            None => Some(rejection_reason(cfg)?),
+        },
+    };
+
+    let mut errors = Vec::new();
+    for run in &runs {
+        let result = match &reason {
+            None => verify_run(&run.run_id, client, api_key).await,
+            Some(reason) => reject_run(&run.run_id, reason, client, api_key).await,
+        };
+
+        match result {
+            Ok(()) => println!("{action}: {}", run.run_id),
+            Err(e) => errors.push((run.run_id.clone(), e)),
+        }
+    }
+
+    println!(
+        "\n{} of {} runs processed ({action}).",
+        runs.len() - errors.len(),
+        runs.len()
+    );
+
+    if !errors.is_empty() {
+        for (run_id, e) in &errors {
+            eprintln!("  {run_id}: {e:#}");
+        }
+        bail!("{} runs failed", errors.len());
+    }
+
+    Ok(())
+}
+
+/// Rejects every pending run of `player` in `game_ids`, once they're listed
+/// and, at a terminal, confirmed. Returns the IDs of the rejected runs.
+/// The runs `player` is in, like `--player` lists them: co-op runs count
+/// whoever is listed first.
+fn runs_by_player(mut runs: Vec<Run>, player: &str) -> Vec<Run> {
+    runs.retain(|run| run.players.iter().any(|p| p.eq_ignore_ascii_case(player)));
+    runs
+}
+
+pub async fn reject_runs_by_player(
+    player: &str,
+    reason: &str,
+    game_ids: &[String],
+    client: &reqwest::Client,
+    api_key: &str,
+) -> Result<Vec<String>> {
+    let runs = runs_by_player(get_all_pending_runs(game_ids, None, client).await?, player);
+    if runs.is_empty() {
+        println!("{player} has no pending runs.");
+        return Ok(Vec::new());
+    }
+
+    for run in &runs {
+        println!("  {run}");
+    }
+
+    if std::io::stdin().is_terminal() {
+        let prompt = format!("Reject these {} runs?", runs.len());
+        let confirmed = interruptible_prompt(move || {
+            Confirm::new().with_prompt(prompt).default(false).interact()
+        })?;
+        if !confirmed {
+            return Ok(Vec::new());
+        }
+    }
+
+    let mut rejected = Vec::new();
+    for run in &runs {
+        match reject_run(&run.run_id, reason, client, api_key).await {
+            Ok(()) => {
+                tracing::info!(run_id = %run.run_id, player, reason, "Rejected run");
+                println!("Rejected {}", run.run_id);
+                rejected.push(run.run_id.clone());
+            }
+            Err(e) => tracing::error!("Couldn't reject {}: {e:#}", run.run_id),
+        }
+    }
+
+    println!(
+        "\n{} of {} runs by {player} rejected.",
+        rejected.len(),
+        runs.len()
+    );
+    Ok(rejected)
+}
+
+/// How long a fetched `ban_list_url` is reused before fetching it again.
+const BAN_LIST_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// A fetched `ban_list_url`, cached under the user's cache directory.
+#[derive(Serialize, Deserialize)]
+struct CachedBanList {
+    url: String,
+    players: Vec<String>,
+}
+
+fn ban_list_cache() -> Option<PathBuf> {
+    Some(dirs::cache_dir()?.join("dlvod").join("ban-list.json"))
+}
+
+/// Fetches a shared ban list: a JSON array of player names.
+pub async fn fetch_ban_list(url: &str, client: &reqwest::Client) -> Result<Vec<String>> {
+    client
+        .get(url)
+        .send_with_retry()
+        .await
+        .context("Requesting the ban list")?
+        .error_for_status()
+        .context("Requesting the ban list")?
+        .json_limited()
+        .await
+        .context("Couldn't parse the ban list, expected an array of player names")
+}
+
+/// Reads the cached ban list from `url` if it's recent enough.
+fn cached_ban_list(path: &Path, url: &str) -> Option<Vec<String>> {
+    let age = fs::metadata(path).ok()?.modified().ok()?.elapsed().ok()?;
+    if age > BAN_LIST_TTL {
+        return None;
+    }
+
+    let cached: CachedBanList = serde_json::from_str(&fs::read_to_string(path).ok()?).ok()?;
+    (cached.url == url).then_some(cached.players)
+}
+
+/// The players banned by the shared list at `url`, fetched at most once an
+/// hour. Empty, with a warning, if it can't be fetched.
+pub async fn load_ban_list(url: &str, client: &reqwest::Client) -> Vec<String> {
+    let cache = ban_list_cache();
+    if let Some(players) = cache.as_deref().and_then(|path| cached_ban_list(path, url)) {
+        return players;
+    }
+
+    let players = match fetch_ban_list(url, client).await {
+        Ok(players) => players,
+        Err(e) => {
+            tracing::warn!("Using the local ban list only: {e:#}");
+            return Vec::new();
+        }
+    };
+
+    if let Some(path) = cache {
+        let cached = CachedBanList {
+            url: url.to_string(),
+            players: players.clone(),
+        };
+        let written = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| fs::write(&path, serde_json::to_string(&cached)?));
+        if let Err(e) = written {
+            tracing::debug!("Couldn't cache the ban list in {}: {e}", path.display());
+        }
+    }
+
+    players
+}
+
+/// Whether `player` is on the `banned` list, ignoring case.
+pub fn is_banned(player: &str, banned: &[String]) -> bool {
+    banned.iter().any(|name| name.eq_ignore_ascii_case(player))
+}
+
+/// Flags the runs with any player on the `banned` list, co-op partners
+/// included.
+pub fn mark_banned(runs: &mut [Run], banned: &[String]) {
+    for run in runs {
+        run.banned = run.players.iter().any(|player| is_banned(player, banned));
+    }
+}
+
+/// Rejects the runs flagged by [`mark_banned`], and drops them from `runs`.
+///
+/// Like [`auto_verify`], nothing is rejected without `auto_reject.apply = true`
+/// in the configuration file, and `--dry-run` only lists the candidates.
+pub async fn auto_reject_banned(
+    runs: &mut Vec<Run>,
+    cfg: &Config,
+    client: &reqwest::Client,
+) -> Result<()> {
+    let count = runs.iter().filter(|run| run.banned).count();
+    if count == 0 {
+        return Ok(());
+    }
+
+    if cfg.dry_run {
+        for run in runs.iter().filter(|run| run.banned) {
+            println!("Would auto-reject {run}");
+        }
+        return Ok(());
+    }
+
+    if !cfg.file.auto_reject.apply {
+        tracing::warn!(
+            "{count} runs are from banned players, set auto_reject.apply = true to reject them"
+        );
+        return Ok(());
+    }
+
+    let api_key = cfg
+        .api_key
+        .as_deref()
+        .context("Auto-rejection requires an API key")?;
+    let reason = &cfg.file.auto_reject.reason;
+
+    let mut rejected = HashSet::new();
+    for run in runs.iter().filter(|run| run.banned) {
+        match reject_run(&run.run_id, reason, client, api_key).await {
+            Ok(()) => {
+                println!("{} {run}", paint("1;31", "Auto-rejected"));
+                rejected.insert(run.run_id.clone());
+            }
+            Err(e) => tracing::error!("Couldn't auto-reject {}: {e:#}", run.run_id),
+        }
+    }
+
+    runs.retain(|run| !rejected.contains(&run.run_id));
+    Ok(())
+}
+
+/// The IDs of the runs within `percent` of their category's world record.
+///
+/// Runs by banned players never are, even when [`auto_reject_banned`] left
+/// them in `runs` because it only listed them or failed to reject them. Nor
+/// are runs without a video, which can't have been checked.
+fn auto_verify_candidates(runs: &[Run], percent: f64) -> HashSet<String> {
+    runs.iter()
+        .filter(|run| !run.banned && !run.vod_missing)
+        .filter(|run| {
+            run.wr_time.is_some_and(|wr| {
+                run.duration.as_secs_f64() < wr.as_secs_f64() * (1.0 + percent / 100.0)
+            })
+        })
+        .map(|run| run.run_id.clone())
+        .collect()
+}
+
+/// Verifies the runs within `percent` of their category's world record, and
+/// drops them from `runs`. The records come from
+/// [`mark_wr_candidates`](crate::mark_wr_candidates): runs it couldn't find
+/// one for are never candidates.
+///
+/// Nothing is verified without `auto_verify.apply = true` in the configuration
+/// file; with `--dry-run`, the candidates are only listed.
+pub async fn auto_verify(
+    runs: &mut Vec<Run>,
+    percent: f64,
+    cfg: &Config,
+    client: &reqwest::Client,
+) -> Result<()> {
+    let candidates = auto_verify_candidates(runs, percent);
+
+    if candidates.is_empty() {
+        return Ok(());
+    }
+
+    if cfg.dry_run {
+        for run in runs.iter().filter(|run| candidates.contains(&run.run_id)) {
+            println!("Would auto-verify {run}");
+        }
+        return Ok(());
+    }
+
+    if !cfg.file.auto_verify.apply {
+        tracing::warn!(
+            "{} runs are within {percent}% of the world record, set auto_verify.apply = true \
+             to verify them",
+            candidates.len()
+        );
+        return Ok(());
+    }
+
+    let api_key = cfg
+        .api_key
+        .as_deref()
+        .context("Auto-verification requires an API key")?;
+
+    let mut verified = HashSet::new();
+    for run in runs.iter().filter(|run| candidates.contains(&run.run_id)) {
+        match verify_run(&run.run_id, client, api_key).await {
+            Ok(()) => {
+                println!("{} {run}", paint("1;32", "Auto-verified"));
+                verified.insert(run.run_id.clone());
+            }
+            Err(e) => tracing::error!("Couldn't auto-verify {}: {e:#}", run.run_id),
+        }
+    }
+
+    runs.retain(|run| !verified.contains(&run.run_id));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn run(id: &str, secs: u64, wr_secs: Option<u64>) -> Run {
+        Run {
+            run_id: id.to_string(),
+            vod_uri: format!("https://www.twitch.tv/videos/{id}"),
+            duration: Duration::from_secs(secs),
+            wr_time: wr_secs.map(Duration::from_secs),
+            ..Default::default()
+        }
+    }
+
+    fn sorted(ids: HashSet<String>) -> Vec<String> {
+        let mut ids = ids.into_iter().collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn verifies_runs_close_to_the_record() {
+        let runs = [
+            run("close", 104, Some(100)),
+            run("slow", 120, Some(100)),
+            run("unknown", 50, None),
+        ];
+        assert_eq!(sorted(auto_verify_candidates(&runs, 5.0)), ["close"]);
+    }
+
+    #[test]
+    fn needs_a_record_to_compare_with() {
+        // An empty leaderboard, or one that couldn't be fetched.
+        let runs = [run("fast", 1, None)];
+        assert!(auto_verify_candidates(&runs, 100.0).is_empty());
+    }
+
+    #[test]
+    fn never_verifies_runs_without_a_video() {
+        let mut missing = run("missing", 100, Some(100));
+        missing.vod_uri.clear();
+        missing.vod_missing = true;
+        let runs = [missing, run("fine", 100, Some(100))];
+
+        assert_eq!(sorted(auto_verify_candidates(&runs, 5.0)), ["fine"]);
+    }
+
+    /// `run` by `players`, the first one listed as its player.
+    fn played_by(mut run: Run, players: &[&str]) -> Run {
+        run.player = players[0].to_string();
+        run.players = players.iter().map(|p| p.to_string()).collect();
+        run
+    }
+
+    #[test]
+    fn never_verifies_banned_players() {
+        let banned = played_by(run("banned", 100, Some(100)), &["Cheater"]);
+        let partner = played_by(run("partner", 100, Some(100)), &["Honest", "Cheater"]);
+        let fine = played_by(run("fine", 100, Some(100)), &["Honest"]);
+        let mut runs = vec![banned, partner, fine];
+        mark_banned(&mut runs, &["cheater".to_string()]);
+
+        let flagged = runs.iter().map(|run| run.banned).collect::<Vec<_>>();
+        assert_eq!(flagged, [true, true, false]);
+        assert_eq!(sorted(auto_verify_candidates(&runs, 5.0)), ["fine"]);
+    }
+
+    #[test]
+    fn rejects_co_op_runs_of_the_player() {
+        let runs = vec![
+            played_by(run("solo", 100, None), &["Someone"]),
+            played_by(run("co-op", 100, None), &["Partner", "Someone"]),
+            played_by(run("other", 100, None), &["Other"]),
+        ];
+        let ids = runs_by_player(runs, "someone")
+            .into_iter()
+            .map(|run| run.run_id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["solo", "co-op"]);
+    }
+}
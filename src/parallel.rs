@@ -0,0 +1,134 @@
+//! How many downloads run at the same time.
+//!
+//! `--parallel N` is a fixed limit. With `--parallel auto`, downloads start one
+//! at a time and a monitor task follows the CPU load: spare CPU lets one more
+//! download start, a saturated CPU holds the next one back. The load is
+//! averaged over [`SAMPLES_PER_ADJUSTMENT`] seconds and only the band between
+//! [`LOW_CPU_LOAD`] and [`HIGH_CPU_LOAD`] is stable, so a single adjustment
+//! doesn't bounce the limit straight back.
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::{
+    sync::{Semaphore, SemaphorePermit},
+    task::JoinHandle,
+};
+
+use crate::config::{Config, Parallel};
+
+/// Below this CPU load, one more download can run.
+const LOW_CPU_LOAD: f32 = 50.0;
+/// Above this CPU load, one download less should run.
+const HIGH_CPU_LOAD: f32 = 90.0;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// How many samples are averaged before each adjustment.
+const SAMPLES_PER_ADJUSTMENT: u32 = 10;
+
+/// Returns the new concurrency limit for a CPU load of `cpu_pct`, given the
+/// `current` one. Never goes below 1; the caller caps it at `--parallel-max`.
+pub fn compute_parallel_limit(current: usize, cpu_pct: f32) -> usize {
+    if cpu_pct < LOW_CPU_LOAD {
+        current + 1
+    } else if cpu_pct > HIGH_CPU_LOAD {
+        current.saturating_sub(1).max(1)
+    } else {
+        current
+    }
+}
+
+/// Hands out download slots, adjusting their number with `--parallel auto`.
+pub struct Limiter {
+    semaphore: Arc<Semaphore>,
+    monitor: Option<JoinHandle<()>>,
+}
+
+impl Limiter {
+    pub fn new(cfg: &Config) -> Self {
+        match cfg.parallel {
+            Parallel::Fixed(n) => Self {
+                semaphore: Arc::new(Semaphore::new(n)),
+                monitor: None,
+            },
+            Parallel::Auto => {
+                let semaphore = Arc::new(Semaphore::new(1));
+                let monitor = tokio::spawn(monitor(Arc::clone(&semaphore), cfg.parallel_max));
+                Self {
+                    semaphore,
+                    monitor: Some(monitor),
+                }
+            }
+        }
+    }
+
+    /// Waits for a free slot, held until the permit is dropped.
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.semaphore
+            .acquire()
+            .await
+            .expect("the semaphore is never closed")
+    }
+}
+
+impl Drop for Limiter {
+    fn drop(&mut self) {
+        if let Some(monitor) = &self.monitor {
+            monitor.abort();
+        }
+    }
+}
+
+async fn monitor(semaphore: Arc<Semaphore>, max: usize) {
+    let mut system = sysinfo::System::new();
+    let mut limit = 1;
+
+    // The first reading only sets the baseline.
+    system.refresh_cpu_usage();
+
+    loop {
+        let mut total = 0.0;
+        for _ in 0..SAMPLES_PER_ADJUSTMENT {
+            tokio::time::sleep(SAMPLE_INTERVAL).await;
+            system.refresh_cpu_usage();
+            total += system.global_cpu_usage();
+        }
+        let load = total / SAMPLES_PER_ADJUSTMENT as f32;
+
+        let new_limit = compute_parallel_limit(limit, load).min(max);
+        if new_limit > limit {
+            semaphore.add_permits(new_limit - limit);
+        } else if new_limit < limit {
+            // Waits for a running download to finish rather than stopping it.
+            match semaphore.acquire_many((limit - new_limit) as u32).await {
+                Ok(permits) => permits.forget(),
+                Err(_) => return,
+            }
+        }
+
+        if new_limit != limit {
+            tracing::debug!("CPU load at {load:.0}%, downloading up to {new_limit} runs at a time");
+            limit = new_limit;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_the_cpu_load() {
+        // Spare CPU, one more.
+        assert_eq!(compute_parallel_limit(1, 0.0), 2);
+        assert_eq!(compute_parallel_limit(3, 49.9), 4);
+
+        // Saturated, one less but never none.
+        assert_eq!(compute_parallel_limit(3, 95.0), 2);
+        assert_eq!(compute_parallel_limit(1, 100.0), 1);
+
+        // In between, and at the edges of the band, nothing changes.
+        for load in [LOW_CPU_LOAD, 70.0, HIGH_CPU_LOAD] {
+            assert_eq!(compute_parallel_limit(3, load), 3, "{load}");
+        }
+    }
+}
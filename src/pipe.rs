@@ -0,0 +1,342 @@
+//! Copying yt-dlp's output into ffmpeg's input.
+//!
+//! With the `linux-splice` feature, data moves between the two pipes with
+//! splice(2) and never enters userspace. Piping 1 GiB of `/dev/zero` from
+//! `head` to `cat` through the copy loop (release build), the 4 KiB buffer
+//! loop used about 0.35s of CPU time and splice about 0.1s. In a real download
+//! the wall-clock time is bound by the network and the transcode either way;
+//! splice only leaves more CPU to ffmpeg.
+//!
+//! With the `io-uring` feature, `--use-io-uring` runs the copy on a
+//! tokio-uring ring instead, with a 64 KiB buffer. In the same test it took
+//! about 0.65s wall-clock and 0.27s of CPU time, against 0.85-1.3s and
+//! 0.35-0.55s for the 4 KiB buffer loop. Most of that gain comes from the
+//! bigger buffer: the buffer loop with 64 KiB reads is as fast as io_uring,
+//! since the kernel hands pipe operations to io_uring's worker threads anyway,
+//! which is why `--pipe-buffer-size` now defaults to 64 KiB.
+//!
+//! `--max-pipe-memory` caps the buffers of all downloads together: each one
+//! gets at most its share under `--parallel`, and waits for memory to be freed
+//! before allocating it.
+//!
+//! With `--multi-output`, [`tee`] hands each chunk to every ffmpeg before
+//! reading the next one, always through the buffer loop: a spliced pipe can only be read once.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use bytesize::ByteSize;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    process::{ChildStdin, ChildStdout},
+    sync::{Semaphore, SemaphorePermit},
+};
+
+#[cfg(all(
+    target_os = "linux",
+    any(feature = "io-uring", feature = "linux-splice")
+))]
+use std::fs::File;
+
+use crate::{config::Config, unix_millis, DownloadStatus};
+
+/// Buffers never get smaller than this, whatever `--max-pipe-memory` says.
+const MIN_BUFFER_SIZE: u64 = 4096;
+
+/// What `--max-pipe-memory` is accounted in, to fit semaphore permits.
+const PERMIT_SIZE: u64 = 1024;
+
+/// The memory left under `--max-pipe-memory`, in [`PERMIT_SIZE`] units.
+static PIPE_MEMORY: OnceLock<Semaphore> = OnceLock::new();
+
+/// How to run the copy, taken from [`Config`] before handing it to a thread.
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    buffer_size: usize,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    io_uring: bool,
+}
+
+impl Options {
+    pub fn new(cfg: &Config) -> Self {
+        let mut buffer_size = cfg.pipe_buffer_size.as_u64().max(MIN_BUFFER_SIZE);
+
+        if let Some(max) = cfg.max_pipe_memory {
+            // Share the budget between the downloads that can run at once.
+            let share = max.as_u64() / cfg.max_parallel() as u64;
+            buffer_size = buffer_size.min(share).max(MIN_BUFFER_SIZE);
+
+            PIPE_MEMORY.get_or_init(|| {
+                let permits = (max.as_u64() / PERMIT_SIZE).max(buffer_size / PERMIT_SIZE);
+                Semaphore::new(permits as usize)
+            });
+        }
+
+        tracing::debug!("Pipe buffer: {} per download", ByteSize(buffer_size));
+
+        Self {
+            buffer_size: buffer_size as usize,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            io_uring: cfg.use_io_uring,
+        }
+    }
+
+    /// Waits until the buffer fits under `--max-pipe-memory`.
+    async fn reserve_buffer(&self) -> Option<SemaphorePermit<'static>> {
+        let semaphore = PIPE_MEMORY.get()?;
+        let permits = (self.buffer_size as u64).div_ceil(PERMIT_SIZE) as u32;
+        semaphore.acquire_many(permits).await.ok()
+    }
+}
+
+/// Copies `reader` into `writer` until EOF or until `done` is set, keeping
+/// `status` and the watchdog's `last_write` up to date.
+pub async fn copy(
+    reader: ChildStdout,
+    writer: ChildStdin,
+    options: Options,
+    status: Arc<DownloadStatus>,
+    last_write: Arc<AtomicU64>,
+    done: Arc<AtomicBool>,
+) -> Result<()> {
+    // Splicing doesn't go through a buffer, but takes its share anyway:
+    // falling back needs one.
+    let _permit = options.reserve_buffer().await;
+
+    // Both run on a thread of their own. Taking the descriptors from tokio
+    // puts them back in blocking mode.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    if options.io_uring {
+        let (reader, writer) = (
+            File::from(reader.into_owned_fd()?),
+            File::from(writer.into_owned_fd()?),
+        );
+        return tokio::task::spawn_blocking(move || {
+            uring_copy(&reader, &writer, options, &status, &last_write, &done)
+        })
+        .await?;
+    }
+
+    #[cfg(all(target_os = "linux", feature = "linux-splice"))]
+    let (mut reader, mut writer) = {
+        let (reader, writer) = (
+            File::from(reader.into_owned_fd()?),
+            File::from(writer.into_owned_fd()?),
+        );
+        let (status, last_write, done) = (status.clone(), last_write.clone(), done.clone());
+        let (spliced, reader, writer) = tokio::task::spawn_blocking(move || {
+            let spliced = splice_copy(&reader, &writer, &status, &last_write, &done);
+            (spliced, reader, writer)
+        })
+        .await?;
+
+        if spliced? {
+            return Ok(());
+        }
+        (
+            tokio::fs::File::from_std(reader),
+            tokio::fs::File::from_std(writer),
+        )
+    };
+
+    #[cfg(not(all(target_os = "linux", feature = "linux-splice")))]
+    let (mut reader, mut writer) = (reader, writer);
+
+    buffer_copy(
+        &mut reader,
+        std::slice::from_mut(&mut writer),
+        options,
+        &status,
+        &last_write,
+        &done,
+    )
+    .await
+}
+
+/// [`copy`], into every one of `writers`. The slowest one sets the pace.
+pub async fn tee(
+    mut reader: ChildStdout,
+    mut writers: Vec<ChildStdin>,
+    options: Options,
+    status: Arc<DownloadStatus>,
+    last_write: Arc<AtomicU64>,
+    done: Arc<AtomicBool>,
+) -> Result<()> {
+    let _permit = options.reserve_buffer().await;
+    buffer_copy(
+        &mut reader,
+        &mut writers,
+        options,
+        &status,
+        &last_write,
+        &done,
+    )
+    .await
+}
+
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Waits while the download is paused through the control socket.
+async fn wait_while_paused(status: &DownloadStatus, last_write: &AtomicU64, done: &AtomicBool) {
+    while status.paused.load(Ordering::SeqCst) && !done.load(Ordering::SeqCst) {
+        // Not a stall, keep the watchdog quiet.
+        last_write.store(unix_millis(), Ordering::SeqCst);
+        tokio::time::sleep(PAUSE_POLL_INTERVAL).await;
+    }
+}
+
+/// [`wait_while_paused`], for the copies running on a thread.
+#[cfg(all(
+    target_os = "linux",
+    any(feature = "io-uring", feature = "linux-splice")
+))]
+fn block_while_paused(status: &DownloadStatus, last_write: &AtomicU64, done: &AtomicBool) {
+    while status.paused.load(Ordering::SeqCst) && !done.load(Ordering::SeqCst) {
+        last_write.store(unix_millis(), Ordering::SeqCst);
+        std::thread::sleep(PAUSE_POLL_INTERVAL);
+    }
+}
+
+fn record_write(status: &DownloadStatus, last_write: &AtomicU64, len: usize) {
+    status.bytes_written.fetch_add(len as u64, Ordering::SeqCst);
+    last_write.store(unix_millis(), Ordering::SeqCst);
+}
+
+async fn buffer_copy(
+    reader: &mut (impl AsyncRead + Unpin),
+    writers: &mut [impl AsyncWrite + Unpin],
+    options: Options,
+    status: &DownloadStatus,
+    last_write: &AtomicU64,
+    done: &AtomicBool,
+) -> Result<()> {
+    let mut buf = vec![0u8; options.buffer_size];
+
+    loop {
+        wait_while_paused(status, last_write, done).await;
+
+        let bytes_read = reader
+            .read(&mut buf)
+            .await
+            .context("Couldn't read from yt-dlp")?;
+
+        futures::future::try_join_all(
+            writers
+                .iter_mut()
+                .map(|writer| writer.write_all(&buf[0..bytes_read])),
+        )
+        .await
+        .context("Couldn't write to ffmpeg")?;
+        record_write(status, last_write, bytes_read);
+
+        if bytes_read == 0 || done.load(Ordering::SeqCst) {
+            // A file's writes only complete here.
+            futures::future::try_join_all(writers.iter_mut().map(|writer| writer.flush()))
+                .await
+                .context("Couldn't write to ffmpeg")?;
+            return Ok(());
+        }
+    }
+}
+
+/// Returns `false` without copying anything if the descriptors can't be
+/// spliced, so the caller can fall back to [`buffer_copy`].
+#[cfg(all(target_os = "linux", feature = "linux-splice"))]
+fn splice_copy(
+    reader: &File,
+    writer: &File,
+    status: &DownloadStatus,
+    last_write: &AtomicU64,
+    done: &AtomicBool,
+) -> Result<bool> {
+    use nix::{
+        errno::Errno,
+        fcntl::{splice, SpliceFFlags},
+    };
+
+    /// Pipe buffers default to 64 KiB, there's no point asking for more.
+    const CHUNK: usize = 64 * 1024;
+
+    let mut first = true;
+
+    loop {
+        block_while_paused(status, last_write, done);
+
+        let moved = match splice(
+            reader,
+            None,
+            writer,
+            None,
+            CHUNK,
+            SpliceFFlags::SPLICE_F_MOVE | SpliceFFlags::SPLICE_F_MORE,
+        ) {
+            Ok(moved) => moved,
+            Err(Errno::EINTR) => continue,
+            Err(Errno::EINVAL) if first => {
+                tracing::debug!("splice isn't supported here, copying through a buffer");
+                return Ok(false);
+            }
+            Err(e) => return Err(e).context("Couldn't splice yt-dlp into ffmpeg"),
+        };
+        first = false;
+
+        record_write(status, last_write, moved);
+
+        if moved == 0 || done.load(Ordering::SeqCst) {
+            return Ok(true);
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+fn uring_copy(
+    reader: &File,
+    writer: &File,
+    options: Options,
+    status: &DownloadStatus,
+    last_write: &AtomicU64,
+    done: &AtomicBool,
+) -> Result<()> {
+    use tokio_uring::buf::BoundedBuf;
+
+    // The ring takes ownership of its files; duplicates close along with the
+    // originals once the copy is over.
+    let reader = reader.try_clone()?;
+    let writer = writer.try_clone()?;
+
+    tokio_uring::start(async {
+        let reader = tokio_uring::fs::File::from_std(reader);
+        let writer = tokio_uring::fs::File::from_std(writer);
+        let mut buf = vec![0u8; options.buffer_size];
+
+        loop {
+            block_while_paused(status, last_write, done);
+
+            // Pipes have no offset, the position is ignored.
+            let (result, read_buf) = reader.read_at(buf, 0).await;
+            let bytes_read = result.context("Couldn't read from yt-dlp")?;
+            buf = read_buf;
+
+            let mut written = 0;
+            while written < bytes_read {
+                let (result, slice) = writer
+                    .write_at(buf.slice(written..bytes_read), 0)
+                    .submit()
+                    .await;
+                written += result.context("Couldn't write to ffmpeg")?;
+                buf = slice.into_inner();
+            }
+            record_write(status, last_write, bytes_read);
+
+            if bytes_read == 0 || done.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+        }
+    })
+}
@@ -0,0 +1,156 @@
+//! Where a run's video is hosted.
+//!
+//! yt-dlp handles every host runners link to, but not the same way: a Twitch
+//! clip has different formats than a VOD of the same channel, and the same
+//! video shows up under several URLs. Links are normalized when runs are read,
+//! and the platform picks the yt-dlp format when `--yt-format` isn't given.
+
+use reqwest::Url;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VodPlatform {
+    Twitch,
+    /// A clip is a few seconds to a minute long, rarely a whole run.
+    TwitchClip,
+    YouTube,
+    /// Turns most requests away without a logged in session's cookies.
+    Bilibili,
+    Other,
+}
+
+impl VodPlatform {
+    /// The yt-dlp format to ask for, where its default doesn't stream well.
+    pub fn ytdlp_format(self) -> Option<&'static str> {
+        match self {
+            // VODs only come as single HLS streams, no merging needed.
+            VodPlatform::Twitch => Some("best"),
+            // Clips are progressive files, not all of them mp4.
+            VodPlatform::TwitchClip => Some("best[ext=mp4]/best"),
+            VodPlatform::YouTube | VodPlatform::Bilibili | VodPlatform::Other => None,
+        }
+    }
+
+    /// Arguments yt-dlp needs to get anything from the platform at all.
+    pub fn ytdlp_args(self) -> &'static [&'static str] {
+        match self {
+            VodPlatform::Bilibili => &["--cookies-from-browser", "chrome"],
+            _ => &[],
+        }
+    }
+}
+
+/// Tells the platform from a video URL.
+pub fn vod_platform(url: &str) -> VodPlatform {
+    let Ok(url) = Url::parse(url) else {
+        return VodPlatform::Other;
+    };
+    let host = url.host_str().unwrap_or_default();
+    let segments = url
+        .path_segments()
+        .map(|segments| segments.filter(|s| !s.is_empty()).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    match host.trim_start_matches("www.").trim_start_matches("m.") {
+        "clips.twitch.tv" => VodPlatform::TwitchClip,
+        "twitch.tv" if segments.get(1) == Some(&"clip") => VodPlatform::TwitchClip,
+        "twitch.tv" => VodPlatform::Twitch,
+        "youtube.com" | "youtu.be" | "music.youtube.com" => VodPlatform::YouTube,
+        "bilibili.com" | "b23.tv" => VodPlatform::Bilibili,
+        _ => VodPlatform::Other,
+    }
+}
+
+/// Rewrites the many forms of Twitch links into one per video:
+/// `https://www.twitch.tv/videos/<id>` for VODs, including the old
+/// `/<channel>/v/<id>` form, and `https://clips.twitch.tv/<slug>` for clips.
+/// Timestamps and other query parameters are dropped. Other URLs are returned
+/// as they are.
+pub fn normalize_twitch_url(url: &str) -> String {
+    let Ok(parsed) = Url::parse(url) else {
+        return url.to_string();
+    };
+    let host = parsed.host_str().unwrap_or_default();
+    let segments = parsed
+        .path_segments()
+        .map(|segments| segments.filter(|s| !s.is_empty()).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    match (
+        host.trim_start_matches("www.").trim_start_matches("m."),
+        &segments[..],
+    ) {
+        ("clips.twitch.tv", [slug, ..]) => format!("https://clips.twitch.tv/{slug}"),
+        ("twitch.tv", ["videos", id, ..]) | ("twitch.tv", [_, "v", id, ..]) => {
+            format!("https://www.twitch.tv/videos/{id}")
+        }
+        ("twitch.tv", [_, "clip", slug, ..]) => format!("https://clips.twitch.tv/{slug}"),
+        _ => url.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_platforms_apart() {
+        assert_eq!(
+            vod_platform("https://www.twitch.tv/videos/1"),
+            VodPlatform::Twitch
+        );
+        assert_eq!(
+            vod_platform("https://m.twitch.tv/videos/1"),
+            VodPlatform::Twitch
+        );
+        assert_eq!(
+            vod_platform("https://clips.twitch.tv/Slug"),
+            VodPlatform::TwitchClip
+        );
+        assert_eq!(
+            vod_platform("https://www.twitch.tv/someone/clip/Slug"),
+            VodPlatform::TwitchClip
+        );
+        assert_eq!(vod_platform("https://youtu.be/abc"), VodPlatform::YouTube);
+        assert_eq!(
+            vod_platform("https://www.youtube.com/watch?v=abc"),
+            VodPlatform::YouTube
+        );
+        assert_eq!(
+            vod_platform("https://example.test/v.mp4"),
+            VodPlatform::Other
+        );
+        for url in [
+            "https://www.bilibili.com/video/BV1xx411c7mD",
+            "https://m.bilibili.com/video/BV1xx411c7mD",
+            "https://b23.tv/abcdef",
+        ] {
+            assert_eq!(vod_platform(url), VodPlatform::Bilibili, "{url}");
+        }
+        assert_eq!(vod_platform("not a url"), VodPlatform::Other);
+    }
+
+    #[test]
+    fn normalizes_twitch_links() {
+        for url in [
+            "https://twitch.tv/videos/123",
+            "https://www.twitch.tv/videos/123?t=1h2m3s",
+            "https://m.twitch.tv/videos/123",
+            "https://www.twitch.tv/someone/v/123",
+        ] {
+            assert_eq!(
+                normalize_twitch_url(url),
+                "https://www.twitch.tv/videos/123",
+                "{url}"
+            );
+        }
+
+        assert_eq!(
+            normalize_twitch_url("https://www.twitch.tv/someone/clip/Slug?filter=clips"),
+            "https://clips.twitch.tv/Slug"
+        );
+        assert_eq!(
+            normalize_twitch_url("https://youtu.be/abc?t=10"),
+            "https://youtu.be/abc?t=10"
+        );
+    }
+}
@@ -0,0 +1,579 @@
+//! ffmpeg passes that run on the finished download.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::{
+    config::Config,
+    encode::{build_ffmpeg_args, escape_filter_path, media_extension, Codec},
+    platform, sidecar, tools, Run,
+};
+
+pub struct Chapter {
+    pub start: Duration,
+    pub end: Duration,
+    pub title: String,
+}
+
+/// Parses `hh:mm:ss`, `mm:ss` or plain seconds, with optional fractional seconds.
+pub fn parse_timestamp(s: &str) -> Result<Duration> {
+    let mut secs = 0f64;
+    for part in s.split(':') {
+        let value: f64 = part
+            .parse()
+            .with_context(|| format!("Invalid timestamp {s:?}"))?;
+        secs = secs * 60.0 + value;
+    }
+
+    Duration::try_from_secs_f64(secs).with_context(|| format!("Invalid timestamp {s:?}"))
+}
+
+/// Parses a `<cols>x<rows>` grid size.
+pub fn parse_grid(s: &str) -> Result<(u32, u32)> {
+    let (cols, rows) = s
+        .split_once(['x', 'X'])
+        .with_context(|| format!("Invalid grid {s:?}, expected e.g. 10x10"))?;
+
+    let parse = |n: &str| match n.trim().parse::<u32>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => bail!("Invalid grid {s:?}, expected e.g. 10x10"),
+    };
+    Ok((parse(cols)?, parse(rows)?))
+}
+
+/// Parses a chapters file with one `<timestamp> <title>` per line.
+///
+/// Each chapter ends where the next one starts, and the last one at `total`.
+pub fn parse_chapters(text: &str, total: Duration) -> Result<Vec<Chapter>> {
+    let mut starts = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (ts, title) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            Ok((parse_timestamp(ts)?, title.trim().to_string()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    starts.sort_by_key(|(start, _)| *start);
+
+    let ends = starts
+        .iter()
+        .skip(1)
+        .map(|(start, _)| *start)
+        .chain([total])
+        .collect::<Vec<_>>();
+
+    Ok(starts
+        .into_iter()
+        .zip(ends)
+        .map(|((start, title), end)| Chapter { start, end, title })
+        .collect())
+}
+
+/// Chapters for a VOD whose run starts at `run_start`.
+pub fn run_start_chapters(run_start: Duration, total: Duration) -> Vec<Chapter> {
+    vec![
+        Chapter {
+            start: Duration::ZERO,
+            end: run_start,
+            title: "Pre-run".to_string(),
+        },
+        Chapter {
+            start: run_start,
+            end: total,
+            title: "Run Start".to_string(),
+        },
+    ]
+}
+
+fn escape_metadata(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Writes `chapters` as an ffmetadata file in `temp_dir` and returns the ffmpeg
+/// input and mapping arguments that apply it to input 0.
+pub fn build_chapter_args(chapters: &[Chapter], temp_dir: &Path) -> Result<Vec<String>> {
+    let mut metadata = String::from(";FFMETADATA1\n");
+    for chapter in chapters {
+        metadata.push_str(&format!(
+            "[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+            chapter.start.as_millis(),
+            chapter.end.as_millis(),
+            escape_metadata(&chapter.title)
+        ));
+    }
+
+    let path = temp_dir.join("chapters.ffmeta");
+    fs::write(&path, metadata).with_context(|| format!("Couldn't write {}", path.display()))?;
+
+    Ok([
+        "-i".to_string(),
+        path.to_string_lossy().into_owned(),
+        "-map".to_string(),
+        "0".to_string(),
+        "-map_metadata".to_string(),
+        "1".to_string(),
+        "-map_chapters".to_string(),
+        "1".to_string(),
+        "-codec".to_string(),
+        "copy".to_string(),
+    ]
+    .into())
+}
+
+/// Returns the duration of a media file according to ffprobe.
+pub fn probe_duration(path: &Path) -> Result<Duration> {
+    let output = Command::new(tools::ffprobe())
+        .args(["-v", "error", "-show_entries", "format=duration"])
+        .args(["-of", "default=noprint_wrappers=1:nokey=1"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .context("Couldn't run ffprobe")?;
+
+    if !output.status.success() {
+        bail!("ffprobe failed: {}", output.status);
+    }
+
+    let secs: f64 = String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .context("Couldn't parse ffprobe duration")?;
+    Ok(Duration::from_secs_f64(secs))
+}
+
+/// Whether ffprobe finds at least one subtitle stream in a media file.
+pub fn has_subtitle_streams(path: &Path) -> Result<bool> {
+    let output = Command::new(tools::ffprobe())
+        .args(["-v", "error", "-select_streams", "s"])
+        .args(["-show_entries", "stream=index", "-of", "csv=p=0"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .context("Couldn't run ffprobe")?;
+
+    if !output.status.success() {
+        bail!("ffprobe failed: {}", output.status);
+    }
+
+    Ok(!String::from_utf8_lossy(&output.stdout).trim().is_empty())
+}
+
+/// Where to stage a file that is renamed to `output` once finished: `temp_dir`
+/// if it's on the same filesystem, so the rename is atomic, or else next to
+/// `output`.
+pub fn staging_dir(temp_dir: &Path, output: &Path) -> PathBuf {
+    let output_dir = match output.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    if same_filesystem(temp_dir, output_dir) {
+        temp_dir.to_path_buf()
+    } else {
+        tracing::debug!(
+            "{} isn't on the same filesystem as {}, staging files there",
+            temp_dir.display(),
+            output_dir.display()
+        );
+        output_dir.to_path_buf()
+    }
+}
+
+#[cfg(unix)]
+fn same_filesystem(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => false,
+    }
+}
+
+/// Without device numbers, stay on the safe side.
+#[cfg(not(unix))]
+fn same_filesystem(_: &Path, _: &Path) -> bool {
+    false
+}
+
+/// Creates a file to stage `output` in, with `output`'s extension so ffmpeg
+/// picks the same muxer. It's removed when dropped, unless persisted.
+pub fn staged_file(output: &Path, temp_dir: &Path) -> Result<tempfile::TempPath> {
+    let dir = staging_dir(temp_dir, output);
+    let suffix = match media_extension(output) {
+        Some(ext) => format!(".{}", ext.to_string_lossy()),
+        None => String::new(),
+    };
+
+    let file = tempfile::Builder::new()
+        .prefix(".dlvod-remux-")
+        .suffix(&suffix)
+        .tempfile_in(&dir)
+        .with_context(|| format!("Couldn't create a temporary file in {}", dir.display()))?;
+    Ok(file.into_temp_path())
+}
+
+/// Creates the file a VOD is saved to before [`burn_subtitles`], where the
+/// result can be renamed into place. The file is removed when dropped.
+pub fn subtitle_source(output: &Path, temp_dir: &Path) -> Result<tempfile::NamedTempFile> {
+    let dir = staging_dir(temp_dir, output);
+    tempfile::Builder::new()
+        .prefix(".dlvod-source-")
+        .suffix(".mkv")
+        .tempfile_in(&dir)
+        .with_context(|| format!("Couldn't create a temporary file in {}", dir.display()))
+}
+
+/// Transcodes the saved VOD at `source` into `output`, burning in its
+/// subtitles. Sources without subtitle streams are transcoded as they are.
+///
+/// Returns the ffmpeg command line it ran, for `--write-metadata`.
+pub fn burn_subtitles(
+    source: &Path,
+    output: &Path,
+    codec: Codec,
+    cfg: &Config,
+) -> Result<Vec<String>> {
+    let subtitles = if has_subtitle_streams(source)? {
+        Some(source)
+    } else {
+        tracing::warn!("The VOD has no subtitles, transcoding it without");
+        None
+    };
+
+    let tmp = staged_file(output, &cfg.temp_dir())?;
+    let mut cmd = Command::new(tools::ffmpeg());
+    cmd.args(["-hide_banner", "-loglevel", "error"])
+        .args(build_ffmpeg_args(
+            codec,
+            cfg,
+            &source.to_string_lossy(),
+            subtitles,
+        ))
+        .arg(&tmp)
+        .stdin(Stdio::null());
+    let status = cmd.status().context("Couldn't run ffmpeg")?;
+
+    if !status.success() {
+        bail!("ffmpeg failed: {status}");
+    }
+
+    tmp.persist(output)
+        .with_context(|| format!("Couldn't write {}", output.display()))?;
+    Ok(sidecar::extract_std_args(&cmd))
+}
+
+/// Runs `ffmpeg -i <path> <args> <tmp>` and replaces `path` with the result.
+pub fn remux_in_place(path: &Path, args: &[String], temp_dir: &Path) -> Result<()> {
+    let tmp = staged_file(path, temp_dir)?;
+    let status = Command::new(tools::ffmpeg())
+        .args(["-y", "-hide_banner", "-loglevel", "error", "-i"])
+        .arg(path)
+        .args(args)
+        .arg(&tmp)
+        .stdin(Stdio::null())
+        .status()
+        .context("Couldn't run ffmpeg")?;
+
+    if !status.success() {
+        bail!("ffmpeg failed: {status}");
+    }
+
+    tmp.persist(path)
+        .with_context(|| format!("Couldn't replace {}", path.display()))
+}
+
+/// Embeds chapters from `<output>.chapters`, or from `run_start` if there is
+/// no chapters file.
+pub fn add_chapters(output: &Path, run_start: Option<Duration>, temp_dir: &Path) -> Result<()> {
+    let chapters_file = output.with_extension("chapters");
+    let chapters_text = match fs::read_to_string(&chapters_file) {
+        Ok(text) => Some(text),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            return Err(e).with_context(|| format!("Couldn't read {}", chapters_file.display()))
+        }
+    };
+
+    let chapters = match (chapters_text, run_start) {
+        (Some(text), _) => parse_chapters(&text, probe_duration(output)?)?,
+        (None, Some(run_start)) => run_start_chapters(run_start, probe_duration(output)?),
+        (None, None) => return Ok(()),
+    };
+
+    let metadata_dir = tempfile::tempdir_in(temp_dir)
+        .with_context(|| format!("Couldn't create a directory in {}", temp_dir.display()))?;
+    let args = build_chapter_args(&chapters, metadata_dir.path())?;
+    remux_in_place(output, &args, temp_dir).context("Couldn't embed chapters")
+}
+
+/// Saves `length` seconds from the middle of the run as `<output>.gif`, small
+/// enough for moderation dashboards.
+pub fn generate_gif_preview(output_path: &Path, run: &Run, length: u64) -> Result<PathBuf> {
+    let gif = output_path.with_extension("gif");
+    let middle = run.duration / 2;
+
+    let status = Command::new(tools::ffmpeg())
+        .args(["-y", "-hide_banner", "-loglevel", "error"])
+        .args(["-ss", &format!("{:.3}", middle.as_secs_f64())])
+        .args(["-t", &length.to_string(), "-i"])
+        .arg(output_path)
+        .args(["-vf", "fps=10,scale=320:-1:flags=lanczos", "-loop", "0"])
+        .arg(&gif)
+        .stdin(Stdio::null())
+        .status()
+        .context("Couldn't run ffmpeg")?;
+
+    if !status.success() {
+        let _ = fs::remove_file(&gif);
+        bail!("ffmpeg failed: {status}");
+    }
+
+    Ok(gif)
+}
+
+/// Saves a `cols`x`rows` grid of thumbnails taken at regular intervals over
+/// the run as `<output>_sprites.jpg`.
+pub fn generate_sprite_sheet(
+    output_path: &Path,
+    run: &Run,
+    cols: u32,
+    rows: u32,
+) -> Result<PathBuf> {
+    let mut name = output_path.file_stem().unwrap_or_default().to_os_string();
+    name.push("_sprites.jpg");
+    let sprites = output_path.with_file_name(name);
+
+    let interval = run.duration.as_secs_f64() / (f64::from(cols) * f64::from(rows));
+    if interval <= 0.0 {
+        bail!("The run has no duration to take thumbnails from");
+    }
+
+    let status = Command::new(tools::ffmpeg())
+        .args(["-y", "-hide_banner", "-loglevel", "error", "-i"])
+        .arg(output_path)
+        .args([
+            "-vf",
+            &format!("fps=1/{interval:.3},scale=160:-1,tile={cols}x{rows}"),
+        ])
+        .args(["-frames:v", "1", "-update", "1"])
+        .arg(&sprites)
+        .stdin(Stdio::null())
+        .status()
+        .context("Couldn't run ffmpeg")?;
+
+    if !status.success() {
+        let _ = fs::remove_file(&sprites);
+        bail!("ffmpeg failed: {status}");
+    }
+
+    Ok(sprites)
+}
+
+/// Loudness targets shared by both loudnorm modes.
+pub const LOUDNORM_TARGET: &str = "I=-16:TP=-1.5:LRA=11";
+
+/// Measurements printed by the first loudnorm pass.
+#[derive(Debug, Deserialize)]
+pub struct LoudnormStats {
+    pub input_i: String,
+    pub input_tp: String,
+    pub input_lra: String,
+    pub input_thresh: String,
+    pub target_offset: String,
+}
+
+/// Extracts the JSON block that `loudnorm=print_format=json` prints at the end
+/// of ffmpeg's stderr.
+pub fn parse_loudnorm_stats(stderr: &str) -> Result<LoudnormStats> {
+    let start = stderr
+        .rfind('{')
+        .context("No loudnorm statistics in ffmpeg output")?;
+    let end = stderr[start..]
+        .find('}')
+        .context("Truncated loudnorm statistics")?;
+
+    serde_json::from_str(&stderr[start..=start + end]).context("Couldn't parse loudnorm statistics")
+}
+
+/// Normalizes the audio of `output` with a measurement pass followed by a
+/// linear loudnorm pass. Video is copied as-is.
+pub fn normalize_audio_two_pass(output: &Path, temp_dir: &Path) -> Result<()> {
+    let analysis = Command::new(tools::ffmpeg())
+        .args(["-hide_banner", "-nostats", "-i"])
+        .arg(output)
+        .args([
+            "-af",
+            &format!("loudnorm={LOUDNORM_TARGET}:print_format=json"),
+        ])
+        .args(["-vn", "-f", "null", "-"])
+        .stdin(Stdio::null())
+        .output()
+        .context("Couldn't run ffmpeg")?;
+
+    if !analysis.status.success() {
+        bail!("Loudness analysis failed: {}", analysis.status);
+    }
+
+    let stats = parse_loudnorm_stats(&String::from_utf8_lossy(&analysis.stderr))?;
+    let filter = format!(
+        "loudnorm={LOUDNORM_TARGET}:measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:offset={}:linear=true",
+        stats.input_i, stats.input_tp, stats.input_lra, stats.input_thresh, stats.target_offset
+    );
+
+    let args = [
+        "-c:v", "copy", "-af", &filter, "-c:a", "aac", "-b:a", "96k", "-ar", "44100",
+    ]
+    .map(String::from);
+    remux_in_place(output, &args, temp_dir).context("Couldn't normalize audio")
+}
+
+/// Extracts `fps` frames per second over `duration` from `start` as JPEG files
+/// in `<output>_frames/`, returning their paths in order.
+pub fn extract_frames(
+    output: &Path,
+    start: Duration,
+    duration: Duration,
+    fps: f32,
+) -> Result<Vec<PathBuf>> {
+    let mut dir_name = output.file_stem().unwrap_or_default().to_os_string();
+    dir_name.push("_frames");
+    let dir = output.with_file_name(dir_name);
+    fs::create_dir_all(&dir).with_context(|| format!("Couldn't create {}", dir.display()))?;
+
+    let status = Command::new(tools::ffmpeg())
+        .args(["-y", "-hide_banner", "-loglevel", "error"])
+        .args(["-ss", &format!("{:.3}", start.as_secs_f64())])
+        .args(["-t", &format!("{:.3}", duration.as_secs_f64()), "-i"])
+        .arg(output)
+        .args(["-vf", &format!("fps={fps}"), "-q:v", "2"])
+        .arg(dir.join("frame_%05d.jpg"))
+        .stdin(Stdio::null())
+        .status()
+        .context("Couldn't run ffmpeg")?;
+
+    if !status.success() {
+        bail!("ffmpeg failed: {status}");
+    }
+
+    let mut frames = fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with("frame_"))
+        })
+        .collect::<Vec<_>>();
+    frames.sort();
+    Ok(frames)
+}
+
+/// Downloads the VOD as-is into `dir`, as the reference for [`measure_vmaf`].
+pub fn download_reference(run: &Run, cfg: &Config, dir: &Path) -> Result<PathBuf> {
+    let mut cmd = Command::new(tools::ytdlp());
+    cmd.args(tools::ytdlp_network_args());
+    cmd.arg(&run.vod_uri)
+        .args(platform::vod_platform(&run.vod_uri).ytdlp_args())
+        .args(["-q", "--no-progress", "-o"])
+        .arg(dir.join("reference.%(ext)s"))
+        .stdin(Stdio::null());
+
+    if cfg.insecure {
+        cmd.arg("--no-check-certificates");
+    }
+
+    let status = cmd.status().context("Couldn't run yt-dlp")?;
+    if !status.success() {
+        bail!("yt-dlp failed: {status}");
+    }
+
+    // The extension is yt-dlp's choice.
+    fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| path.file_stem().is_some_and(|stem| stem == "reference"))
+        .context("yt-dlp didn't save the reference")
+}
+
+/// Returns the frame rate of the first video stream in `path`.
+fn probe_frame_rate(path: &Path) -> Result<String> {
+    let output = Command::new(tools::ffprobe())
+        .args(["-v", "error", "-select_streams", "v:0"])
+        .args(["-show_entries", "stream=avg_frame_rate", "-of", "csv=p=0"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .context("Couldn't run ffprobe")?;
+
+    if !output.status.success() {
+        bail!("ffprobe failed: {}", output.status);
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[derive(Deserialize)]
+struct VmafLog {
+    pooled_metrics: PooledMetrics,
+}
+
+#[derive(Deserialize)]
+struct PooledMetrics {
+    vmaf: PooledScore,
+}
+
+#[derive(Deserialize)]
+struct PooledScore {
+    mean: f64,
+}
+
+/// Computes the mean VMAF score of `output` against `reference`. The reference
+/// is brought to the output's frame rate and the output scaled up to the
+/// reference's size, so that frames are compared one to one.
+pub fn measure_vmaf(output: &Path, reference: &Path) -> Result<f64> {
+    let fps = probe_frame_rate(output)?;
+    let log = tempfile::Builder::new()
+        .prefix("dlvod-vmaf-")
+        .suffix(".json")
+        .tempfile_in(reference.parent().unwrap_or(Path::new(".")))?;
+
+    let filter = format!(
+        "[1:v]fps={fps},setpts=PTS-STARTPTS[ref0];\
+         [0:v]setpts=PTS-STARTPTS[dist0];\
+         [dist0][ref0]scale2ref=flags=bicubic[dist][ref];\
+         [dist][ref]libvmaf=log_fmt=json:log_path={}",
+        escape_filter_path(log.path())
+    );
+
+    let status = Command::new(tools::ffmpeg())
+        .args(["-hide_banner", "-loglevel", "error", "-i"])
+        .arg(output)
+        .arg("-i")
+        .arg(reference)
+        .args(["-lavfi", &filter, "-f", "null", "-"])
+        .stdin(Stdio::null())
+        .status()
+        .context("Couldn't run ffmpeg")?;
+
+    if !status.success() {
+        bail!("VMAF measurement failed: {status}");
+    }
+
+    let text = fs::read_to_string(log.path()).context("Couldn't read the VMAF log")?;
+    let log: VmafLog = serde_json::from_str(&text).context("Couldn't parse the VMAF log")?;
+    Ok(log.pooled_metrics.vmaf.mean)
+}
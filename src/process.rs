@@ -0,0 +1,183 @@
+//! Spawning yt-dlp and ffmpeg so that aborting also stops whatever they run.
+//!
+//! yt-dlp hands downloads to aria2c, and killing yt-dlp alone leaves it
+//! running. On Unix, each child leads its own process group, which is killed
+//! as a whole. On Windows, each child is put in a job object, which is
+//! terminated instead; processes it starts before being assigned to the job
+//! escape it, but it's assigned right after spawning.
+
+use std::io;
+
+use tokio::process::{Child, Command};
+
+#[cfg(windows)]
+use std::{collections::HashMap, sync::Mutex};
+
+/// Job objects of the running children, by process ID. Handles are stored as
+/// integers to keep the map `Send`.
+#[cfg(windows)]
+static JOBS: Mutex<Option<HashMap<u32, isize>>> = Mutex::new(None);
+
+/// Spawns `cmd` in a process group or job of its own, for
+/// [`kill_process_tree`].
+pub fn spawn(cmd: &mut Command) -> io::Result<Child> {
+    #[cfg(unix)]
+    {
+        // Also keeps the terminal's Ctrl+C from reaching the child directly,
+        // dlvod stops it itself.
+        cmd.process_group(0);
+    }
+
+    let child = cmd.spawn()?;
+
+    #[cfg(windows)]
+    if let Err(e) = assign_job(&child) {
+        tracing::debug!("Couldn't create a job for process {:?}: {e}", child.id());
+    }
+
+    Ok(child)
+}
+
+#[cfg(windows)]
+fn assign_job(child: &Child) -> io::Result<()> {
+    use windows_sys::Win32::{
+        Foundation::CloseHandle,
+        System::JobObjects::{AssignProcessToJobObject, CreateJobObjectW},
+    };
+
+    // Both are there until the child is waited on, which can't have happened yet.
+    let (Some(pid), Some(handle)) = (child.id(), child.raw_handle()) else {
+        return Ok(());
+    };
+
+    // SAFETY: a null name and attributes create an anonymous job, and the
+    // process handle stays valid as long as `child`.
+    unsafe {
+        let job = CreateJobObjectW(std::ptr::null(), std::ptr::null());
+        if job.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+
+        if AssignProcessToJobObject(job, handle) == 0 {
+            let e = io::Error::last_os_error();
+            CloseHandle(job);
+            return Err(e);
+        }
+
+        JOBS.lock()
+            .unwrap()
+            .get_or_insert_with(HashMap::new)
+            .insert(pid, job as isize);
+    }
+
+    Ok(())
+}
+
+/// `cmd` as a POSIX shell command line, quoted so it can be pasted in a
+/// terminal.
+pub fn shell_command(cmd: &Command) -> String {
+    let cmd = cmd.as_std();
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| shell_quote(&arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn shell_quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-+=/.,:@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// Kills `child` and everything it started.
+pub fn kill_process_tree(child: &mut Child) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        use nix::{
+            errno::Errno,
+            sys::signal::{killpg, Signal},
+            unistd::Pid,
+        };
+
+        match killpg(Pid::from_raw(pid as i32), Signal::SIGKILL) {
+            // The group is already gone.
+            Ok(()) | Err(Errno::ESRCH) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    #[cfg(windows)]
+    if let Some(job) = child.id().and_then(take_job) {
+        use windows_sys::Win32::{Foundation::CloseHandle, System::JobObjects::TerminateJobObject};
+
+        // SAFETY: `job` came from `assign_job` and is closed only here or in
+        // `release`, after being removed from the map.
+        unsafe {
+            TerminateJobObject(job as _, 1);
+            CloseHandle(job as _);
+        }
+    }
+
+    // In case the child couldn't be put in a group or job.
+    child.start_kill()
+}
+
+/// Kills `child` and everything it started if it's still running, then waits
+/// for it, so that nothing is left running or unreaped once a download gives
+/// up on it.
+pub async fn kill_and_wait(child: &mut Child) {
+    let pid = child.id();
+    if !matches!(child.try_wait(), Ok(Some(_))) {
+        if let Err(e) = kill_process_tree(child) {
+            tracing::debug!("Couldn't kill process {pid:?}: {e}");
+        }
+    }
+    if let Err(e) = child.wait().await {
+        tracing::debug!("Couldn't wait for process {pid:?}: {e}");
+    }
+    release(pid);
+}
+
+/// Frees what [`spawn`] set up for the child with ID `pid`, once it has
+/// exited. The ID has to be taken before waiting: tokio forgets it after.
+#[cfg_attr(not(windows), allow(unused_variables))]
+pub fn release(pid: Option<u32>) {
+    #[cfg(windows)]
+    if let Some(job) = pid.and_then(take_job) {
+        // SAFETY: see `kill_process_tree`.
+        unsafe {
+            windows_sys::Win32::Foundation::CloseHandle(job as _);
+        }
+    }
+}
+
+#[cfg(windows)]
+fn take_job(pid: u32) -> Option<isize> {
+    JOBS.lock().unwrap().as_mut()?.remove(&pid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_what_the_shell_would_interpret() {
+        let mut cmd = Command::new("yt-dlp");
+        cmd.args([
+            "https://www.youtube.com/watch?v=abc&t=10",
+            "-o",
+            "-",
+            "-f",
+            "best[height<=720]",
+            "it's",
+            "",
+        ]);
+        assert_eq!(
+            shell_command(&cmd),
+            r"yt-dlp 'https://www.youtube.com/watch?v=abc&t=10' -o - -f 'best[height<=720]' 'it'\''s' ''"
+        );
+    }
+}
@@ -0,0 +1,231 @@
+//! Progress display for `--parallel` downloads.
+//!
+//! A single download rewrites one line in place. With several at once, their
+//! lines would overwrite each other, so each download sends its progress to a
+//! [`ProgressAggregator`] instead, which keeps one line per run and redraws
+//! them together.
+//!
+//! Anything else printed meanwhile would land in the middle of the table, so
+//! messages go through [`println`] and logs through [`stderr`]: while the
+//! table is drawn, they're printed above it.
+
+use std::{
+    fmt,
+    io::{self, IsTerminal, Write},
+    sync::Mutex,
+};
+
+use bytesize::ByteSize;
+use ratatui::crossterm::terminal;
+use tokio::{sync::mpsc, task::JoinHandle};
+
+/// How many updates can wait for the renderer before downloads wait for it.
+const EVENT_BACKLOG: usize = 256;
+
+/// Lines to print above the table, while an aggregator is drawing one.
+static LINES: Mutex<Option<mpsc::UnboundedSender<String>>> = Mutex::new(None);
+
+/// Prints `line` to stdout, above the progress table if there's one.
+pub fn println(line: impl fmt::Display) {
+    match LINES.lock().unwrap().as_ref() {
+        // The newlines that end a single download's progress line aren't
+        // needed here.
+        Some(tx) => {
+            let _ = tx.send(line.to_string().trim_start_matches('\n').to_string());
+        }
+        None => println!("{line}"),
+    }
+}
+
+/// Whether a progress table is being drawn.
+pub fn active() -> bool {
+    LINES.lock().unwrap().is_some()
+}
+
+/// stderr, or the lines above the progress table if there's one, for logs.
+pub fn stderr() -> Stderr {
+    Stderr
+}
+
+pub struct Stderr;
+
+impl Write for Stderr {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match LINES.lock().unwrap().as_ref() {
+            Some(tx) => {
+                for line in String::from_utf8_lossy(buf).lines() {
+                    let _ = tx.send(line.to_string());
+                }
+                Ok(buf.len())
+            }
+            None => io::stderr().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// A progress line from one of the downloads.
+#[derive(Debug, Clone)]
+pub struct ProgressEvent {
+    pub run_id: String,
+    pub bytes: u64,
+    pub message: String,
+}
+
+/// Collects [`ProgressEvent`]s from every download and renders them.
+pub struct ProgressAggregator {
+    tx: mpsc::Sender<ProgressEvent>,
+    renderer: JoinHandle<()>,
+}
+
+impl ProgressAggregator {
+    pub fn start() -> Self {
+        let (tx, rx) = mpsc::channel(EVENT_BACKLOG);
+        let (lines_tx, lines) = mpsc::unbounded_channel();
+        // Without a terminal, there's no table for lines to get mixed into.
+        if io::stdout().is_terminal() {
+            *LINES.lock().unwrap() = Some(lines_tx);
+        }
+        Self {
+            tx,
+            renderer: tokio::spawn(render(rx, lines)),
+        }
+    }
+
+    /// A sender for one download to report through.
+    pub fn sender(&self) -> mpsc::Sender<ProgressEvent> {
+        self.tx.clone()
+    }
+
+    /// Waits for the renderer to catch up, once every download is over.
+    pub async fn finish(self) {
+        LINES.lock().unwrap().take();
+        drop(self.tx);
+        if self.renderer.await.is_err() {
+            tracing::warn!("Progress renderer panicked");
+        }
+    }
+}
+
+/// One line per run, in the order they started.
+#[derive(Default)]
+struct Table {
+    rows: Vec<(String, String)>,
+    /// Lines drawn last time, to move back over.
+    drawn: usize,
+}
+
+impl Table {
+    fn update(&mut self, event: ProgressEvent) {
+        let line = format!("{} ({})", event.message, ByteSize(event.bytes));
+        match self
+            .rows
+            .iter_mut()
+            .find(|(run_id, _)| *run_id == event.run_id)
+        {
+            Some((_, row)) => *row = line,
+            None => self.rows.push((event.run_id, line)),
+        }
+    }
+
+    fn draw(&mut self, out: &mut impl Write) -> io::Result<()> {
+        // A wrapped line would throw the cursor movement off.
+        let width = terminal::size().map_or(80, |(cols, _)| cols as usize);
+
+        if self.drawn > 0 {
+            write!(out, "\x1b[{}A", self.drawn)?;
+        }
+        for (run_id, line) in &self.rows {
+            let row: String = format!("{run_id}: {line}")
+                .chars()
+                .take(width.saturating_sub(1))
+                .collect();
+            writeln!(out, "\r\x1b[2K{row}")?;
+        }
+        self.drawn = self.rows.len();
+        out.flush()
+    }
+
+    /// Prints `line` where the table was, and the table again below it.
+    fn print_above(&mut self, out: &mut impl Write, line: &str) -> io::Result<()> {
+        if self.drawn > 0 {
+            write!(out, "\x1b[{}A", self.drawn)?;
+        }
+        writeln!(out, "\r\x1b[2K{line}")?;
+        self.drawn = 0;
+        self.draw(out)
+    }
+}
+
+async fn render(mut rx: mpsc::Receiver<ProgressEvent>, mut lines: mpsc::UnboundedReceiver<String>) {
+    let interactive = io::stdout().is_terminal();
+    let mut table = Table::default();
+
+    loop {
+        let event = tokio::select! {
+            event = rx.recv() => match event {
+                Some(event) => event,
+                None => break,
+            },
+            Some(line) = lines.recv() => {
+                let _ = table.print_above(&mut io::stdout().lock(), &line);
+                continue;
+            }
+        };
+        let mut stdout = io::stdout().lock();
+
+        // Without a terminal to redraw, plain lines are all that's readable.
+        if !interactive {
+            let _ = writeln!(
+                stdout,
+                "{}: {} ({})",
+                event.run_id,
+                event.message,
+                ByteSize(event.bytes)
+            );
+            continue;
+        }
+
+        table.update(event);
+        let _ = table.draw(&mut stdout);
+    }
+
+    // Printed after the last update.
+    while let Ok(line) = lines.try_recv() {
+        let _ = table.print_above(&mut io::stdout().lock(), &line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(run_id: &str, message: &str) -> ProgressEvent {
+        ProgressEvent {
+            run_id: run_id.to_string(),
+            bytes: 0,
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn prints_lines_above_the_table() {
+        let mut table = Table::default();
+        table.update(event("a", "10%"));
+        table.update(event("b", "20%"));
+        table.draw(&mut Vec::new()).unwrap();
+
+        let mut out = Vec::new();
+        table.print_above(&mut out, "Done!").unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        // Back over both rows, the line where the first one was, then both.
+        assert!(out.starts_with("\x1b[2A\r\x1b[2KDone!\n"), "{out:?}");
+        assert!(out.ends_with("b: 20% (0 B)\n"), "{out:?}");
+        assert_eq!(out.matches('\n').count(), 3);
+        assert_eq!(table.drawn, 2);
+    }
+}
@@ -0,0 +1,141 @@
+//! `--resume`: keeping what a failed download already encoded.
+//!
+//! When yt-dlp dies mid-stream, ffmpeg still finalizes what it was given, so
+//! the output is playable up to where the stream broke. That part is set
+//! aside up to its last keyframe, and the retry seeks ffmpeg's input past it.
+//! yt-dlp can't continue a download written to stdout, so the VOD is fetched
+//! from the start again: only the encode is resumed. Once the retry succeeds,
+//! the parts are joined without re-encoding.
+
+use std::{
+    fs,
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use tempfile::TempPath;
+
+use crate::{postprocess, tools};
+
+/// Returns the time of the last keyframe in `output`, in milliseconds, or
+/// `None` if there's no output or nothing in it worth keeping.
+pub fn detect_resume_point(output: &Path) -> Result<Option<u64>> {
+    if !output.exists() {
+        return Ok(None);
+    }
+
+    // Packets only, nothing is decoded.
+    let probe = Command::new(tools::ffprobe())
+        .args(["-v", "error", "-select_streams", "v:0"])
+        .args(["-show_entries", "packet=pts_time,flags", "-of", "csv=p=0"])
+        .arg(output)
+        .stdin(Stdio::null())
+        .output()
+        .context("Couldn't run ffprobe")?;
+
+    // Truncated beyond repair.
+    if !probe.status.success() {
+        return Ok(None);
+    }
+
+    let last_keyframe = String::from_utf8_lossy(&probe.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (pts, flags) = line.split_once(',')?;
+            if !flags.starts_with('K') {
+                return None;
+            }
+            pts.parse::<f64>().ok()
+        })
+        .fold(0f64, f64::max);
+
+    let millis = (last_keyframe * 1000.0) as u64;
+    Ok((millis > 0).then_some(millis))
+}
+
+/// The parts set aside by failed attempts, each with where it's cut.
+#[derive(Default)]
+pub struct Resume {
+    parts: Vec<(TempPath, Duration)>,
+}
+
+impl Resume {
+    /// How far into the VOD the next attempt starts.
+    pub fn offset(&self) -> Option<Duration> {
+        let offset: Duration = self.parts.iter().map(|(_, cut)| *cut).sum();
+        (!offset.is_zero()).then_some(offset)
+    }
+
+    /// Sets a failed attempt's `output` aside. Returns `false` if there was
+    /// nothing to keep, and the next attempt starts where this one did.
+    pub fn save_part(&mut self, output: &Path, temp_dir: &Path) -> Result<bool> {
+        let Some(millis) = detect_resume_point(output)? else {
+            return Ok(false);
+        };
+
+        let part = postprocess::staged_file(output, temp_dir)?;
+        fs::rename(output, &part)
+            .with_context(|| format!("Couldn't set {} aside", output.display()))?;
+        self.parts.push((part, Duration::from_millis(millis)));
+
+        Ok(true)
+    }
+
+    /// Joins the saved parts and the last attempt's `output` into `output`.
+    pub fn finish(self, output: &Path, temp_dir: &Path) -> Result<()> {
+        if self.parts.is_empty() {
+            return Ok(());
+        }
+
+        crate::progress::println(format_args!(
+            "Joining {} resumed parts...",
+            self.parts.len() + 1
+        ));
+
+        let mut list = tempfile::Builder::new()
+            .prefix(".dlvod-concat-")
+            .suffix(".txt")
+            .tempfile_in(temp_dir)
+            .with_context(|| {
+                format!("Couldn't create a temporary file in {}", temp_dir.display())
+            })?;
+        for (part, cut) in &self.parts {
+            writeln!(list, "file {}", concat_quote(part)?)?;
+            writeln!(list, "outpoint {:.3}", cut.as_secs_f64())?;
+        }
+        writeln!(list, "file {}", concat_quote(output)?)?;
+        list.flush()?;
+
+        let joined = postprocess::staged_file(output, temp_dir)?;
+        let status = Command::new(tools::ffmpeg())
+            .args(["-y", "-hide_banner", "-loglevel", "error"])
+            .args(["-f", "concat", "-safe", "0", "-i"])
+            .arg(list.path())
+            .args(["-c", "copy"])
+            .arg(&joined)
+            .stdin(Stdio::null())
+            .status()
+            .context("Couldn't run ffmpeg")?;
+
+        if !status.success() {
+            bail!("Joining the resumed parts failed: {status}");
+        }
+
+        joined
+            .persist(output)
+            .with_context(|| format!("Couldn't write {}", output.display()))
+    }
+}
+
+/// Quotes `path` for a concat demuxer script, where relative paths would be
+/// taken from the script's directory.
+fn concat_quote(path: &Path) -> Result<String> {
+    let path = std::path::absolute(path)?;
+    Ok(format!(
+        "'{}'",
+        path.to_string_lossy().replace('\'', r"'\''")
+    ))
+}
@@ -0,0 +1,233 @@
+//! Segmented downloads: fetch a progressive VOD as parallel byte ranges and
+//! stitch them back together before handing them to ffmpeg.
+
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{bail, Context, Result};
+use reqwest::{header, StatusCode};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+    process::Command,
+    sync::Semaphore,
+    task::JoinSet,
+};
+
+use crate::{platform, progress, tools};
+
+/// Number of segments fetched at the same time.
+const CONCURRENT_SEGMENTS: usize = 8;
+
+/// A direct media URL that can be fetched in byte ranges.
+pub struct Source {
+    pub url: String,
+    pub len: u64,
+}
+
+/// Resolves the direct media URL for `vod_uri`, or `None` if the source can't
+/// be fetched in byte ranges (e.g. HLS). With `insecure`, yt-dlp doesn't
+/// verify certificates either.
+pub async fn segmented_source(
+    vod_uri: &str,
+    client: &reqwest::Client,
+    insecure: bool,
+) -> Result<Option<Source>> {
+    let mut command = Command::new(tools::ytdlp());
+    command.args(tools::ytdlp_network_args());
+    if insecure {
+        command.arg("--no-check-certificates");
+    }
+
+    let output = command
+        .args(platform::vod_platform(vod_uri).ytdlp_args())
+        .args(["--get-url", "-f", "b", vod_uri])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        bail!("yt-dlp couldn't resolve a direct URL: {}", output.status);
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let Some(url) = stdout.lines().next().map(str::trim) else {
+        return Ok(None);
+    };
+
+    if url.contains(".m3u8") {
+        return Ok(None);
+    }
+
+    let response = client.head(url).send().await?.error_for_status()?;
+    let headers = response.headers();
+    let accepts_ranges = headers
+        .get(header::ACCEPT_RANGES)
+        .is_some_and(|v| v.as_bytes() == b"bytes");
+
+    match response.content_length() {
+        Some(len) if accepts_ranges && len > 0 => Ok(Some(Source {
+            url: url.to_string(),
+            len,
+        })),
+        _ => Ok(None),
+    }
+}
+
+/// Downloads `source` into segments of `segment_size` bytes under `dir`.
+///
+/// Segments are returned in order. Files are named `<prefix>.seg<n>` and should
+/// be removed by the caller once merged.
+pub async fn download_segments(
+    client: &reqwest::Client,
+    source: &Source,
+    segment_size: u64,
+    dir: &Path,
+    prefix: &str,
+    bytes_written: &Arc<AtomicU64>,
+    done: &Arc<AtomicBool>,
+) -> Result<Vec<PathBuf>> {
+    let len = source.len;
+    let count = len.div_ceil(segment_size);
+    let semaphore = Arc::new(Semaphore::new(CONCURRENT_SEGMENTS));
+    let mut tasks = JoinSet::new();
+
+    let segments = (0..count)
+        .map(|i| dir.join(format!("{prefix}.seg{i}")))
+        .collect::<Vec<_>>();
+
+    for (i, path) in segments.iter().enumerate() {
+        let start = i as u64 * segment_size;
+        let end = (start + segment_size).min(len) - 1;
+        let client = client.clone();
+        let url = source.url.clone();
+        let path = path.clone();
+        let semaphore = Arc::clone(&semaphore);
+        let bytes_written = Arc::clone(bytes_written);
+        let done = Arc::clone(done);
+
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire().await?;
+
+            let mut response = client
+                .get(&url)
+                .header(header::RANGE, format!("bytes={start}-{end}"))
+                .send()
+                .await?
+                .error_for_status()?;
+
+            // A server ignoring the range sends the whole file, or another
+            // part of it. Either way the segment would be wrong.
+            let content_range = response
+                .headers()
+                .get(header::CONTENT_RANGE)
+                .and_then(|v| v.to_str().ok());
+            if response.status() != StatusCode::PARTIAL_CONTENT
+                || !content_range.is_some_and(|v| content_range_matches(v, start, end, len))
+            {
+                bail!(
+                    "Asked for bytes {start}-{end}, got {} with range {content_range:?}",
+                    response.status()
+                );
+            }
+
+            let mut file = tokio::fs::File::create(&path)
+                .await
+                .with_context(|| format!("Couldn't create {}", path.display()))?;
+
+            while let Some(chunk) = response.chunk().await? {
+                if done.load(Ordering::SeqCst) {
+                    bail!("Ctrl+C");
+                }
+
+                file.write_all(&chunk).await?;
+                bytes_written.fetch_add(chunk.len() as u64, Ordering::SeqCst);
+            }
+            file.flush().await?;
+
+            Ok::<_, anyhow::Error>(())
+        });
+    }
+
+    let mut finished = 0;
+    while let Some(result) = tasks.join_next().await {
+        if let Err(e) = result.context("Segment task failed").and_then(|r| r) {
+            tasks.abort_all();
+            remove_segments(&segments);
+            return Err(e);
+        }
+
+        // The count would break the table of parallel downloads.
+        finished += 1;
+        if !progress::active() {
+            print!("\r\x1b[2K\rDownloaded segment {finished}/{count}");
+            io::stdout().flush()?;
+        }
+    }
+    if !progress::active() {
+        println!();
+    }
+
+    Ok(segments)
+}
+
+/// Whether a `Content-Range` header value is the range `start-end` of a
+/// `len`-byte resource, like `bytes 0-1023/4096`.
+fn content_range_matches(value: &str, start: u64, end: u64, len: u64) -> bool {
+    let Some((range, total)) = value
+        .strip_prefix("bytes ")
+        .and_then(|rest| rest.split_once('/'))
+    else {
+        return false;
+    };
+    let total_matches = total == "*" || total.parse() == Ok(len);
+    total_matches && range.split_once('-') == Some((&start.to_string(), &end.to_string()))
+}
+
+/// Concatenates `segments` in order into `output`.
+pub async fn merge_segments(
+    segments: &[PathBuf],
+    output: &mut (impl AsyncWrite + Unpin),
+) -> Result<()> {
+    let mut writer = BufWriter::new(output);
+
+    for segment in segments {
+        let mut file = tokio::fs::File::open(segment)
+            .await
+            .with_context(|| format!("Couldn't open {}", segment.display()))?;
+        tokio::io::copy(&mut file, &mut writer)
+            .await
+            .with_context(|| format!("Couldn't merge {}", segment.display()))?;
+    }
+
+    writer.flush().await?;
+    Ok(())
+}
+
+pub fn remove_segments(segments: &[PathBuf]) {
+    for segment in segments {
+        let _ = std::fs::remove_file(segment);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_the_content_range() {
+        assert!(content_range_matches("bytes 0-1023/4096", 0, 1023, 4096));
+        assert!(content_range_matches("bytes 1024-2047/*", 1024, 2047, 4096));
+        assert!(!content_range_matches("bytes 0-4095/4096", 0, 1023, 4096));
+        assert!(!content_range_matches("bytes 0-1023/8192", 0, 1023, 4096));
+        assert!(!content_range_matches("bytes */4096", 0, 1023, 4096));
+        assert!(!content_range_matches("0-1023/4096", 0, 1023, 4096));
+    }
+}
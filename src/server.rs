@@ -0,0 +1,350 @@
+//! `--server` mode: list and queue downloads over HTTP.
+//!
+//! Endpoints:
+//!
+//! - `GET /runs`: pending runs. Only those submitted since the last request
+//!   are fetched from speedrun.com, with every run fetched again
+//!   every [`FULL_REFRESH_INTERVAL`] to drop those moderated in the meantime.
+//! - `POST /download/{run_id}`: queue a run for download.
+//! - `GET /queue`: state of every queued download.
+//! - `DELETE /queue/{run_id}`: cancel a download that hasn't started yet.
+//!
+//! When a token is configured, every request must carry it as
+//! `Authorization: Bearer <token>`.
+//!
+//! Ctrl+C and SIGTERM stop the server: requests in flight are answered, the
+//! download in progress is stopped and nothing queued is started.
+
+use std::{
+    sync::{atomic::AtomicBool, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::{
+    api_client, config::Config, download_run, get_all_pending_runs_since, latest_submission,
+    moderation, shutdown_requested, DownloadStatus, Run, Skipped,
+};
+
+/// How long the pending runs are only fetched incrementally.
+const FULL_REFRESH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case", tag = "state", content = "error")]
+enum QueueState {
+    Queued,
+    Downloading,
+    Done,
+    Failed(String),
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct QueueItem {
+    run: Run,
+    #[serde(flatten)]
+    state: QueueState,
+}
+
+/// The pending runs as last fetched.
+#[derive(Default)]
+struct RunCache {
+    runs: Vec<Run>,
+    /// When the most recent of `runs` was submitted.
+    last_seen: Option<DateTime<Utc>>,
+    /// When every run was last fetched.
+    full_refresh: Option<Instant>,
+}
+
+struct AppState {
+    cfg: Arc<Config>,
+    client: reqwest::Client,
+    runs: Mutex<RunCache>,
+    queue: Mutex<Vec<QueueItem>>,
+    tx: mpsc::UnboundedSender<String>,
+}
+
+type SharedState = Arc<AppState>;
+
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        ApiError(StatusCode::BAD_GATEWAY, format!("{e:#}"))
+    }
+}
+
+pub async fn serve(cfg: Config, status: Arc<DownloadStatus>, done: Arc<AtomicBool>) -> Result<()> {
+    let cfg = Arc::new(cfg);
+    let (tx, rx) = mpsc::unbounded_channel();
+    let state = Arc::new(AppState {
+        cfg: Arc::clone(&cfg),
+        client: api_client(&cfg).await?,
+        runs: Mutex::new(RunCache::default()),
+        queue: Mutex::new(Vec::new()),
+        tx,
+    });
+
+    let worker = tokio::spawn(download_worker(Arc::clone(&state), rx, status, done));
+
+    let app = Router::new()
+        .route("/runs", get(list_runs))
+        .route("/download/{run_id}", post(queue_download))
+        .route("/queue", get(list_queue))
+        .route("/queue/{run_id}", delete(cancel_download))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            authenticate,
+        ))
+        .with_state(state);
+
+    let addr = (cfg.server_host.as_str(), cfg.port);
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Couldn't bind to {}:{}", cfg.server_host, cfg.port))?;
+
+    println!("Listening on http://{}", listener.local_addr()?);
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_requested())
+        .await?;
+
+    // The download in progress was stopped with the rest, let it clean up.
+    if worker.await.is_err() {
+        tracing::warn!("Download worker panicked");
+    }
+
+    Ok(())
+}
+
+async fn authenticate(
+    State(state): State<SharedState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if let Some(token) = &state.cfg.server_token {
+        let authorized = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .is_some_and(|v| constant_time_eq(v.as_bytes(), token.as_bytes()));
+
+        if !authorized {
+            return Err(ApiError(StatusCode::UNAUTHORIZED, "Invalid token".into()));
+        }
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Compares `a` and `b` in a time that only depends on their lengths, so
+/// how long a wrong token takes doesn't tell how much of it was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn refresh_runs(state: &AppState) -> Result<Vec<Run>> {
+    let last_seen = {
+        let cache = state.runs.lock().unwrap();
+        match cache.full_refresh {
+            Some(at) if at.elapsed() < FULL_REFRESH_INTERVAL => cache.last_seen,
+            _ => None,
+        }
+    };
+
+    let mut runs =
+        get_all_pending_runs_since(&state.cfg.file.games, None, last_seen, &state.client).await?;
+    moderation::mark_banned(&mut runs, &state.cfg.file.banned_players);
+
+    let mut cache = state.runs.lock().unwrap();
+    if last_seen.is_none() {
+        cache.runs = runs;
+        cache.full_refresh = Some(Instant::now());
+    } else {
+        for run in runs {
+            if !cache.runs.iter().any(|cached| cached.run_id == run.run_id) {
+                cache.runs.push(run);
+            }
+        }
+    }
+    cache.last_seen = latest_submission(&cache.runs).or(cache.last_seen);
+    Ok(cache.runs.clone())
+}
+
+async fn list_runs(State(state): State<SharedState>) -> Result<Json<Vec<Run>>, ApiError> {
+    Ok(Json(refresh_runs(&state).await?))
+}
+
+async fn queue_download(
+    State(state): State<SharedState>,
+    Path(run_id): Path<String>,
+) -> Result<(StatusCode, Json<QueueItem>), ApiError> {
+    let cached = state
+        .runs
+        .lock()
+        .unwrap()
+        .runs
+        .iter()
+        .find(|run| run.run_id == run_id)
+        .cloned();
+
+    let run = match cached {
+        Some(run) => run,
+        None => refresh_runs(&state)
+            .await?
+            .into_iter()
+            .find(|run| run.run_id == run_id)
+            .ok_or_else(|| ApiError(StatusCode::NOT_FOUND, format!("No pending run {run_id}")))?,
+    };
+
+    let item = QueueItem {
+        run,
+        state: QueueState::Queued,
+    };
+    state.queue.lock().unwrap().push(item.clone());
+    state
+        .tx
+        .send(run_id)
+        .map_err(|_| ApiError(StatusCode::SERVICE_UNAVAILABLE, "Queue is closed".into()))?;
+
+    Ok((StatusCode::ACCEPTED, Json(item)))
+}
+
+async fn list_queue(State(state): State<SharedState>) -> Json<Vec<QueueItem>> {
+    Json(state.queue.lock().unwrap().clone())
+}
+
+async fn cancel_download(
+    State(state): State<SharedState>,
+    Path(run_id): Path<String>,
+) -> Result<Json<QueueItem>, ApiError> {
+    let mut queue = state.queue.lock().unwrap();
+    let mut items = queue
+        .iter_mut()
+        .filter(|item| item.run.run_id == run_id)
+        .peekable();
+
+    if items.peek().is_none() {
+        return Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!("Run {run_id} isn't queued"),
+        ));
+    }
+
+    let item = items
+        .find(|item| matches!(item.state, QueueState::Queued))
+        .ok_or_else(|| {
+            ApiError(
+                StatusCode::CONFLICT,
+                format!("Run {run_id} already started"),
+            )
+        })?;
+
+    item.state = QueueState::Cancelled;
+    Ok(Json(item.clone()))
+}
+
+fn set_state(state: &AppState, run_id: &str, from: fn(&QueueState) -> bool, to: QueueState) {
+    if let Some(item) = state
+        .queue
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .find(|item| item.run.run_id == run_id && from(&item.state))
+    {
+        item.state = to;
+    }
+}
+
+async fn download_worker(
+    state: SharedState,
+    mut rx: mpsc::UnboundedReceiver<String>,
+    status: Arc<DownloadStatus>,
+    done: Arc<AtomicBool>,
+) {
+    loop {
+        let run_id = tokio::select! {
+            biased;
+            () = shutdown_requested() => return,
+            run_id = rx.recv() => match run_id {
+                Some(run_id) => run_id,
+                None => return,
+            },
+        };
+
+        let run = {
+            let mut queue = state.queue.lock().unwrap();
+            let Some(item) = queue
+                .iter_mut()
+                .find(|item| item.run.run_id == run_id && matches!(item.state, QueueState::Queued))
+            else {
+                continue;
+            };
+            item.state = QueueState::Downloading;
+            item.run.clone()
+        };
+
+        // The download pipeline blocks on child processes, keep it off the
+        // runtime's worker threads.
+        let result = tokio::task::spawn_blocking({
+            let cfg = Arc::clone(&state.cfg);
+            let status = Arc::clone(&status);
+            let done = Arc::clone(&done);
+            let handle = tokio::runtime::Handle::current();
+            move || handle.block_on(download_run(&run, &cfg, &status, &done))
+        })
+        .await
+        .context("Download task failed")
+        .and_then(|r| r);
+
+        let new_state = match result {
+            Ok(path) => {
+                println!("Downloaded {run_id} to {}", path.display());
+                QueueState::Done
+            }
+            Err(e) if e.is::<Skipped>() => QueueState::Done,
+            Err(e) => {
+                tracing::error!("Download of {run_id} failed: {e:?}");
+                QueueState::Failed(format!("{e:#}"))
+            }
+        };
+        set_state(
+            &state,
+            &run_id,
+            |s| matches!(s, QueueState::Downloading),
+            new_state,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_tokens() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secre"));
+        assert!(!constant_time_eq(b"", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
@@ -0,0 +1,177 @@
+//! `--write-metadata`: a `<filename>.json` next to each download with the run
+//! it came from and the exact yt-dlp and ffmpeg command lines, so a download
+//! that goes wrong can be reproduced by hand.
+
+use std::{
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::process::Command;
+
+use crate::Run;
+
+/// Arguments whose value is a secret, left out of the sidecar.
+const SECRET_ARGS: &[&str] = &["--cookies", "--api-key"];
+
+const REDACTED: &str = "<redacted>";
+
+/// The command lines of a download, program first.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Commands {
+    /// Empty for segmented downloads, which don't go through yt-dlp.
+    pub ytdlp_args: Vec<String>,
+    pub ffmpeg_args: Vec<String>,
+    /// The ffmpeg passes run on the finished download, like
+    /// `--burn-subtitles`' transcode.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub postprocess_args: Vec<Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct Sidecar<'a> {
+    #[serde(flatten)]
+    run: &'a Run,
+    downloaded_at: DateTime<Utc>,
+    #[serde(flatten)]
+    commands: &'a Commands,
+    /// The `--measure-quality` score, when it was measured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vmaf: Option<f64>,
+}
+
+/// The program and arguments `cmd` will run with, with the values of
+/// [`SECRET_ARGS`] redacted.
+pub fn extract_args(cmd: &Command) -> Vec<String> {
+    extract_std_args(cmd.as_std())
+}
+
+/// [`extract_args`], for commands run without tokio.
+pub fn extract_std_args(cmd: &std::process::Command) -> Vec<String> {
+    let mut args = vec![cmd.get_program().to_string_lossy().into_owned()];
+    let mut redact_next = false;
+
+    for arg in cmd.get_args().map(OsStr::to_string_lossy) {
+        if redact_next {
+            args.push(REDACTED.to_string());
+            redact_next = false;
+            continue;
+        }
+
+        match arg.split_once('=') {
+            Some((name, _)) if SECRET_ARGS.contains(&name) => {
+                args.push(format!("{name}={REDACTED}"));
+            }
+            _ => {
+                redact_next = SECRET_ARGS.contains(&arg.as_ref());
+                args.push(arg.into_owned());
+            }
+        }
+    }
+
+    args
+}
+
+/// Writes the sidecar of the download at `output_path`, and returns its path.
+pub fn write(
+    run: &Run,
+    commands: &Commands,
+    vmaf: Option<f64>,
+    output_path: &Path,
+) -> Result<PathBuf> {
+    let path = output_path.with_extension("json");
+    let sidecar = Sidecar {
+        run,
+        downloaded_at: Utc::now(),
+        commands,
+        vmaf,
+    };
+    let json = serde_json::to_string_pretty(&sidecar)?;
+    fs::write(&path, json).with_context(|| format!("Couldn't write {}", path.display()))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_secret_values() {
+        let mut cmd = Command::new("yt-dlp");
+        cmd.args(["https://example.test/v", "--cookies", "cookies.txt", "-q"])
+            .args(["--api-key=hunter2", "-o", "-"]);
+
+        assert_eq!(
+            extract_args(&cmd),
+            [
+                "yt-dlp",
+                "https://example.test/v",
+                "--cookies",
+                REDACTED,
+                "-q",
+                "--api-key=<redacted>",
+                "-o",
+                "-"
+            ]
+        );
+    }
+
+    #[test]
+    fn includes_the_vmaf_score() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("run.mp4");
+        let read = |path: PathBuf| -> serde_json::Value {
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+        };
+
+        let path = write(&Run::default(), &Commands::default(), Some(93.5), &output).unwrap();
+        assert_eq!(path, dir.path().join("run.json"));
+        assert_eq!(read(path)["vmaf"], 93.5);
+
+        let path = write(&Run::default(), &Commands::default(), None, &output).unwrap();
+        assert!(read(path).get("vmaf").is_none());
+    }
+
+    #[test]
+    fn lists_the_postprocessing_passes() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("run.mp4");
+        let read = |commands: &Commands| -> serde_json::Value {
+            let path = write(&Run::default(), commands, None, &output).unwrap();
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+        };
+
+        let mut commands = Commands::default();
+        assert!(read(&commands).get("postprocess_args").is_none());
+
+        let mut burn = std::process::Command::new("ffmpeg");
+        burn.args(["-i", "run.mkv", "-vf", "subtitles=run.mkv", "run.mp4"]);
+        commands.postprocess_args.push(extract_std_args(&burn));
+        assert_eq!(
+            read(&commands)["postprocess_args"],
+            serde_json::json!([[
+                "ffmpeg",
+                "-i",
+                "run.mkv",
+                "-vf",
+                "subtitles=run.mkv",
+                "run.mp4"
+            ]])
+        );
+    }
+
+    #[test]
+    fn keeps_other_arguments() {
+        let mut cmd = Command::new("ffmpeg");
+        cmd.args(["-i", "pipe:", "--cookies-from-browser", "chrome"]);
+
+        assert_eq!(
+            extract_args(&cmd),
+            ["ffmpeg", "-i", "pipe:", "--cookies-from-browser", "chrome"]
+        );
+    }
+}
@@ -0,0 +1,263 @@
+//! Totals printed when the session ends, and the download history kept
+//! across sessions for `dlvod stats`.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write,
+    fs::{self, OpenOptions},
+    io::Write as _,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use bytesize::ByteSize;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::format_time;
+
+const BYTES_PER_MB: f64 = 1_000_000.0;
+
+/// Counters shared by every download of the session, sequential, parallel or
+/// queued through the HTTP API.
+#[derive(Default)]
+pub struct SessionStats {
+    pub fetched: AtomicUsize,
+    pub downloaded: AtomicUsize,
+    pub failed: AtomicUsize,
+    pub skipped: AtomicUsize,
+    pub bytes_written: AtomicU64,
+    encode_millis: AtomicU64,
+    /// Length of the transcoded media, for the average speed.
+    media_millis: AtomicU64,
+}
+
+pub static SESSION_STATS: SessionStats = SessionStats {
+    fetched: AtomicUsize::new(0),
+    downloaded: AtomicUsize::new(0),
+    failed: AtomicUsize::new(0),
+    skipped: AtomicUsize::new(0),
+    bytes_written: AtomicU64::new(0),
+    encode_millis: AtomicU64::new(0),
+    media_millis: AtomicU64::new(0),
+};
+
+impl SessionStats {
+    /// Counts `elapsed` spent downloading and transcoding `media` worth of VOD.
+    pub fn record_encode(&self, elapsed: Duration, media: Duration) {
+        self.encode_millis
+            .fetch_add(elapsed.as_millis() as u64, Ordering::Relaxed);
+        self.media_millis
+            .fetch_add(media.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.fetched.load(Ordering::Relaxed) == 0
+            && self.downloaded.load(Ordering::Relaxed) == 0
+            && self.failed.load(Ordering::Relaxed) == 0
+            && self.skipped.load(Ordering::Relaxed) == 0
+    }
+
+    /// The summary table, or `None` if the session did nothing worth one.
+    pub fn summary(&self) -> Option<String> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let encode = Duration::from_millis(self.encode_millis.load(Ordering::Relaxed));
+        let media = Duration::from_millis(self.media_millis.load(Ordering::Relaxed));
+        let speed = if encode.is_zero() {
+            "-".to_string()
+        } else {
+            format!("{:.2}x", media.as_secs_f64() / encode.as_secs_f64())
+        };
+
+        let rows = [
+            (
+                "Runs fetched",
+                self.fetched.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "Downloaded",
+                self.downloaded.load(Ordering::Relaxed).to_string(),
+            ),
+            ("Failed", self.failed.load(Ordering::Relaxed).to_string()),
+            ("Skipped", self.skipped.load(Ordering::Relaxed).to_string()),
+            (
+                "Bytes written",
+                ByteSize(self.bytes_written.load(Ordering::Relaxed)).to_string(),
+            ),
+            ("Encoding time", format_time(encode)),
+            ("Average speed", speed),
+        ];
+
+        let mut summary = String::from("Session summary\n");
+        for (label, value) in rows {
+            let _ = writeln!(summary, "  {label:<16}{value:>12}");
+        }
+        Some(summary)
+    }
+}
+
+/// Prints the summary table of `stats`, if there's anything in it.
+pub fn print_session_summary(stats: &SessionStats) {
+    if let Some(summary) = stats.summary() {
+        println!("\n{summary}");
+    }
+}
+
+/// One finished download, a line of the history file.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadRecord {
+    pub run_id: String,
+    pub finished: DateTime<Utc>,
+    /// What yt-dlp handed over, before transcoding.
+    pub bytes: u64,
+    pub seconds: f64,
+    /// The runner's account, to tell apart players sharing a name later.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub player_id: Option<String>,
+}
+
+impl DownloadRecord {
+    pub fn new(run_id: &str, player_id: Option<&str>, bytes: u64, elapsed: Duration) -> Self {
+        Self {
+            run_id: run_id.to_string(),
+            player_id: player_id.map(str::to_string),
+            finished: Utc::now(),
+            bytes,
+            seconds: elapsed.as_secs_f64(),
+        }
+    }
+
+    /// Average throughput, in MB/s.
+    pub fn speed(&self) -> f64 {
+        if self.seconds > 0.0 {
+            self.bytes as f64 / BYTES_PER_MB / self.seconds
+        } else {
+            0.0
+        }
+    }
+
+    pub fn report(&self) {
+        crate::progress::println(format_args!(
+            "Download speed: {:.2} MB/s, File size: {:.1} MB",
+            self.speed(),
+            self.bytes as f64 / BYTES_PER_MB
+        ));
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    Some(dirs::data_local_dir()?.join("dlvod").join("history.jsonl"))
+}
+
+/// Appends `record` to the history. The history is only informative, failing
+/// to write it is a warning.
+pub fn record_download(record: &DownloadRecord) {
+    let Some(path) = history_path() else {
+        return;
+    };
+
+    let appended = (|| -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        writeln!(file, "{}", serde_json::to_string(record)?)?;
+        Ok(())
+    })();
+
+    if let Err(e) = appended {
+        tracing::warn!("Couldn't write to {}: {e:#}", path.display());
+    }
+}
+
+fn load_history() -> Result<Vec<DownloadRecord>> {
+    let Some(path) = history_path() else {
+        return Ok(Vec::new());
+    };
+
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Couldn't read {}", path.display())),
+    };
+
+    // A line cut short by a crash shouldn't hide the rest.
+    Ok(text
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// Accounts of the players downloaded in past sessions, read once.
+pub fn known_player_ids() -> &'static HashSet<String> {
+    static KNOWN: OnceLock<HashSet<String>> = OnceLock::new();
+    KNOWN.get_or_init(|| {
+        load_history()
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|record| record.player_id)
+            .collect()
+    })
+}
+
+/// `dlvod stats`: totals from the history, and with `show_speeds`, the
+/// average download speed per month.
+pub fn print_history(show_speeds: bool) -> Result<()> {
+    let records = load_history()?;
+    if records.is_empty() {
+        println!("No downloads recorded yet");
+        return Ok(());
+    }
+
+    let bytes: u64 = records.iter().map(|r| r.bytes).sum();
+    println!(
+        "{} downloads recorded, {} in total",
+        records.len(),
+        ByteSize(bytes)
+    );
+
+    if !show_speeds {
+        return Ok(());
+    }
+
+    let mut months: BTreeMap<String, (usize, u64, f64)> = BTreeMap::new();
+    for record in &records {
+        let month = months
+            .entry(record.finished.format("%Y-%m").to_string())
+            .or_default();
+        month.0 += 1;
+        month.1 += record.bytes;
+        month.2 += record.seconds;
+    }
+
+    let (slowest, fastest) = records
+        .iter()
+        .map(DownloadRecord::speed)
+        .fold((f64::MAX, 0f64), |(min, max), speed| {
+            (min.min(speed), max.max(speed))
+        });
+
+    println!(
+        "\n  {:<10}{:>10}{:>14}",
+        "Month", "Downloads", "Average MB/s"
+    );
+    for (month, (count, bytes, seconds)) in &months {
+        let speed = if *seconds > 0.0 {
+            *bytes as f64 / BYTES_PER_MB / seconds
+        } else {
+            0.0
+        };
+        println!("  {month:<10}{count:>10}{speed:>14.2}");
+    }
+    println!("\n  Slowest {slowest:.2} MB/s, fastest {fastest:.2} MB/s");
+
+    Ok(())
+}
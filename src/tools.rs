@@ -0,0 +1,319 @@
+//! Locations of the external programs dlvod runs.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::{api::ResponseExt, config::Config};
+
+struct Tools {
+    ffmpeg: PathBuf,
+    ffprobe: PathBuf,
+    ytdlp: PathBuf,
+}
+
+static TOOLS: OnceLock<Tools> = OnceLock::new();
+
+/// Oldest ffmpeg known to support every filter and option dlvod uses.
+pub const MIN_FFMPEG_VERSION: &str = "5.0";
+/// Oldest yt-dlp that still extracts VODs from the usual hosts.
+pub const MIN_YTDLP_VERSION: &str = "2023.07.06";
+
+/// Looks for `program` in the directories listed in `PATH`.
+pub fn find_on_path(program: &str) -> Option<PathBuf> {
+    let name = format!("{program}{}", env::consts::EXE_SUFFIX);
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(&name))
+        .find(|path| path.is_file())
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    path.metadata()
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+fn check_override(path: &Path, flag: &str) -> Result<PathBuf> {
+    if !is_executable(path) {
+        bail!("{flag} {} is not an executable file", path.display());
+    }
+    Ok(path.to_path_buf())
+}
+
+/// Records the programs to run, checking the ones given with `--ffmpeg-path`
+/// and `--ytdlp-path`. The rest are looked up in `PATH` when spawned.
+///
+/// A custom ffmpeg brings its own ffprobe, expected next to it.
+pub fn init(cfg: &Config) -> Result<()> {
+    let (ffmpeg, ffprobe) = match &cfg.ffmpeg_path {
+        Some(path) => {
+            let ffmpeg = check_override(path, "--ffmpeg-path")?;
+            let ffprobe = ffmpeg.with_file_name(format!("ffprobe{}", env::consts::EXE_SUFFIX));
+            (ffmpeg, ffprobe)
+        }
+        None => ("ffmpeg".into(), "ffprobe".into()),
+    };
+
+    let ytdlp = match &cfg.ytdlp_path {
+        Some(path) => check_override(path, "--ytdlp-path")?,
+        None => "yt-dlp".into(),
+    };
+
+    let _ = TOOLS.set(Tools {
+        ffmpeg,
+        ffprobe,
+        ytdlp,
+    });
+    Ok(())
+}
+
+fn tools() -> &'static Tools {
+    TOOLS.get_or_init(|| Tools {
+        ffmpeg: "ffmpeg".into(),
+        ffprobe: "ffprobe".into(),
+        ytdlp: "yt-dlp".into(),
+    })
+}
+
+pub fn ffmpeg() -> &'static Path {
+    &tools().ffmpeg
+}
+
+pub fn ffprobe() -> &'static Path {
+    &tools().ffprobe
+}
+
+pub fn ytdlp() -> &'static Path {
+    &tools().ytdlp
+}
+
+/// Arguments for every yt-dlp run that goes online: the `--doh` proxy, if any.
+pub fn ytdlp_network_args() -> Vec<String> {
+    #[cfg(feature = "doh")]
+    if let Some(proxy) = crate::doh::proxy_url() {
+        return vec!["--proxy".to_string(), proxy];
+    }
+    Vec::new()
+}
+
+/// Splits a version like `6.1.1-3ubuntu5`, `n6.1` or `2024.03.10` into its
+/// numeric components, ignoring any suffix.
+fn version_parts(version: &str) -> Option<Vec<u64>> {
+    let version = version.trim_start_matches(['n', 'v']);
+    let numeric = version
+        .split(|c: char| !c.is_ascii_digit() && c != '.')
+        .next()?;
+
+    numeric
+        .split('.')
+        .filter(|part| !part.is_empty())
+        .map(|part| part.parse().ok())
+        .collect::<Option<Vec<_>>>()
+        .filter(|parts| !parts.is_empty())
+}
+
+fn install_hint(cmd: &str) -> &'static str {
+    match cmd {
+        "ffmpeg" => {
+            "Install it from https://ffmpeg.org/download.html or your package manager, \
+             or point --ffmpeg-path to it."
+        }
+        _ => {
+            "Install it from https://github.com/yt-dlp/yt-dlp#installation \
+             (e.g. `pip install -U yt-dlp`), or point --ytdlp-path to it."
+        }
+    }
+}
+
+/// Runs `cmd` (`ffmpeg` or `yt-dlp`) to read its version.
+pub fn tool_version(cmd: &str) -> Result<String> {
+    let (path, flag) = match cmd {
+        "ffmpeg" => (ffmpeg(), "-version"),
+        "yt-dlp" => (ytdlp(), "--version"),
+        _ => bail!("Unknown dependency {cmd}"),
+    };
+    let install = install_hint(cmd);
+
+    let output = Command::new(path)
+        .arg(flag)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .with_context(|| format!("{cmd} is not installed. {install}"))?;
+
+    if !output.status.success() {
+        bail!("{cmd} {flag} failed: {}. {install}", output.status);
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let first_line = stdout.lines().next().unwrap_or_default();
+    let version = match cmd {
+        // "ffmpeg version 6.1.1-3ubuntu5 Copyright (c) ..."
+        "ffmpeg" => first_line
+            .split_whitespace()
+            .skip_while(|word| *word != "version")
+            .nth(1)
+            .unwrap_or_default(),
+        _ => first_line.trim(),
+    };
+
+    Ok(version.to_string())
+}
+
+/// Reads the version of `cmd` (`ffmpeg` or `yt-dlp`), failing if it's missing
+/// or older than `min_version`.
+///
+/// Versions that can't be compared, like ffmpeg's git snapshots, pass with a
+/// warning. Returns the version found.
+pub fn check_dependency(cmd: &str, min_version: &str) -> Result<String> {
+    let version = tool_version(cmd)?;
+
+    match (version_parts(&version), version_parts(min_version)) {
+        (Some(found), Some(min)) if found < min => {
+            bail!(
+                "{cmd} {version} is too old, {min_version} or newer is required. {}",
+                install_hint(cmd)
+            )
+        }
+        (Some(_), _) => {}
+        (None, _) => tracing::warn!("Couldn't tell whether {cmd} {version:?} is recent enough"),
+    }
+
+    tracing::debug!("Found {cmd} {version}");
+    Ok(version)
+}
+
+/// Prints the versions of dlvod and the programs it runs, for bug reports.
+/// Missing programs are reported as such rather than failing.
+pub fn print_versions(json: bool) {
+    let ffmpeg = tool_version("ffmpeg").ok();
+    let ytdlp = tool_version("yt-dlp").ok();
+
+    if json {
+        let versions = serde_json::json!({
+            "dlvod": env!("CARGO_PKG_VERSION"),
+            "ffmpeg": ffmpeg,
+            "yt-dlp": ytdlp,
+        });
+        println!("{versions}");
+        return;
+    }
+
+    let or_missing = |v: Option<String>| v.unwrap_or_else(|| "not found".to_string());
+    println!(
+        "dlvod: {} / ffmpeg: {} / yt-dlp: {}",
+        env!("CARGO_PKG_VERSION"),
+        or_missing(ffmpeg),
+        or_missing(ytdlp)
+    );
+}
+
+/// The latest stable yt-dlp release is looked up at most this often.
+const UPDATE_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Startup doesn't wait longer than this on GitHub.
+const UPDATE_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+const YTDLP_LATEST_RELEASE: &str = "https://api.github.com/repos/yt-dlp/yt-dlp/releases/latest";
+
+fn last_update_check() -> Option<PathBuf> {
+    Some(dirs::cache_dir()?.join("dlvod").join("last_update_check"))
+}
+
+fn checked_recently(path: &Path) -> bool {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|text| DateTime::parse_from_rfc3339(text.trim()).ok())
+        .and_then(|checked| (Utc::now() - checked.to_utc()).to_std().ok())
+        .is_some_and(|age| age < UPDATE_CHECK_INTERVAL)
+}
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+}
+
+/// Returns the installed and the latest stable yt-dlp versions, the latter
+/// only if it's newer.
+pub async fn ytdlp_update(client: &reqwest::Client) -> Result<(String, Option<String>)> {
+    let installed = tool_version("yt-dlp")?;
+    let latest = client
+        .get(YTDLP_LATEST_RELEASE)
+        .timeout(UPDATE_CHECK_TIMEOUT)
+        .send()
+        .await
+        .context("Requesting the latest yt-dlp release")?
+        .error_for_status()
+        .context("Requesting the latest yt-dlp release")?
+        .json_limited::<Release>()
+        .await
+        .context("Couldn't parse the latest yt-dlp release")?
+        .tag_name;
+
+    let newer = match (version_parts(&installed), version_parts(&latest)) {
+        (Some(installed), Some(latest)) => latest > installed,
+        _ => false,
+    };
+    Ok((installed, newer.then_some(latest)))
+}
+
+/// Warns if a newer yt-dlp is out, checking once a day at most. Skipped in CI,
+/// where nobody would read it.
+pub async fn warn_if_ytdlp_outdated(client: &reqwest::Client) {
+    if env::var_os("CI").is_some() {
+        return;
+    }
+
+    let cache = last_update_check();
+    if cache.as_deref().is_some_and(checked_recently) {
+        return;
+    }
+
+    match ytdlp_update(client).await {
+        Ok((installed, Some(latest))) => tracing::warn!(
+            "yt-dlp {installed} is outdated, {latest} is available. {}",
+            install_hint("yt-dlp")
+        ),
+        Ok((_, None)) => {}
+        // Don't record the check, try again next time.
+        Err(e) => {
+            tracing::debug!("Couldn't check for yt-dlp updates: {e:#}");
+            return;
+        }
+    }
+
+    if let Some(path) = cache {
+        let written = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| fs::write(&path, Utc::now().to_rfc3339()));
+        if let Err(e) = written {
+            tracing::debug!("Couldn't write {}: {e}", path.display());
+        }
+    }
+}
+
+/// `dlvod check-updates`: looks for a newer yt-dlp right away.
+pub async fn check_updates(client: &reqwest::Client) -> Result<()> {
+    match ytdlp_update(client).await? {
+        (installed, Some(latest)) => println!(
+            "yt-dlp {installed} is outdated, {latest} is available. {}",
+            install_hint("yt-dlp")
+        ),
+        (installed, None) => println!("yt-dlp {installed} is up to date"),
+    }
+    Ok(())
+}
@@ -0,0 +1,745 @@
+//! Full-screen run picker.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use image::DynamicImage;
+use ratatui::{
+    crossterm::{
+        event::{
+            self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyEventKind,
+            KeyModifiers, MouseButton, MouseEvent, MouseEventKind,
+        },
+        execute,
+    },
+    layout::{Constraint, Layout, Position, Rect},
+    style::{Color, Modifier, Style},
+    text::Line,
+    widgets::{Block, Borders, Clear, Paragraph, Row, Table, TableState, Wrap},
+    DefaultTerminal, Frame,
+};
+use ratatui_image::{picker::Picker, protocol::StatefulProtocol, StatefulImage};
+use tokio::sync::mpsc;
+
+use crate::{
+    api::ResponseExt, fetch_category_rules, fetch_player_runs, preview_run, tools::find_on_path,
+    Run, RunSummary, SHUTTING_DOWN,
+};
+
+/// Lines of notes shown under the table before scrolling.
+const NOTES_LINES: u16 = 3;
+
+/// Width of the details sidebar, cover art included.
+const SIDEBAR_WIDTH: u16 = 28;
+
+/// Rows the cover art takes at the top of the sidebar.
+const COVER_HEIGHT: u16 = 12;
+
+/// Two clicks on the same row within this interval count as a double-click.
+const DOUBLE_CLICK_INTERVAL: Duration = Duration::from_millis(400);
+
+/// How long to wait for input before redrawing anyway, to show cover art
+/// that came in meanwhile and notice SIGTERM.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Details shown over the table until dismissed.
+struct Popup {
+    title: String,
+    body: PopupBody,
+    scroll: u16,
+}
+
+enum PopupBody {
+    Text(String),
+    Runs(Vec<RunSummary>),
+}
+
+struct App<'a> {
+    runs: &'a [Run],
+    /// Indices into `runs` that match the filter, in display order.
+    visible: Vec<usize>,
+    selected: BTreeSet<usize>,
+    table: TableState,
+    filter: String,
+    filtering: bool,
+    /// Where the table was last drawn, to map clicks to rows.
+    table_area: Rect,
+    last_click: Option<(usize, Instant)>,
+    /// Shown in the status bar until the next key press.
+    message: Option<String>,
+    popup: Option<Popup>,
+    /// How far the notes panel is scrolled, and for which run.
+    notes_scroll: (Option<usize>, u16),
+    /// Kept alive because on X11 the copied text goes away with it.
+    clipboard: Option<arboard::Clipboard>,
+    /// Draws images with whatever the terminal supports, down to half blocks.
+    picker: Picker,
+    /// Cover art by game ID, `None` while it's being fetched or where it
+    /// couldn't be.
+    covers: HashMap<String, Option<StatefulProtocol>>,
+    /// Cover art fetched in the background, by game ID.
+    fetched_covers: mpsc::UnboundedReceiver<(String, DynamicImage)>,
+    covers_tx: mpsc::UnboundedSender<(String, DynamicImage)>,
+}
+
+enum Outcome {
+    Continue,
+    Preview(usize),
+    Rules(usize),
+    PlayerRuns(usize),
+    Confirm,
+    Quit,
+}
+
+impl<'a> App<'a> {
+    fn new(runs: &'a [Run], picker: Picker) -> Self {
+        let (covers_tx, fetched_covers) = mpsc::unbounded_channel();
+        let mut app = Self {
+            runs,
+            visible: Vec::new(),
+            selected: BTreeSet::new(),
+            table: TableState::default(),
+            filter: String::new(),
+            filtering: false,
+            table_area: Rect::default(),
+            last_click: None,
+            message: None,
+            popup: None,
+            notes_scroll: (None, 0),
+            clipboard: None,
+            picker,
+            covers: HashMap::new(),
+            fetched_covers,
+            covers_tx,
+        };
+        app.apply_filter();
+        app
+    }
+
+    fn matches(&self, run: &Run) -> bool {
+        let filter = self.filter.to_lowercase();
+        [&run.game_name, &run.cat_full, &run.player]
+            .iter()
+            .any(|field| field.to_lowercase().contains(&filter))
+    }
+
+    fn apply_filter(&mut self) {
+        self.visible = (0..self.runs.len())
+            .filter(|&i| self.matches(&self.runs[i]))
+            .collect();
+
+        let row = match self.table.selected() {
+            _ if self.visible.is_empty() => None,
+            Some(row) => Some(row.min(self.visible.len() - 1)),
+            None => Some(0),
+        };
+        self.table.select(row);
+    }
+
+    fn highlighted(&self) -> Option<usize> {
+        self.table
+            .selected()
+            .and_then(|row| self.visible.get(row).copied())
+    }
+
+    fn select_previous(&mut self) {
+        self.table.select_previous();
+    }
+
+    fn select_next(&mut self) {
+        // TableState doesn't know the row count, so clamp here.
+        let last = self.visible.len().saturating_sub(1);
+        let row = self.table.selected().map_or(0, |row| (row + 1).min(last));
+        self.table.select(Some(row));
+    }
+
+    fn toggle(&mut self, i: usize) {
+        if !self.selected.remove(&i) {
+            self.selected.insert(i);
+        }
+    }
+
+    /// Maps a click position to a row of `visible`, accounting for the border,
+    /// the header and how far the table is scrolled.
+    fn row_at(&self, column: u16, row: u16) -> Option<usize> {
+        let first_row = self.table_area.y + 2;
+        if !self.table_area.contains(Position::new(column, row)) || row < first_row {
+            return None;
+        }
+
+        let row = (row - first_row) as usize + self.table.offset();
+        (row < self.visible.len()).then_some(row)
+    }
+
+    fn handle_mouse(&mut self, mouse: MouseEvent) -> Outcome {
+        if let Some(popup) = &mut self.popup {
+            match mouse.kind {
+                MouseEventKind::ScrollUp => popup.scroll = popup.scroll.saturating_sub(1),
+                MouseEventKind::ScrollDown => popup.scroll = popup.scroll.saturating_add(1),
+                _ => {}
+            }
+            return Outcome::Continue;
+        }
+
+        match mouse.kind {
+            MouseEventKind::ScrollUp => self.select_previous(),
+            MouseEventKind::ScrollDown => self.select_next(),
+            MouseEventKind::Down(MouseButton::Left) => {
+                let Some(row) = self.row_at(mouse.column, mouse.row) else {
+                    return Outcome::Continue;
+                };
+                let i = self.visible[row];
+                self.table.select(Some(row));
+
+                let double_click = self
+                    .last_click
+                    .is_some_and(|(last, at)| last == row && at.elapsed() < DOUBLE_CLICK_INTERVAL);
+
+                if double_click {
+                    self.last_click = None;
+                    self.selected.insert(i);
+                    return Outcome::Confirm;
+                }
+
+                self.last_click = Some((row, Instant::now()));
+                self.toggle(i);
+            }
+            _ => {}
+        }
+
+        Outcome::Continue
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Outcome {
+        self.message = None;
+
+        if let Some(popup) = &mut self.popup {
+            match key.code {
+                KeyCode::Esc | KeyCode::Enter => self.popup = None,
+                KeyCode::Up | KeyCode::Char('k') => popup.scroll = popup.scroll.saturating_sub(1),
+                KeyCode::Down | KeyCode::Char('j') => popup.scroll = popup.scroll.saturating_add(1),
+                KeyCode::PageUp => popup.scroll = popup.scroll.saturating_sub(10),
+                KeyCode::PageDown => popup.scroll = popup.scroll.saturating_add(10),
+                _ => {}
+            }
+            return Outcome::Continue;
+        }
+
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            return Outcome::Quit;
+        }
+
+        if self.filtering {
+            match key.code {
+                KeyCode::Enter => self.filtering = false,
+                KeyCode::Esc => {
+                    self.filtering = false;
+                    self.filter.clear();
+                    self.apply_filter();
+                }
+                KeyCode::Backspace => {
+                    self.filter.pop();
+                    self.apply_filter();
+                }
+                KeyCode::Char(c) => {
+                    self.filter.push(c);
+                    self.apply_filter();
+                }
+                _ => {}
+            }
+            return Outcome::Continue;
+        }
+
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => self.select_previous(),
+            KeyCode::Down | KeyCode::Char('j') => self.select_next(),
+            KeyCode::Char(' ') => {
+                if let Some(i) = self.highlighted() {
+                    self.toggle(i);
+                }
+            }
+            KeyCode::Char('/') => self.filtering = true,
+            KeyCode::PageUp => self.notes_scroll.1 = self.notes_scroll.1.saturating_sub(1),
+            KeyCode::PageDown => self.notes_scroll.1 = self.notes_scroll.1.saturating_add(1),
+            KeyCode::Char('p' | 'P') => {
+                if let Some(i) = self.highlighted() {
+                    return Outcome::Preview(i);
+                }
+            }
+            KeyCode::Char('r' | 'R') => {
+                if let Some(i) = self.highlighted() {
+                    return Outcome::Rules(i);
+                }
+            }
+            KeyCode::Char('f' | 'F') => {
+                if let Some(i) = self.highlighted() {
+                    self.message = Some(self.open_url(&self.runs[i].permalink));
+                }
+            }
+            KeyCode::Char('u' | 'U') => {
+                if let Some(i) = self.highlighted() {
+                    return Outcome::PlayerRuns(i);
+                }
+            }
+            KeyCode::Enter => return Outcome::Confirm,
+            KeyCode::Esc | KeyCode::Char('q') => return Outcome::Quit,
+            _ => {}
+        }
+
+        Outcome::Continue
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let highlighted = self.highlighted();
+        if self.notes_scroll.0 != highlighted {
+            self.notes_scroll = (highlighted, 0);
+        }
+
+        let notes = highlighted.and_then(|i| self.runs[i].notes.as_deref());
+        let notes_height = if notes.is_some() { NOTES_LINES + 2 } else { 0 };
+
+        let [main_area, notes_area, status_area] = Layout::vertical([
+            Constraint::Min(1),
+            Constraint::Length(notes_height),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [table_area, sidebar_area] =
+            Layout::horizontal([Constraint::Min(1), Constraint::Length(SIDEBAR_WIDTH)])
+                .areas(main_area);
+
+        let header = Row::new(["", "Game", "Category", "", "Player", "Time", "Submitted"])
+            .style(Style::new().add_modifier(Modifier::BOLD));
+
+        let rows = self.visible.iter().map(|&i| {
+            let run = &self.runs[i];
+            let mark = if self.selected.contains(&i) {
+                "[x]"
+            } else {
+                "[ ]"
+            };
+            let submitted = run
+                .submitted
+                .map(|d| d.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default();
+
+            let mut player = run.player.clone();
+            if run.banned {
+                player.push_str(" [BANNED]");
+            }
+            if run.vod_missing {
+                player.push_str(" [NO VOD]");
+            }
+            let style = if run.banned {
+                Style::new().fg(Color::Red)
+            } else {
+                Style::new()
+            };
+
+            Row::new([
+                mark.to_string(),
+                run.game_name.clone(),
+                run.cat_full.clone(),
+                run.country_label().unwrap_or_default(),
+                player,
+                if run.would_be_wr {
+                    format!("{} #1", run.time)
+                } else {
+                    run.time.clone()
+                },
+                submitted,
+            ])
+            .style(style)
+        });
+
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(3),
+                Constraint::Fill(2),
+                Constraint::Fill(3),
+                Constraint::Length(2),
+                Constraint::Fill(1),
+                Constraint::Length(15),
+                Constraint::Length(16),
+            ],
+        )
+        .header(header)
+        .block(
+            Block::new()
+                .borders(Borders::ALL)
+                .title(" Pending runs ")
+                .title_bottom(
+                    highlighted
+                        .map(|i| format!(" {} ", self.runs[i].permalink))
+                        .unwrap_or_default(),
+                ),
+        )
+        .row_highlight_style(Style::new().bg(Color::DarkGray))
+        .highlight_symbol("> ");
+
+        frame.render_stateful_widget(table, table_area, &mut self.table);
+        self.table_area = table_area;
+
+        if let Some(i) = highlighted {
+            self.draw_sidebar(frame, i, sidebar_area);
+        }
+
+        if let Some(notes) = notes {
+            self.draw_notes(frame, notes, notes_area);
+        }
+
+        let status = if let Some(message) = &self.message {
+            Line::from(format!(" {message}"))
+        } else if self.filtering {
+            Line::from(format!("/{}", self.filter))
+        } else {
+            let filter = if self.filter.is_empty() {
+                String::new()
+            } else {
+                format!(" | filter: {}", self.filter)
+            };
+            Line::from(format!(
+                " {} selected | {}/{} runs{filter} | ↑↓ move  Space select  Enter confirm  / filter  P preview  R rules  U player  F forum  q quit",
+                self.selected.len(),
+                self.visible.len(),
+                self.runs.len(),
+            ))
+        };
+
+        frame.render_widget(
+            Paragraph::new(status).style(Style::new().reversed()),
+            status_area,
+        );
+
+        if let Some(popup) = &self.popup {
+            let area = frame
+                .area()
+                .centered(Constraint::Percentage(80), Constraint::Percentage(80));
+            let block = Block::new()
+                .borders(Borders::ALL)
+                .title(format!(" {} ", popup.title))
+                .title_bottom(" ↑↓ scroll  Esc close ");
+
+            frame.render_widget(Clear, area);
+            match &popup.body {
+                PopupBody::Text(text) => frame.render_widget(
+                    Paragraph::new(text.as_str())
+                        .wrap(Wrap { trim: false })
+                        .scroll((popup.scroll, 0))
+                        .block(block),
+                    area,
+                ),
+                PopupBody::Runs(runs) => {
+                    let header = Row::new(["Game", "Category", "Time", "Date"])
+                        .style(Style::new().add_modifier(Modifier::BOLD));
+                    let rows = runs.iter().skip(popup.scroll as usize).map(|run| {
+                        Row::new([
+                            run.game.as_str(),
+                            run.category.as_str(),
+                            run.time.as_str(),
+                            run.date.as_str(),
+                        ])
+                    });
+                    let widths = [
+                        Constraint::Fill(2),
+                        Constraint::Fill(2),
+                        Constraint::Length(12),
+                        Constraint::Length(10),
+                    ];
+
+                    frame.render_widget(Table::new(rows, widths).header(header).block(block), area);
+                }
+            }
+        }
+    }
+
+    /// Cover art on top, then what doesn't fit in the table.
+    fn draw_sidebar(&mut self, frame: &mut Frame, i: usize, area: Rect) {
+        let run = &self.runs[i];
+        let block = Block::new().borders(Borders::ALL).title(" Details ");
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+
+        let cover = self.covers.get_mut(&run.game_id).and_then(Option::as_mut);
+        let cover_height = if cover.is_some() { COVER_HEIGHT } else { 0 };
+        let [cover_area, details_area] =
+            Layout::vertical([Constraint::Length(cover_height), Constraint::Min(0)]).areas(inner);
+
+        if let Some(cover) = cover {
+            frame.render_stateful_widget(StatefulImage::default(), cover_area, cover);
+        }
+
+        let mut details = vec![
+            Line::from(run.game_name.as_str()).style(Style::new().add_modifier(Modifier::BOLD))
+        ];
+        details.extend(run.platform.as_deref().map(Line::from));
+        details.extend(run.region.as_deref().map(Line::from));
+        details.extend(
+            run.variables
+                .iter()
+                .map(|(name, label)| Line::from(format!("{name}: {label}"))),
+        );
+
+        frame.render_widget(
+            Paragraph::new(details).wrap(Wrap { trim: false }),
+            details_area,
+        );
+    }
+
+    /// Starts fetching the cover art of the highlighted run's game, once per
+    /// game, and takes in the covers fetched since the last frame.
+    fn load_cover(&mut self, client: &reqwest::Client) {
+        while let Ok((game_id, image)) = self.fetched_covers.try_recv() {
+            let cover = self.picker.new_resize_protocol(image);
+            self.covers.insert(game_id, Some(cover));
+        }
+
+        let Some(run) = self.highlighted().map(|i| &self.runs[i]) else {
+            return;
+        };
+        if self.covers.contains_key(&run.game_id) {
+            return;
+        }
+        self.covers.insert(run.game_id.clone(), None);
+        let Some(uri) = run.cover_uri.clone() else {
+            return;
+        };
+
+        let client = client.clone();
+        let game_id = run.game_id.clone();
+        let tx = self.covers_tx.clone();
+        tokio::spawn(async move {
+            let image = async {
+                let bytes = client
+                    .get(&uri)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .bytes_limited()
+                    .await?;
+                image::load_from_memory(&bytes).context("Couldn't decode the cover art")
+            };
+            // A missing cover isn't worth interrupting for.
+            if let Ok(image) = image.await {
+                let _ = tx.send((game_id, image));
+            }
+        });
+    }
+
+    fn draw_notes(&mut self, frame: &mut Frame, notes: &str, area: Rect) {
+        // Roughly how many lines the notes wrap to, to know whether there's more.
+        let width = area.width.saturating_sub(2).max(1) as usize;
+        let lines = notes
+            .lines()
+            .map(|line| line.chars().count().div_ceil(width).max(1))
+            .sum::<usize>();
+        let max_scroll = lines.saturating_sub(NOTES_LINES as usize) as u16;
+        let scroll = self.notes_scroll.1.min(max_scroll);
+        self.notes_scroll.1 = scroll;
+
+        let mut block = Block::new().borders(Borders::ALL).title(" Notes ");
+        if scroll < max_scroll {
+            block = block.title_bottom(" more… (PgDn) ");
+        }
+
+        frame.render_widget(
+            Paragraph::new(notes)
+                .wrap(Wrap { trim: false })
+                .scroll((scroll, 0))
+                .block(block),
+            area,
+        );
+    }
+
+    fn run(
+        mut self,
+        terminal: &mut DefaultTerminal,
+        client: &reqwest::Client,
+        done: &Arc<AtomicBool>,
+    ) -> Result<Vec<usize>> {
+        loop {
+            self.load_cover(client);
+            terminal.draw(|frame| self.draw(frame))?;
+
+            // Ctrl+C is a key press here, only SIGTERM sets this.
+            if SHUTTING_DOWN.load(Ordering::SeqCst) {
+                return Ok(Vec::new());
+            }
+            if !event::poll(REDRAW_INTERVAL)? {
+                continue;
+            }
+            let outcome = match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => self.handle_key(key),
+                Event::Mouse(mouse) => self.handle_mouse(mouse),
+                _ => continue,
+            };
+
+            match outcome {
+                Outcome::Continue => {}
+                Outcome::Preview(i) => self.preview(terminal, &self.runs[i], done)?,
+                Outcome::Rules(i) => {
+                    let run = &self.runs[i];
+                    let fetched = self.fetch(
+                        terminal,
+                        "rules",
+                        fetch_category_rules(&run.category_id, client),
+                    )?;
+
+                    if let Some(rules) = fetched {
+                        self.popup = Some(Popup {
+                            title: format!("{} - {} rules", run.game_name, run.cat_full),
+                            body: PopupBody::Text(if rules.is_empty() {
+                                "This category has no rules.".to_string()
+                            } else {
+                                rules
+                            }),
+                            scroll: 0,
+                        });
+                    }
+                }
+                Outcome::PlayerRuns(i) => {
+                    let run = &self.runs[i];
+                    let Some(player_id) = &run.player_id else {
+                        self.message = Some(format!("{} is a guest, no history", run.player));
+                        continue;
+                    };
+                    let fetched = self.fetch(
+                        terminal,
+                        "player runs",
+                        fetch_player_runs(player_id, client),
+                    )?;
+
+                    if let Some(runs) = fetched {
+                        self.popup = Some(Popup {
+                            title: format!("Recent verified runs by {}", run.player),
+                            body: PopupBody::Runs(runs),
+                            scroll: 0,
+                        });
+                    }
+                }
+                Outcome::Quit => return Ok(Vec::new()),
+                // Confirming without toggling anything picks the highlighted run.
+                Outcome::Confirm if self.selected.is_empty() => {
+                    return Ok(self.highlighted().into_iter().collect())
+                }
+                Outcome::Confirm => return Ok(self.selected.into_iter().collect()),
+            }
+        }
+    }
+
+    /// Opens `url` in the browser, or copies it to the clipboard if that fails.
+    ///
+    /// Returns what happened, for the status bar: stderr isn't visible while
+    /// the TUI is up.
+    fn open_url(&mut self, url: &str) -> String {
+        let Err(open_error) = open::that(url) else {
+            return format!("Opened {url}");
+        };
+
+        let copied = match &mut self.clipboard {
+            Some(clipboard) => clipboard.set_text(url),
+            None => arboard::Clipboard::new().and_then(|mut clipboard| {
+                clipboard.set_text(url)?;
+                self.clipboard = Some(clipboard);
+                Ok(())
+            }),
+        };
+
+        match copied {
+            Ok(()) => format!("Couldn't open a browser ({open_error}), copied {url}"),
+            Err(e) => format!("Couldn't open a browser or copy the link ({e}): {url}"),
+        }
+    }
+
+    /// Waits for `future` with a note in the status bar, reporting errors there.
+    fn fetch<T>(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        what: &str,
+        future: impl Future<Output = Result<T>>,
+    ) -> Result<Option<T>> {
+        self.message = Some(format!("Fetching {what}..."));
+        terminal.draw(|frame| self.draw(frame))?;
+        self.message = None;
+
+        match block_on(future) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => {
+                self.message = Some(format!("Couldn't fetch {what}: {e:#}"));
+                Ok(None)
+            }
+        }
+    }
+
+    /// Hands the terminal over to mpv for the duration of the preview.
+    fn preview(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        run: &Run,
+        done: &Arc<AtomicBool>,
+    ) -> Result<()> {
+        // The URL printed by `preview_run` would be lost on the alternate
+        // screen, show it in the status bar instead.
+        if run.vod_missing {
+            self.message = Some(format!("{} has no VOD to preview", run.run_id));
+            return Ok(());
+        }
+        if find_on_path("mpv").is_none() {
+            self.message = Some(format!("mpv not found, VOD is at {}", run.vod_uri));
+            return Ok(());
+        }
+
+        suspend();
+        let result = block_on(preview_run(run, done));
+        *terminal = resume()?;
+
+        if let Err(e) = result {
+            self.message = Some(format!("Preview failed: {e:#}"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs `future` to completion from the TUI's event loop, which blocks a
+/// runtime worker thread.
+fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(future))
+}
+
+fn resume() -> Result<DefaultTerminal> {
+    let terminal = ratatui::try_init()?;
+    execute!(io::stdout(), EnableMouseCapture)?;
+    Ok(terminal)
+}
+
+fn suspend() {
+    let _ = execute!(io::stdout(), DisableMouseCapture);
+    ratatui::restore();
+}
+
+/// Shows `runs` in a table and returns the indices of the ones picked.
+///
+/// Returns an empty list if the user quits without confirming.
+pub fn run_tui(
+    runs: &[Run],
+    client: &reqwest::Client,
+    done: &Arc<AtomicBool>,
+) -> Result<Vec<usize>> {
+    let mut terminal = resume()?;
+    // Has to ask the terminal, so only once it's in raw mode.
+    let picker = Picker::from_query_stdio().unwrap_or_else(|_| Picker::halfblocks());
+    let result = App::new(runs, picker).run(&mut terminal, client, done);
+    suspend();
+    result
+}
@@ -0,0 +1,122 @@
+//! Fixtures shared by the integration tests: runs shaped like the
+//! speedrun.com API's, and a mock of the API to serve them.
+
+#![allow(dead_code)]
+
+use serde_json::{json, Value};
+use tokio::sync::{Mutex, MutexGuard};
+use wiremock::MockServer;
+
+/// The API base URL is set for the whole process, so tests talking to a mock
+/// API take turns.
+static API: Mutex<()> = Mutex::const_new(());
+
+pub const GAME_ID: &str = "o1y9wo6q";
+
+/// A mock API, which requests go to until the guard is dropped.
+pub struct MockApi {
+    pub server: MockServer,
+    _turn: MutexGuard<'static, ()>,
+}
+
+/// Points the API base URL at `uri`, for as long as the turn is held.
+pub async fn api_at(uri: &str) -> MutexGuard<'static, ()> {
+    let turn = API.lock().await;
+    dlvod::api::set_base_url(uri).unwrap();
+    turn
+}
+
+impl MockApi {
+    pub async fn start() -> Self {
+        let server = MockServer::start().await;
+        let turn = api_at(&server.uri()).await;
+        Self {
+            server,
+            _turn: turn,
+        }
+    }
+
+    /// The URL of `path` on the mock, like the API's own pagination links.
+    pub fn url(&self, path: &str) -> String {
+        format!("{}/{path}", self.server.uri())
+    }
+}
+
+pub fn client() -> reqwest::Client {
+    reqwest::Client::new()
+}
+
+/// A player with an account.
+pub fn account(id: &str, name: &str) -> Value {
+    json!({
+        "rel": "user",
+        "id": id,
+        "names": { "international": name, "japanese": null },
+        "location": { "country": { "code": "it", "names": { "international": "Italy" } } },
+    })
+}
+
+/// A player without an account, who only has a name.
+pub fn guest(name: &str) -> Value {
+    json!({ "rel": "guest", "name": name })
+}
+
+/// A pending Super Mario 64 run by `players`, as embedded by the listing.
+pub fn run(id: &str, players: Vec<Value>) -> Value {
+    json!({
+        "id": id,
+        "weblink": format!("https://www.speedrun.com/sm64/run/{id}"),
+        "game": {
+            "data": {
+                "id": GAME_ID,
+                "abbreviation": "sm64",
+                "names": { "international": "Super Mario 64", "twitch": "Super Mario 64" },
+                "assets": { "cover-small": { "uri": "https://www.speedrun.com/sm64/cover.png" } },
+            }
+        },
+        "category": {
+            "data": {
+                "id": "wkpoo02r",
+                "name": "120 Star",
+                "variables": {
+                    "data": [{
+                        "id": "e8m7em86",
+                        "name": "Version",
+                        "is-subcategory": true,
+                        "values": {
+                            "values": {
+                                "9qj7z0oq": { "label": "N64" },
+                                "jq6540ol": { "label": "VC" },
+                            }
+                        }
+                    }]
+                }
+            }
+        },
+        "videos": { "links": [{ "uri": format!("https://www.twitch.tv/videos/{id}") }] },
+        "comment": "  Good run  ",
+        "status": { "status": "new" },
+        "players": { "data": players },
+        "times": { "primary": "PT1H39M12.5S" },
+        "submitted": "2024-01-02T03:04:05Z",
+        "values": { "e8m7em86": "9qj7z0oq" },
+        "platform": { "data": { "name": "Nintendo 64" } },
+        "region": { "data": { "name": "USA / NTSC" } },
+    })
+}
+
+/// A page of a run listing, linking to `next` if there's one.
+pub fn page(runs: Vec<Value>, offset: usize, next: Option<String>) -> Value {
+    let links = next
+        .map(|uri| vec![json!({ "rel": "next", "uri": uri })])
+        .unwrap_or_default();
+    json!({
+        "data": runs,
+        "pagination": {
+            "offset": offset,
+            "max": 100,
+            "size": runs.len(),
+            "links": links,
+        }
+    })
+}
@@ -0,0 +1,143 @@
+//! `--http2` against APIs that do and don't speak it.
+
+mod common;
+
+use std::time::Instant;
+
+use clap::Parser;
+use common::{account, api_at, page, run, MockApi, GAME_ID};
+use dlvod::{api_client, config::Config, get_pending_runs};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+use wiremock::{
+    matchers::{method, path, query_param},
+    Mock, ResponseTemplate,
+};
+
+const PAGES: usize = 5;
+
+fn config(args: &[&str]) -> Config {
+    Config::parse_from(std::iter::once("dlvod").chain(args.iter().copied()))
+}
+
+/// An API that only speaks HTTP/1.1, answering every request with one run and
+/// turning the HTTP/2 preface away like such servers do.
+async fn http1_server() -> String {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let body = page(
+        vec![run("y8dwe0lz", vec![account("p1", "Someone")])],
+        0,
+        None,
+    )
+    .to_string();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let body = body.clone();
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(read) => head.extend_from_slice(&buf[..read]),
+                    }
+                }
+                let response = if head.starts_with(b"PRI * HTTP/2.0") {
+                    "HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n".to_string()
+                } else {
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    )
+                };
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+
+    format!("http://{addr}")
+}
+
+/// Mounts a listing of `PAGES` pages of one run each.
+async fn mount_pages(api: &MockApi) {
+    for offset in 1..PAGES {
+        let next = (offset + 1 < PAGES).then(|| {
+            api.url(&format!(
+                "runs?game={GAME_ID}&status=new&offset={}&max=100",
+                (offset + 1) * 100
+            ))
+        });
+        let id = format!("run{offset:05}");
+        Mock::given(method("GET"))
+            .and(path("/runs"))
+            .and(query_param("offset", (offset * 100).to_string()))
+            .respond_with(ResponseTemplate::new(200).set_body_json(page(
+                vec![run(&id, vec![account("p1", "Someone")])],
+                offset * 100,
+                next,
+            )))
+            .mount(&api.server)
+            .await;
+    }
+    // The first page is asked for without an offset.
+    Mock::given(method("GET"))
+        .and(path("/runs"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(page(
+            vec![run("run00000", vec![account("p1", "Someone")])],
+            0,
+            Some(api.url(&format!(
+                "runs?game={GAME_ID}&status=new&offset=100&max=100"
+            ))),
+        )))
+        .with_priority(u8::MAX)
+        .mount(&api.server)
+        .await;
+}
+
+#[tokio::test]
+async fn falls_back_to_http1() {
+    let server = http1_server().await;
+    let _turn = api_at(&server).await;
+
+    let client = api_client(&config(&["--http2"])).await.unwrap();
+    let runs = get_pending_runs(GAME_ID, None, &client).await.unwrap();
+    assert_eq!(runs.len(), 1);
+    assert_eq!(runs[0].player, "Someone");
+}
+
+#[tokio::test]
+async fn fetches_every_page_over_http2() {
+    let api = MockApi::start().await;
+    mount_pages(&api).await;
+
+    let client = api_client(&config(&["--http2"])).await.unwrap();
+    let runs = get_pending_runs(GAME_ID, None, &client).await.unwrap();
+    assert_eq!(runs.len(), PAGES);
+}
+
+/// How long the pages take with and without `--http2`, on a loopback mock:
+/// `cargo test --test http2 -- --ignored --nocapture`.
+#[tokio::test]
+#[ignore = "a measurement, not a check"]
+async fn measures_fetch_times() {
+    let api = MockApi::start().await;
+    mount_pages(&api).await;
+
+    for args in [&[][..], &["--http2"][..]] {
+        let client = api_client(&config(args)).await.unwrap();
+        let start = Instant::now();
+        for _ in 0..20 {
+            get_pending_runs(GAME_ID, None, &client).await.unwrap();
+        }
+        println!(
+            "{PAGES} pages, {args:?}: {:?} per fetch",
+            start.elapsed() / 20
+        );
+    }
+}
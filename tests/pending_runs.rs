@@ -0,0 +1,349 @@
+//! Fetching runs from a mock speedrun.com API.
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{account, client, guest, page, run, MockApi, GAME_ID};
+use dlvod::{get_pending_runs, get_pending_runs_since, get_run};
+use serde_json::json;
+use wiremock::{
+    matchers::{method, path, query_param},
+    Mock, ResponseTemplate,
+};
+
+#[tokio::test]
+async fn parses_pending_runs() {
+    let api = MockApi::start().await;
+    Mock::given(method("GET"))
+        .and(path("/runs"))
+        .and(query_param("game", GAME_ID))
+        .and(query_param("status", "new"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(page(
+            vec![
+                run("y8dwe0lz", vec![account("p1", "Someone")]),
+                run("zpqoxkny", vec![account("p2", "Other")]),
+            ],
+            0,
+            None,
+        )))
+        .expect(1)
+        .mount(&api.server)
+        .await;
+
+    let runs = get_pending_runs(GAME_ID, None, &client()).await.unwrap();
+    assert_eq!(runs.len(), 2);
+
+    let first = &runs[0];
+    assert_eq!(first.run_id, "y8dwe0lz");
+    assert_eq!(first.player, "Someone");
+    assert_eq!(first.player_id.as_deref(), Some("p1"));
+    assert_eq!(first.country.as_deref(), Some("it"));
+    assert_eq!(first.game, "sm64");
+    assert_eq!(first.game_id, GAME_ID);
+    assert_eq!(first.game_name, "Super Mario 64");
+    assert_eq!(first.category_id, "wkpoo02r");
+    assert_eq!(first.cat, "120star");
+    assert_eq!(first.cat_full, "120 Star - Version: N64");
+    assert_eq!(
+        first.variables,
+        [("Version".to_string(), "N64".to_string())]
+    );
+    assert_eq!(first.time, "01:39:12.500");
+    assert_eq!(first.duration, Duration::from_millis(5_952_500));
+    assert_eq!(first.vod_uri, "https://www.twitch.tv/videos/y8dwe0lz");
+    assert!(!first.vod_missing);
+    assert_eq!(first.platform.as_deref(), Some("Nintendo 64"));
+    assert_eq!(first.region.as_deref(), Some("USA / NTSC"));
+    assert_eq!(first.notes.as_deref(), Some("Good run"));
+    assert_eq!(
+        first.permalink,
+        "https://www.speedrun.com/sm64/run/y8dwe0lz"
+    );
+    assert_eq!(
+        first.submitted.unwrap().to_rfc3339(),
+        "2024-01-02T03:04:05+00:00"
+    );
+    assert_eq!(runs[1].player, "Other");
+}
+
+#[tokio::test]
+async fn asks_for_the_category() {
+    let api = MockApi::start().await;
+    Mock::given(method("GET"))
+        .and(path("/runs"))
+        .and(query_param("category", "wkpoo02r"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(page(
+            vec![run("y8dwe0lz", vec![account("p1", "Someone")])],
+            0,
+            None,
+        )))
+        .expect(1)
+        .mount(&api.server)
+        .await;
+
+    let runs = get_pending_runs(GAME_ID, Some("wkpoo02r"), &client())
+        .await
+        .unwrap();
+    assert_eq!(runs.len(), 1);
+}
+
+#[tokio::test]
+async fn reads_guest_players() {
+    let api = MockApi::start().await;
+    Mock::given(method("GET"))
+        .and(path("/runs"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(page(
+            vec![run("y8dwe0lz", vec![guest("A Guest")])],
+            0,
+            None,
+        )))
+        .mount(&api.server)
+        .await;
+
+    let runs = get_pending_runs(GAME_ID, None, &client()).await.unwrap();
+    let run = &runs[0];
+    assert_eq!(run.player, "A Guest");
+    assert_eq!(run.players, ["A Guest"]);
+    assert_eq!(run.player_id, None);
+    assert_eq!(run.country, None);
+    assert_eq!(
+        run.filename("{player}-{game}-{cat}-{run_id}"),
+        "AGuest-sm64-120star-y8dwe0lz"
+    );
+}
+
+#[tokio::test]
+async fn reads_every_player_of_co_op_runs() {
+    let api = MockApi::start().await;
+    Mock::given(method("GET"))
+        .and(path("/runs"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(page(
+            vec![run(
+                "y8dwe0lz",
+                vec![account("p1", "Someone"), guest("Partner")],
+            )],
+            0,
+            None,
+        )))
+        .mount(&api.server)
+        .await;
+
+    let runs = get_pending_runs(GAME_ID, None, &client()).await.unwrap();
+    let run = &runs[0];
+
+    // Listed under the first player, with everyone shown.
+    assert_eq!(run.player, "Someone");
+    assert_eq!(run.player_id.as_deref(), Some("p1"));
+    assert_eq!(run.players, ["Someone", "Partner"]);
+    assert!(run.to_string().contains("Someone & Partner"), "{run}");
+    assert_eq!(run.filename("{player}-{run_id}"), "Someone-y8dwe0lz");
+}
+
+#[tokio::test]
+async fn follows_pagination() {
+    let api = MockApi::start().await;
+    let next = api.url(&format!(
+        "runs?game={GAME_ID}&status=new&offset=100&max=100"
+    ));
+
+    Mock::given(method("GET"))
+        .and(path("/runs"))
+        .and(query_param("offset", "100"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(page(
+            vec![run("zpqoxkny", vec![account("p3", "Third")])],
+            100,
+            None,
+        )))
+        .expect(1)
+        .named("second page")
+        .mount(&api.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/runs"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(page(
+            vec![
+                run("y8dwe0lz", vec![account("p1", "First")]),
+                run("kz4ge9lm", vec![account("p2", "Second")]),
+            ],
+            0,
+            Some(next),
+        )))
+        .expect(1)
+        .named("first page")
+        .mount(&api.server)
+        .await;
+
+    let runs = get_pending_runs(GAME_ID, None, &client()).await.unwrap();
+    let players = runs
+        .iter()
+        .map(|run| run.player.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(players, ["First", "Second", "Third"]);
+
+    // The second page keeps the filters of the first.
+    let requests = api.server.received_requests().await.unwrap();
+    let second = requests
+        .iter()
+        .find(|request| {
+            request
+                .url
+                .query()
+                .unwrap_or_default()
+                .contains("offset=100")
+        })
+        .unwrap();
+    let query = second.url.query_pairs().collect::<Vec<_>>();
+    assert!(query.iter().any(|(k, v)| k == "game" && v == GAME_ID));
+    assert!(query.iter().any(|(k, v)| k == "status" && v == "new"));
+    assert!(query.iter().any(|(k, _)| k == "embed"));
+}
+
+#[tokio::test]
+async fn retries_when_rate_limited() {
+    let api = MockApi::start().await;
+    Mock::given(method("GET"))
+        .and(path("/runs"))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&api.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/runs"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(page(
+            vec![run("y8dwe0lz", vec![account("p1", "Someone")])],
+            0,
+            None,
+        )))
+        .expect(1)
+        .mount(&api.server)
+        .await;
+
+    let runs = get_pending_runs(GAME_ID, None, &client()).await.unwrap();
+    assert_eq!(runs.len(), 1);
+    assert_eq!(api.server.received_requests().await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn gives_up_on_long_retry_afters() {
+    let api = MockApi::start().await;
+    Mock::given(method("GET"))
+        .and(path("/runs"))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "86400"))
+        .expect(1)
+        .mount(&api.server)
+        .await;
+
+    let started = Instant::now();
+    assert!(get_pending_runs(GAME_ID, None, &client()).await.is_err());
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn retries_server_errors() {
+    let api = MockApi::start().await;
+    Mock::given(method("GET"))
+        .and(path("/runs"))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(1)
+        .mount(&api.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/runs"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(page(Vec::new(), 0, None)))
+        .mount(&api.server)
+        .await;
+
+    let runs = get_pending_runs(GAME_ID, None, &client()).await.unwrap();
+    assert!(runs.is_empty());
+}
+
+#[tokio::test]
+async fn fails_on_runs_it_cant_read() {
+    let api = MockApi::start().await;
+    let mut broken = run("y8dwe0lz", vec![account("p1", "Someone")]);
+    broken["game"] = json!(null);
+    Mock::given(method("GET"))
+        .and(path("/runs"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(page(vec![broken], 0, None)))
+        .mount(&api.server)
+        .await;
+
+    let e = get_pending_runs(GAME_ID, None, &client())
+        .await
+        .unwrap_err();
+    assert!(format!("{e:#}").contains("game"), "{e:#}");
+}
+
+#[tokio::test]
+async fn fetches_single_runs() {
+    let api = MockApi::start().await;
+    Mock::given(method("GET"))
+        .and(path("/runs/y8dwe0lz"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json!({ "data": run("y8dwe0lz", vec![guest("Someone")]) })),
+        )
+        .mount(&api.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/runs/missing0"))
+        .respond_with(ResponseTemplate::new(404).set_body_json(json!({ "status": 404 })))
+        .expect(1)
+        .mount(&api.server)
+        .await;
+
+    let run = get_run("y8dwe0lz", &client()).await.unwrap();
+    assert_eq!(run.run_id, "y8dwe0lz");
+    assert_eq!(run.player, "Someone");
+
+    // Not found isn't worth retrying.
+    let e = get_run("missing0", &client()).await.unwrap_err();
+    assert!(e.to_string().contains("missing0"), "{e}");
+}
+
+#[test]
+fn only_accepts_https_base_urls() {
+    use dlvod::api::parse_base_url;
+
+    assert_eq!(
+        parse_base_url("https://mirror.example.test/api/v1/").unwrap(),
+        "https://mirror.example.test/api/v1"
+    );
+    assert!(parse_base_url("http://127.0.0.1:8000").is_ok());
+    assert!(parse_base_url("http://localhost/api").is_ok());
+    assert!(parse_base_url("http://[::1]:8000").is_ok());
+    assert!(parse_base_url("http://mirror.example.test/api/v1").is_err());
+    assert!(parse_base_url("ftp://mirror.example.test").is_err());
+    assert!(parse_base_url("https://mirror.example.test/api?x=1").is_err());
+    assert!(parse_base_url("not a url").is_err());
+}
+
+#[tokio::test]
+async fn only_asks_for_runs_submitted_since() {
+    let api = MockApi::start().await;
+    let mut old = run("y8dwe0lz", vec![account("p1", "Old")]);
+    old["submitted"] = json!("2024-01-01T00:00:00Z");
+    let mut new = run("zpqoxkny", vec![account("p2", "New")]);
+    new["submitted"] = json!("2024-03-01T00:00:00Z");
+
+    // An API ignoring `submitted_after` sends everything anyway.
+    Mock::given(method("GET"))
+        .and(path("/runs"))
+        .and(query_param("submitted_after", "2024-02-01T00:00:00Z"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(page(vec![old, new], 0, None)))
+        .expect(1)
+        .mount(&api.server)
+        .await;
+
+    let last_seen = "2024-02-01T00:00:00Z".parse().unwrap();
+    let runs = get_pending_runs_since(GAME_ID, None, Some(last_seen), &client())
+        .await
+        .unwrap();
+    let players = runs
+        .iter()
+        .map(|run| run.player.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(players, ["New"]);
+}
@@ -0,0 +1,68 @@
+//! `--api-timeout` against an API that answers too slowly.
+
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use dlvod::{api_client, config::Config};
+use wiremock::{matchers::path, Mock, MockServer, ResponseTemplate};
+
+fn config(args: &[&str]) -> Config {
+    Config::parse_from(std::iter::once("dlvod").chain(args.iter().copied()))
+}
+
+async fn slow_server(delay: Duration) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(path("/runs"))
+        .respond_with(ResponseTemplate::new(200).set_delay(delay))
+        .mount(&server)
+        .await;
+    server
+}
+
+#[tokio::test]
+async fn gives_up_on_slow_responses() {
+    let server = slow_server(Duration::from_secs(5)).await;
+    let client = api_client(&config(&["--api-timeout", "1"])).await.unwrap();
+
+    let start = Instant::now();
+    let e = client
+        .get(format!("{}/runs", server.uri()))
+        .send()
+        .await
+        .unwrap_err();
+    assert!(e.is_timeout(), "{e}");
+    assert!(
+        start.elapsed() < Duration::from_secs(3),
+        "{:?}",
+        start.elapsed()
+    );
+}
+
+#[tokio::test]
+async fn waits_for_responses_within_the_timeout() {
+    let server = slow_server(Duration::from_millis(200)).await;
+    let client = api_client(&config(&["--api-timeout", "2"])).await.unwrap();
+
+    let response = client
+        .get(format!("{}/runs", server.uri()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+#[test]
+fn the_command_line_wins_over_the_config_file() {
+    let mut cfg = config(&[]);
+    assert_eq!(cfg.api_timeout(), Duration::from_secs(30));
+    assert_eq!(cfg.api_connect_timeout(), Duration::from_secs(10));
+
+    cfg.file = toml::from_str("api_timeout = 60\napi_connect_timeout = 5").unwrap();
+    assert_eq!(cfg.api_timeout(), Duration::from_secs(60));
+    assert_eq!(cfg.api_connect_timeout(), Duration::from_secs(5));
+
+    let mut cfg = config(&["--api-timeout", "15", "--connect-timeout", "3"]);
+    cfg.file = toml::from_str("api_timeout = 60").unwrap();
+    assert_eq!(cfg.api_timeout(), Duration::from_secs(15));
+    assert_eq!(cfg.api_connect_timeout(), Duration::from_secs(3));
+}
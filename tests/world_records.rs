@@ -0,0 +1,73 @@
+//! Comparing pending runs with the world record of their subcategory.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{account, client, page, run, MockApi, GAME_ID};
+use dlvod::{get_pending_runs, mark_wr_candidates};
+use serde_json::json;
+use wiremock::{
+    matchers::{method, path, query_param},
+    Mock, ResponseTemplate,
+};
+
+/// A leaderboard whose first place took `secs`, or an empty one.
+fn leaderboard(secs: Option<f64>) -> serde_json::Value {
+    let runs = secs
+        .map(|secs| vec![json!({ "place": 1, "run": { "times": { "primary_t": secs } } })])
+        .unwrap_or_default();
+    json!({ "data": { "runs": runs } })
+}
+
+async fn pending_run(api: &MockApi, category: &str) -> dlvod::Run {
+    let mut pending = run("y8dwe0lz", vec![account("p1", "Someone")]);
+    pending["category"]["data"]["id"] = json!(category);
+    Mock::given(method("GET"))
+        .and(path("/runs"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(page(vec![pending], 0, None)))
+        .mount(&api.server)
+        .await;
+
+    let mut runs = get_pending_runs(GAME_ID, None, &client()).await.unwrap();
+    runs.remove(0)
+}
+
+#[tokio::test]
+async fn looks_up_the_record_of_the_subcategory() {
+    let api = MockApi::start().await;
+    let mut run = pending_run(&api, "subcat01").await;
+    assert_eq!(
+        run.subcategory,
+        [("e8m7em86".to_string(), "9qj7z0oq".to_string())]
+    );
+
+    Mock::given(method("GET"))
+        .and(path(format!("/leaderboards/{GAME_ID}/category/subcat01")))
+        .and(query_param("top", "1"))
+        .and(query_param("var-e8m7em86", "9qj7z0oq"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(leaderboard(Some(6000.0))))
+        .expect(1)
+        .mount(&api.server)
+        .await;
+
+    mark_wr_candidates(std::slice::from_mut(&mut run), &client()).await;
+    assert_eq!(run.wr_time, Some(Duration::from_secs(6000)));
+    assert!(run.would_be_wr);
+}
+
+#[tokio::test]
+async fn an_empty_leaderboard_is_no_record() {
+    let api = MockApi::start().await;
+    let mut run = pending_run(&api, "emptycat").await;
+
+    Mock::given(method("GET"))
+        .and(path(format!("/leaderboards/{GAME_ID}/category/emptycat")))
+        .respond_with(ResponseTemplate::new(200).set_body_json(leaderboard(None)))
+        .mount(&api.server)
+        .await;
+
+    mark_wr_candidates(std::slice::from_mut(&mut run), &client()).await;
+    assert_eq!(run.wr_time, None);
+    assert!(!run.would_be_wr);
+}